@@ -11,12 +11,66 @@ let
     options = {
       allowedCalls = lib.mkOption {
         type = lib.types.listOf lib.types.str;
+        default = [ ];
         description = ''
           List of gRPC methods this user may call, e.g.
-          ["mypackage.MyService/GetStatus"]. Use ["*"] to allow all.
+          ["mypackage.MyService/GetStatus"]. Use ["*"] to allow all. Globs
+          such as "mypackage.MyService/*" and "regex:<expr>" entries are
+          supported.
         '';
       };
 
+      deniedCalls = lib.mkOption {
+        type = lib.types.listOf lib.types.str;
+        default = [ ];
+        description = "gRPC methods this user may never call. Deny rules override allow rules.";
+      };
+
+      allowedNetworks = lib.mkOption {
+        type = lib.types.listOf lib.types.str;
+        default = [ ];
+        example = [ "10.0.0.0/8" ];
+        description = "If non-empty, networks (CIDR) this user may call from.";
+      };
+
+      deniedNetworks = lib.mkOption {
+        type = lib.types.listOf lib.types.str;
+        default = [ ];
+        description = "Networks (CIDR) this user may never call from.";
+      };
+
+      roles = lib.mkOption {
+        type = lib.types.listOf lib.types.str;
+        default = [ ];
+        description = "Roles whose allowed calls this user is granted.";
+      };
+
+      rateLimit = lib.mkOption {
+        type = lib.types.nullOr (
+          lib.types.submodule {
+            options = {
+              rate = lib.mkOption {
+                type = lib.types.number;
+                description = "Calls per second.";
+              };
+              burst = lib.mkOption {
+                type = lib.types.nullOr lib.types.ints.positive;
+                default = null;
+                description = "Calls allowed at once. Defaults to the rate.";
+              };
+            };
+          }
+        );
+        default = null;
+        description = "Token-bucket limit replacing rate_limits.per_user for this user.";
+      };
+
+      maxConcurrentCalls = lib.mkOption {
+        type = lib.types.nullOr lib.types.ints.positive;
+        default = null;
+        description = "Calls this user may have open at once, replacing concurrency_limits.per_user.";
+      };
+
       passwordHashFile = lib.mkOption {
         type = lib.types.nullOr lib.types.str;
         default = null;
@@ -38,6 +92,160 @@ let
           Mutually exclusive with passwordHashFile.
         '';
       };
+
+      apiTokenHashFiles = lib.mkOption {
+        type = lib.types.attrsOf lib.types.str;
+        default = { };
+        example = lib.literalExpression ''{ ci-deploy = config.sops.secrets.grpc-proxier-ci-token-hash.path; }'';
+        description = ''
+          API tokens for this user, keyed by token name. Each value is the
+          path to a file containing the argon2 hash printed by
+          `grpc-proxier-hash --token NAME`.
+        '';
+      };
+    };
+  };
+
+  roleModule = {
+    options = {
+      allowedCalls = lib.mkOption {
+        type = lib.types.listOf lib.types.str;
+        default = [ ];
+        description = "List of gRPC methods members of this role may call.";
+      };
+
+      deniedCalls = lib.mkOption {
+        type = lib.types.listOf lib.types.str;
+        default = [ ];
+        description = "gRPC methods members of this role may never call.";
+      };
+
+      inherits = lib.mkOption {
+        type = lib.types.listOf lib.types.str;
+        default = [ ];
+        description = "Roles whose allowed calls are included in this role.";
+      };
+    };
+  };
+
+  upstreamTlsOptions = {
+    enable = lib.mkEnableOption "TLS on the connection to the upstream";
+
+    caFile = lib.mkOption {
+      type = lib.types.nullOr lib.types.str;
+      default = null;
+      description = "PEM CA bundle used to verify the upstream. Defaults to the Mozilla root store.";
+    };
+
+    certFile = lib.mkOption {
+      type = lib.types.nullOr lib.types.str;
+      default = null;
+      description = "PEM client certificate presented to the upstream (mutual TLS).";
+    };
+
+    keyFile = lib.mkOption {
+      type = lib.types.nullOr lib.types.str;
+      default = null;
+      description = "PEM private key for certFile.";
+    };
+
+    serverName = lib.mkOption {
+      type = lib.types.nullOr lib.types.str;
+      default = null;
+      description = "SNI and certificate name override. Defaults to the host of the upstream address.";
+    };
+
+    authority = lib.mkOption {
+      type = lib.types.nullOr lib.types.str;
+      default = null;
+      description = "`:authority` override sent to the upstream. Defaults to the upstream address.";
+    };
+  };
+
+  upstreamModule = {
+    options = {
+      address = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        description = "Single upstream gRPC server address (host:port).";
+      };
+
+      endpoints = lib.mkOption {
+        type = lib.types.listOf lib.types.str;
+        default = [ ];
+        description = "Several upstream addresses (host:port) to balance across.";
+      };
+
+      dns = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        description = "host:port resolved to one endpoint per A/AAAA record and re-resolved periodically.";
+      };
+
+      loadBalancing = lib.mkOption {
+        type = lib.types.enum [
+          "round_robin"
+          "least_request"
+          "consistent_hash"
+        ];
+        default = "round_robin";
+        description = "How calls are spread over the endpoints.";
+      };
+
+      hashKey = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        description = "Request metadata key hashed by the consistent_hash strategy.";
+      };
+
+      healthCheck = {
+        enable = lib.mkEnableOption "grpc.health.v1 checks that take failing endpoints out of rotation";
+
+        intervalSecs = lib.mkOption {
+          type = lib.types.ints.positive;
+          default = 10;
+          description = "Seconds between health checks of each endpoint.";
+        };
+
+        service = lib.mkOption {
+          type = lib.types.str;
+          default = "";
+          description = "Service name sent in the check. Empty checks the server as a whole.";
+        };
+      };
+
+      circuitBreaker = {
+        enable = lib.mkEnableOption "a circuit breaker per endpoint that stops calls to failing endpoints";
+
+        consecutiveFailures = lib.mkOption {
+          type = lib.types.ints.positive;
+          default = 5;
+          description = "Failed calls in a row that open the breaker.";
+        };
+
+        openSecs = lib.mkOption {
+          type = lib.types.ints.positive;
+          default = 30;
+          description = "Seconds an open breaker rejects calls before probing the endpoint again.";
+        };
+      };
+
+      tls = upstreamTlsOptions;
+    };
+  };
+
+  routeModule = {
+    options = {
+      service = lib.mkOption {
+        type = lib.types.str;
+        example = "billing.*";
+        description = "gRPC service (package.Service) pattern, using the same syntax as allowedCalls.";
+      };
+
+      upstream = lib.mkOption {
+        type = lib.types.str;
+        description = "Name of the upstream in `upstreams` that receives matching calls.";
+      };
     };
   };
 
@@ -54,9 +262,53 @@ let
         description = "Port to listen on for gRPC connections.";
       };
 
+      socketActivation = lib.mkEnableOption ''
+        a systemd socket unit that holds the listening socket. The proxy takes it
+        over on start, so it can listen on privileged ports without capabilities
+        and connections arriving during a restart wait instead of being refused
+      '';
+
+      proxyProtocol = lib.mkEnableOption ''
+        the PROXY protocol on the listener. Every connection must then start with
+        a PROXY header naming the client, as sent by HAProxy or an L4 load balancer.
+        Cannot be combined with nginx.domain
+      '';
+
+      trustedProxies = lib.mkOption {
+        type = lib.types.listOf lib.types.str;
+        default = [ ];
+        example = [ "10.0.0.0/8" ];
+        description = "Networks of proxies whose X-Forwarded-For and X-Real-IP headers name the client, or \"unix\" for Unix socket peers. Loopback is added when nginx.domain is set.";
+      };
+
       upstreamAddress = lib.mkOption {
-        type = lib.types.str;
-        description = "Upstream gRPC server address (host:port).";
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        description = "Upstream gRPC server address (host:port). Shorthand for an upstream named \"default\".";
+      };
+
+      upstreams = lib.mkOption {
+        type = lib.types.attrsOf (lib.types.submodule upstreamModule);
+        default = { };
+        description = "Named upstream clusters that routes can send calls to.";
+      };
+
+      routes = lib.mkOption {
+        type = lib.types.listOf (lib.types.submodule routeModule);
+        default = [ ];
+        example = [
+          {
+            service = "billing.*";
+            upstream = "billing";
+          }
+        ];
+        description = "Routes from gRPC services to upstreams, checked in order.";
+      };
+
+      defaultUpstream = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        description = "Upstream for calls matching no route. Defaults to the upstream named \"default\", if any.";
       };
 
       metricsAddress = lib.mkOption {
@@ -71,6 +323,48 @@ let
         description = "Port for the Prometheus metrics endpoint.";
       };
 
+      extraConfig = lib.mkOption {
+        type = lib.types.lines;
+        default = "";
+        example = ''
+          [jwt]
+          algorithms = ["RS256"]
+          jwks_url = "https://idp.example.com/.well-known/jwks.json"
+        '';
+        description = ''
+          Extra TOML for settings without a dedicated option (e.g. the [jwt]
+          section). It is placed right after the generated top-level keys, so
+          it may start with top-level keys of its own; every generated table
+          follows it with its own header.
+        '';
+      };
+
+      deniedCalls = lib.mkOption {
+        type = lib.types.listOf lib.types.str;
+        default = [ ];
+        example = [ "admin.AdminService/*" ];
+        description = "gRPC methods no user may call. Deny rules override allow rules.";
+      };
+
+      allowedNetworks = lib.mkOption {
+        type = lib.types.listOf lib.types.str;
+        default = [ ];
+        example = [ "10.0.0.0/8" ];
+        description = "If non-empty, networks (CIDR) calls are accepted from.";
+      };
+
+      deniedNetworks = lib.mkOption {
+        type = lib.types.listOf lib.types.str;
+        default = [ ];
+        description = "Networks (CIDR) calls are never accepted from.";
+      };
+
+      roles = lib.mkOption {
+        type = lib.types.attrsOf (lib.types.submodule roleModule);
+        default = { };
+        description = "Named roles that users can reference.";
+      };
+
       users = lib.mkOption {
         type = lib.types.attrsOf (lib.types.submodule userModule);
         default = { };
@@ -112,6 +406,52 @@ let
         description = "The grpc-proxier package to use.";
       };
 
+      tls = {
+        certFile = lib.mkOption {
+          type = lib.types.nullOr lib.types.str;
+          default = null;
+          description = ''
+            Path to a PEM certificate chain. When set together with keyFile,
+            the proxy terminates TLS itself. The file is reloaded on change.
+          '';
+        };
+
+        keyFile = lib.mkOption {
+          type = lib.types.nullOr lib.types.str;
+          default = null;
+          description = "Path to the PEM private key for tls.certFile.";
+        };
+
+        clientCaFile = lib.mkOption {
+          type = lib.types.nullOr lib.types.str;
+          default = null;
+          description = ''
+            Path to a PEM CA bundle used to verify client certificates.
+            Verified clients are mapped to users by certificate CN or SAN.
+          '';
+        };
+
+        clientAuth = lib.mkOption {
+          type = lib.types.enum [
+            "optional"
+            "required"
+          ];
+          default = "optional";
+          description = "Whether clients must present a certificate during the TLS handshake.";
+        };
+
+        clientIdentity = lib.mkOption {
+          type = lib.types.enum [
+            "cn"
+            "san"
+          ];
+          default = "cn";
+          description = "Which certificate field names the user: the subject CN or a SAN.";
+        };
+      };
+
+      upstreamTls = upstreamTlsOptions;
+
       nginx = {
         domain = lib.mkOption {
           type = lib.types.nullOr lib.types.str;
@@ -141,29 +481,118 @@ let
   mkInstanceConfig =
     name: icfg:
     let
+      # JSON string escapes are valid in TOML basic strings, so backslashes
+      # in regex: patterns and quotes survive.
+      tomlList = lib.concatMapStringsSep ", " builtins.toJSON;
+      rolesSections = lib.concatStringsSep "\n" (
+        lib.mapAttrsToList (roleName: rcfg: ''
+          [roles.${roleName}]
+          allowed_calls = [${tomlList rcfg.allowedCalls}]
+          denied_calls = [${tomlList rcfg.deniedCalls}]
+          inherits = [${tomlList rcfg.inherits}]
+        '') icfg.roles
+      );
       usersSections = lib.concatStringsSep "\n" (
-        lib.mapAttrsToList (
-          username: ucfg:
-          let
-            calls = lib.concatMapStringsSep ", " (c: ''"${c}"'') ucfg.allowedCalls;
-          in
-          ''
-            [users.${username}]
-            allowed_calls = [${calls}]
-          ''
-        ) icfg.users
+        lib.mapAttrsToList (username: ucfg: ''
+          [users.${username}]
+          allowed_calls = [${tomlList ucfg.allowedCalls}]
+          denied_calls = [${tomlList ucfg.deniedCalls}]
+          allowed_networks = [${tomlList ucfg.allowedNetworks}]
+          denied_networks = [${tomlList ucfg.deniedNetworks}]
+          roles = [${tomlList ucfg.roles}]
+          ${lib.optionalString (
+            ucfg.maxConcurrentCalls != null
+          ) "max_concurrent_calls = ${toString ucfg.maxConcurrentCalls}"}
+          ${lib.optionalString (ucfg.rateLimit != null) ''
+            rate_limit = { rate = ${toString ucfg.rateLimit.rate}${
+              lib.optionalString (
+                ucfg.rateLimit.burst != null
+              ) ", burst = ${toString ucfg.rateLimit.burst}"
+            } }
+          ''}
+        '') icfg.users
       );
+      tlsSection = lib.optionalString (icfg.tls.certFile != null) ''
+        [tls]
+        cert_path = "${icfg.tls.certFile}"
+        key_path = "${icfg.tls.keyFile}"
+        ${lib.optionalString (icfg.tls.clientCaFile != null) ''
+          client_ca_path = "${icfg.tls.clientCaFile}"
+          client_auth = "${icfg.tls.clientAuth}"
+          client_identity = "${icfg.tls.clientIdentity}"
+        ''}
+      '';
+      optionalKey = key: value: lib.optionalString (value != null) ''${key} = "${value}"'';
+      mkUpstreamTlsSection =
+        header: tcfg:
+        lib.optionalString tcfg.enable ''
+          [${header}]
+          ${optionalKey "ca_path" tcfg.caFile}
+          ${optionalKey "cert_path" tcfg.certFile}
+          ${optionalKey "key_path" tcfg.keyFile}
+          ${optionalKey "server_name" tcfg.serverName}
+          ${optionalKey "authority" tcfg.authority}
+        '';
+      upstreamTlsSection = mkUpstreamTlsSection "upstream_tls" icfg.upstreamTls;
+      upstreamsSections = lib.concatStringsSep "\n" (
+        lib.mapAttrsToList (upstreamName: ucfg: ''
+          [upstreams.${upstreamName}]
+          ${optionalKey "address" ucfg.address}
+          endpoints = [${tomlList ucfg.endpoints}]
+          ${optionalKey "dns" ucfg.dns}
+          load_balancing = "${ucfg.loadBalancing}"
+          ${optionalKey "hash_key" ucfg.hashKey}
+          ${lib.optionalString ucfg.healthCheck.enable ''
+            [upstreams.${upstreamName}.health_check]
+            interval_secs = ${toString ucfg.healthCheck.intervalSecs}
+            service = "${ucfg.healthCheck.service}"
+          ''}
+          ${lib.optionalString ucfg.circuitBreaker.enable ''
+            [upstreams.${upstreamName}.circuit_breaker]
+            consecutive_failures = ${toString ucfg.circuitBreaker.consecutiveFailures}
+            open_secs = ${toString ucfg.circuitBreaker.openSecs}
+          ''}
+          ${mkUpstreamTlsSection "upstreams.${upstreamName}.tls" ucfg.tls}
+        '') icfg.upstreams
+      );
+      # nginx on the same host forwards the client address in headers
+      trustedProxies =
+        icfg.trustedProxies
+        ++ lib.optionals (icfg.nginx.domain != null) [
+          "127.0.0.1/32"
+          "::1/128"
+        ];
+      routesSections = lib.concatMapStringsSep "\n" (route: ''
+        [[routes]]
+        service = ${builtins.toJSON route.service}
+        upstream = ${builtins.toJSON route.upstream}
+      '') icfg.routes;
     in
     pkgs.writeText "grpc-proxier-${name}.toml" ''
-      listen_address = "${icfg.listenAddress}:${toString icfg.listenPort}"
-      upstream_address = "${icfg.upstreamAddress}"
+      listen_address = "${
+        if icfg.socketActivation then "systemd:grpc" else "${icfg.listenAddress}:${toString icfg.listenPort}"
+      }"
+      ${lib.optionalString icfg.proxyProtocol "proxy_protocol = true"}
+      trusted_proxies = [${tomlList trustedProxies}]
+      ${optionalKey "upstream_address" icfg.upstreamAddress}
+      ${optionalKey "default_upstream" icfg.defaultUpstream}
       metrics_address = "${icfg.metricsAddress}:${toString icfg.metricsPort}"
-
+      denied_calls = [${tomlList icfg.deniedCalls}]
+      allowed_networks = [${tomlList icfg.allowedNetworks}]
+      denied_networks = [${tomlList icfg.deniedNetworks}]
+
+      ${icfg.extraConfig}
+      ${tlsSection}
+      ${upstreamTlsSection}
+      ${upstreamsSections}
+      ${routesSections}
+      ${rolesSections}
       ${usersSections}
     '';
 
   # Whether a user has any per-user credential source
-  hasUserCredential = ucfg: ucfg.passwordHashFile != null || ucfg.passwordFile != null;
+  hasUserCredential =
+    ucfg: ucfg.passwordHashFile != null || ucfg.passwordFile != null || ucfg.apiTokenHashFiles != { };
 
   # Assemble a credentials file from per-user passwordHashFile/passwordFile options
   mkCredentialsScript =
@@ -176,18 +605,28 @@ let
         set -euo pipefail
         CREDS="/run/grpc-proxier/${name}/credentials"
         mkdir -p "$(dirname "$CREDS")"
+        : > "$CREDS"
       ''
       + lib.concatStringsSep "" (
         lib.mapAttrsToList (
           username: ucfg:
-          if ucfg.passwordHashFile != null then
-            ''
-              printf '%s:%s\n' "${username}" "$(cat "${ucfg.passwordHashFile}")" >> "$CREDS"
-            ''
-          else
-            ''
-              printf '%s:%s\n' "${username}" "$(cat "${ucfg.passwordFile}" | ${icfg.package}/bin/grpc-proxier-hash)" >> "$CREDS"
-            ''
+          (
+            if ucfg.passwordHashFile != null then
+              ''
+                printf '%s:%s\n' "${username}" "$(cat "${ucfg.passwordHashFile}")" >> "$CREDS"
+              ''
+            else if ucfg.passwordFile != null then
+              ''
+                printf '%s:%s\n' "${username}" "$(cat "${ucfg.passwordFile}" | ${icfg.package}/bin/grpc-proxier-hash)" >> "$CREDS"
+              ''
+            else
+              ""
+          )
+          + lib.concatStrings (
+            lib.mapAttrsToList (tokenName: hashFile: ''
+              printf 'token:%s:%s:%s\n' "${tokenName}" "${username}" "$(cat "${hashFile}")" >> "$CREDS"
+            '') ucfg.apiTokenHashFiles
+          )
         ) users
       )
       + ''
@@ -206,11 +645,7 @@ let
       "/run/grpc-proxier/${name}/credentials";
 
   # Whether this instance needs a pre-start script to assemble credentials
-  needsCredentialsAssembly =
-    icfg:
-    !icfg.noAuth
-    && icfg.credentialsFile == null
-    && lib.any hasUserCredential (lib.attrValues icfg.users);
+  needsCredentialsAssembly = icfg: !icfg.noAuth && icfg.credentialsFile == null;
 in
 {
   options.services.grpc-proxier = {
@@ -256,8 +691,21 @@ in
         assertion =
           icfg.noAuth
           || icfg.credentialsFile != null
+          || icfg.tls.clientCaFile != null
           || lib.all hasUserCredential (lib.attrValues icfg.users);
-        message = "grpc-proxier.instances.${name}: each user needs a passwordHashFile or passwordFile, or set a credentialsFile, or enable noAuth.";
+        message = "grpc-proxier.instances.${name}: each user needs a passwordHashFile, passwordFile or apiTokenHashFiles, or set a credentialsFile, tls.clientCaFile, or enable noAuth.";
+      }) enabledInstances
+      ++ lib.mapAttrsToList (name: icfg: {
+        assertion = icfg.upstreamAddress != null || icfg.upstreams != { };
+        message = "grpc-proxier.instances.${name}: set upstreamAddress or at least one entry in upstreams.";
+      }) enabledInstances
+      ++ lib.mapAttrsToList (name: icfg: {
+        assertion = (icfg.tls.certFile == null) == (icfg.tls.keyFile == null);
+        message = "grpc-proxier.instances.${name}: tls.certFile and tls.keyFile must be set together.";
+      }) enabledInstances
+      ++ lib.mapAttrsToList (name: icfg: {
+        assertion = !(icfg.proxyProtocol && icfg.nginx.domain != null);
+        message = "grpc-proxier.instances.${name}: proxyProtocol cannot be used with nginx.domain, as nginx does not send PROXY headers upstream.";
       }) enabledInstances
       ++ lib.concatLists (
         lib.mapAttrsToList (
@@ -281,7 +729,8 @@ in
       lib.nameValuePair "grpc-proxier-${name}" {
         description = "gRPC Proxier (${name})";
         wantedBy = [ "multi-user.target" ];
-        after = [ "network.target" ];
+        after = [ "network.target" ] ++ lib.optional icfg.socketActivation "grpc-proxier-${name}.socket";
+        requires = lib.optional icfg.socketActivation "grpc-proxier-${name}.socket";
 
         environment = {
           CONFIG_PATH = "${mkInstanceConfig name icfg}";
@@ -294,6 +743,7 @@ in
 
         serviceConfig = {
           ExecStart = "${icfg.package}/bin/grpc-proxier";
+          ExecReload = "${pkgs.coreutils}/bin/kill -HUP $MAINPID";
           User = cfg.user;
           Group = cfg.group;
           Restart = "always";
@@ -314,11 +764,26 @@ in
         }
         // lib.optionalAttrs (needsCredentialsAssembly icfg) {
           ExecStartPre = "+${mkCredentialsScript name icfg}";
+          # Reassemble credentials so SIGHUP picks up rotated secrets.
+          ExecReload = [
+            "+${mkCredentialsScript name icfg}"
+            "${pkgs.coreutils}/bin/kill -HUP $MAINPID"
+          ];
           RuntimeDirectory = "grpc-proxier/${name}";
         };
       }
     ) enabledInstances;
 
+    systemd.sockets = lib.mapAttrs' (
+      name: icfg:
+      lib.nameValuePair "grpc-proxier-${name}" {
+        description = "gRPC Proxier (${name}) socket";
+        wantedBy = [ "sockets.target" ];
+        listenStreams = [ "${icfg.listenAddress}:${toString icfg.listenPort}" ];
+        socketConfig.FileDescriptorName = "grpc";
+      }
+    ) (lib.filterAttrs (_: icfg: icfg.socketActivation) enabledInstances);
+
     # nginx virtual hosts for instances with a domain set
     services.nginx.virtualHosts = lib.mkIf (nginxInstances != { }) (
       lib.mapAttrs' (
@@ -329,8 +794,10 @@ in
             forceSSL = icfg.nginx.acme;
             locations."/" = {
               extraConfig = ''
-                # gRPC proxying
-                grpc_pass grpc://${icfg.listenAddress}:${toString icfg.listenPort};
+                # gRPC proxying, over TLS when the listener terminates it
+                grpc_pass ${
+                  if icfg.tls.certFile != null then "grpcs" else "grpc"
+                }://${icfg.listenAddress}:${toString icfg.listenPort};
 
                 # Forward authentication headers
                 grpc_set_header Authorization $http_authorization;
@@ -1,17 +1,66 @@
-use argon2::Argon2;
-use argon2::password_hash::PasswordHash;
-use argon2::password_hash::PasswordVerifier;
 use base64::Engine;
 use base64::engine::general_purpose::STANDARD;
 
-use crate::config::{Config, Credentials};
+use crate::auth_cache::VerifyCache;
+use crate::auth_pool::AuthPool;
+use crate::cidr::Cidr;
+use crate::config::{ClientIdentity, Config, Credentials};
 use crate::error::ProxyError;
+use crate::jwt::JwtValidator;
+use crate::net::PeerAddr;
+use crate::pattern::CallPattern;
+use crate::tls::ClientCertificate;
 
-pub fn authenticate(auth_header: &str, credentials: &Credentials) -> Result<String, ProxyError> {
-    let encoded = auth_header
-        .strip_prefix("Basic ")
-        .ok_or(ProxyError::AuthInvalid)?;
+/// An authenticated caller. `roles` holds roles granted by the credential
+/// itself (e.g. a JWT claim), on top of those configured for the user.
+#[derive(Debug)]
+pub struct Identity {
+    pub username: String,
+    pub roles: Vec<String>,
+}
+
+impl Identity {
+    pub fn user(username: String) -> Self {
+        Self {
+            username,
+            roles: Vec::new(),
+        }
+    }
+}
+
+pub async fn authenticate(
+    auth_header: &str,
+    credentials: &Credentials,
+    jwt: Option<&JwtValidator>,
+    cache: &VerifyCache,
+    pool: &AuthPool,
+) -> Result<Identity, ProxyError> {
+    if let Some(encoded) = auth_header.strip_prefix("Basic ") {
+        return authenticate_basic(encoded, credentials, cache, pool)
+            .await
+            .map(Identity::user);
+    }
 
+    let token = auth_header
+        .strip_prefix("Bearer ")
+        .ok_or(ProxyError::AuthInvalid)?
+        .trim();
+
+    // JWTs have three dot-separated segments, API tokens only two.
+    match jwt {
+        Some(jwt) if token.split('.').count() == 3 => jwt.validate(token),
+        _ => authenticate_token(token, credentials, cache, pool)
+            .await
+            .map(Identity::user),
+    }
+}
+
+async fn authenticate_basic(
+    encoded: &str,
+    credentials: &Credentials,
+    cache: &VerifyCache,
+    pool: &AuthPool,
+) -> Result<String, ProxyError> {
     let decoded = STANDARD
         .decode(encoded.trim())
         .map_err(|_| ProxyError::AuthInvalid)?;
@@ -25,31 +74,525 @@ pub fn authenticate(auth_header: &str, credentials: &Credentials) -> Result<Stri
         .get(username)
         .ok_or(ProxyError::AuthInvalid)?;
 
-    let parsed_hash = PasswordHash::new(stored_hash).map_err(|_| ProxyError::AuthInvalid)?;
-
-    Argon2::default()
-        .verify_password(password.as_bytes(), &parsed_hash)
-        .map_err(|_| ProxyError::AuthInvalid)?;
+    cache
+        .verify(password, stored_hash, pool.verify(password, stored_hash))
+        .await?;
 
     Ok(username.to_owned())
 }
 
-pub fn authorize(username: &str, grpc_path: &str, config: &Config) -> Result<(), ProxyError> {
-    let user_config = config
-        .users
-        .get(username)
-        .ok_or_else(|| ProxyError::AuthDenied(format!("no config for user '{username}'")))?;
+/// Tokens have the form `<name>.<secret>`. The name selects the credentials
+/// entry so only a single hash has to be verified.
+async fn authenticate_token(
+    token: &str,
+    credentials: &Credentials,
+    cache: &VerifyCache,
+    pool: &AuthPool,
+) -> Result<String, ProxyError> {
+    let (name, secret) = token.split_once('.').ok_or(ProxyError::AuthInvalid)?;
+
+    let api_token = credentials
+        .tokens
+        .get(name)
+        .ok_or(ProxyError::AuthInvalid)?;
+
+    cache
+        .verify(
+            secret,
+            &api_token.hash,
+            pool.verify(secret, &api_token.hash),
+        )
+        .await?;
+
+    tracing::debug!(user = %api_token.username, token = %name, "authenticated with API token");
+    Ok(api_token.username.clone())
+}
+
+/// Maps a verified client certificate to a configured user. The certificate
+/// chain itself has already been checked during the TLS handshake.
+pub fn authenticate_client_cert(
+    cert: &ClientCertificate,
+    identity: ClientIdentity,
+    config: &Config,
+) -> Result<Identity, ProxyError> {
+    let username = match identity {
+        ClientIdentity::Cn => cert
+            .common_name
+            .as_deref()
+            .filter(|cn| config.users.contains_key(*cn)),
+        ClientIdentity::San => cert
+            .subject_alt_names
+            .iter()
+            .map(String::as_str)
+            .find(|san| config.users.contains_key(*san)),
+    };
+
+    username
+        .map(|username| Identity::user(username.to_owned()))
+        .ok_or(ProxyError::AuthInvalid)
+}
+
+/// Evaluates deny rules before allow rules: a call matching any
+/// `denied_calls` entry (global, user or role) is rejected even if an
+/// `allowed_calls` entry also matches. Otherwise the first matching allow
+/// rule grants the call, and calls matching no rule are rejected.
+pub fn authorize(identity: &Identity, grpc_path: &str, config: &Config) -> Result<(), ProxyError> {
+    let username = identity.username.as_str();
+    let user_config = config.users.get(username);
+    if user_config.is_none() && identity.roles.is_empty() {
+        return Err(ProxyError::AuthDenied(format!(
+            "no config for user '{username}'"
+        )));
+    }
 
     // Strip leading slash from path: "/package.Service/Method" → "package.Service/Method"
     let call = grpc_path.strip_prefix('/').unwrap_or(grpc_path);
 
-    for allowed in &user_config.allowed_calls {
-        if allowed == "*" || allowed == call {
+    let user_roles = user_config.into_iter().flat_map(|user| &user.roles);
+    let roles = config.expand_roles(user_roles.chain(&identity.roles).map(String::as_str));
+
+    let mut rule_sets = vec![(RuleSource::Global, &[][..], &config.denied_calls[..])];
+    if let Some(user) = user_config {
+        rule_sets.push((RuleSource::User, &user.allowed_calls, &user.denied_calls));
+    }
+    for role in &roles {
+        let role_config = &config.roles[*role];
+        rule_sets.push((
+            RuleSource::Role(role),
+            &role_config.allowed_calls,
+            &role_config.denied_calls,
+        ));
+    }
+
+    for (source, _, denied_calls) in &rule_sets {
+        if let Some(rule) = matching_rule(denied_calls, call) {
+            tracing::debug!(user = %username, call = %call, %rule, "denied by {source} rule");
+            return Err(ProxyError::AuthDenied(format!(
+                "user '{username}' denied '{call}' by {source} rule '{rule}'"
+            )));
+        }
+    }
+
+    for (source, allowed_calls, _) in &rule_sets {
+        if let Some(rule) = matching_rule(allowed_calls, call) {
+            tracing::debug!(user = %username, call = %call, %rule, "allowed by {source} rule");
             return Ok(());
         }
     }
 
-    Err(ProxyError::AuthDenied(format!(
-        "user '{username}' not allowed to call '{call}'"
-    )))
+    tracing::debug!(user = %username, call = %call, roles = ?roles, "no allow rule matched");
+    if roles.is_empty() {
+        Err(ProxyError::AuthDenied(format!(
+            "user '{username}' not allowed to call '{call}'"
+        )))
+    } else {
+        Err(ProxyError::AuthDenied(format!(
+            "user '{username}' not allowed to call '{call}' (roles: {})",
+            roles.join(", ")
+        )))
+    }
+}
+
+/// Checks the caller's address against the global `allowed_networks` and
+/// `denied_networks`. Runs before authentication, so blocked networks never
+/// get to try credentials.
+pub fn authorize_address(client_addr: PeerAddr, config: &Config) -> Result<(), ProxyError> {
+    check_networks(
+        client_addr,
+        &config.allowed_networks,
+        &config.denied_networks,
+    )
+    .map_err(|reason| ProxyError::AddressDenied(format!("{client_addr} {reason}")))
+}
+
+/// Checks the caller's address against the user's own network lists.
+pub fn authorize_user_address(
+    identity: &Identity,
+    client_addr: PeerAddr,
+    config: &Config,
+) -> Result<(), ProxyError> {
+    let Some(user) = config.users.get(&identity.username) else {
+        return Ok(());
+    };
+    check_networks(client_addr, &user.allowed_networks, &user.denied_networks).map_err(|reason| {
+        ProxyError::AddressDenied(format!(
+            "user '{}' not allowed from {client_addr}: {reason}",
+            identity.username
+        ))
+    })
+}
+
+/// Deny overrides allow, as for calls. A caller without an IP address, on a
+/// Unix socket, is in no network.
+fn check_networks(client_addr: PeerAddr, allowed: &[Cidr], denied: &[Cidr]) -> Result<(), String> {
+    let in_network = |network: &Cidr| client_addr.ip().is_some_and(|ip| network.contains(ip));
+    if let Some(network) = denied.iter().find(|network| in_network(network)) {
+        return Err(format!("is in denied network {network}"));
+    }
+    if !allowed.is_empty() && !allowed.iter().any(in_network) {
+        return Err("is not in an allowed network".to_owned());
+    }
+    Ok(())
+}
+
+/// Where an authorization rule was configured, for denial messages and logs.
+enum RuleSource<'a> {
+    Global,
+    User,
+    Role(&'a str),
+}
+
+impl std::fmt::Display for RuleSource<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Global => f.write_str("global"),
+            Self::User => f.write_str("user"),
+            Self::Role(role) => write!(f, "role '{role}'"),
+        }
+    }
+}
+
+fn matching_rule<'a>(rules: &'a [CallPattern], call: &str) -> Option<&'a CallPattern> {
+    rules.iter().find(|rule| rule.matches(call))
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::password_hash::{PasswordHasher, SaltString};
+    use argon2::{Algorithm, Argon2, Params, Version};
+    use prometheus::{Histogram, HistogramOpts, IntCounter};
+
+    use super::*;
+    use crate::config::{ApiToken, AuthCacheConfig, AuthPoolConfig, JwtConfig};
+
+    /// Cheap argon2 parameters; verification reads them from the hash.
+    fn hash(secret: &str) -> String {
+        let params = Params::new(8, 1, 1, None).unwrap();
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password(
+                secret.as_bytes(),
+                &SaltString::from_b64("c2FsdHNhbHQ").unwrap(),
+            )
+            .unwrap()
+            .to_string()
+    }
+
+    fn test_credentials() -> Credentials {
+        let mut credentials = Credentials::empty();
+        credentials
+            .users
+            .insert("alice".to_owned(), hash("wonderland"));
+        credentials.tokens.insert(
+            "ci".to_owned(),
+            ApiToken {
+                username: "bob".to_owned(),
+                hash: hash("s3cret"),
+            },
+        );
+        credentials
+    }
+
+    fn pool() -> AuthPool {
+        let queue_wait = Histogram::with_opts(HistogramOpts::new("queue_wait", "help")).unwrap();
+        AuthPool::new(&AuthPoolConfig::default(), queue_wait).unwrap()
+    }
+
+    fn cache() -> VerifyCache {
+        VerifyCache::new(
+            &AuthCacheConfig::default(),
+            IntCounter::new("hits", "help").unwrap(),
+            IntCounter::new("misses", "help").unwrap(),
+        )
+    }
+
+    async fn hmac_validator() -> JwtValidator {
+        let path = std::env::temp_dir().join(format!("grpc-proxier-auth-{}", std::process::id()));
+        std::fs::write(&path, "jwt secret").unwrap();
+        let config: JwtConfig = toml::from_str(&format!(
+            "algorithms = [\"HS256\"]\nsecret_path = {:?}",
+            path.to_str().unwrap()
+        ))
+        .unwrap();
+        let validator = JwtValidator::new(&config).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        validator
+    }
+
+    async fn login(header: &str, jwt: Option<&JwtValidator>) -> Result<Identity, ProxyError> {
+        authenticate(header, &test_credentials(), jwt, &cache(), &pool()).await
+    }
+
+    #[tokio::test]
+    async fn accepts_passwords_and_api_tokens() {
+        let basic = format!("Basic {}", STANDARD.encode("alice:wonderland"));
+        assert_eq!(login(&basic, None).await.unwrap().username, "alice");
+        assert_eq!(
+            login("Bearer ci.s3cret", None).await.unwrap().username,
+            "bob"
+        );
+    }
+
+    #[tokio::test]
+    async fn api_tokens_still_work_with_jwt_configured() {
+        let jwt = hmac_validator().await;
+        let identity = login("Bearer ci.s3cret", Some(&jwt)).await.unwrap();
+        assert_eq!(identity.username, "bob");
+        assert!(identity.roles.is_empty());
+        assert!(matches!(
+            login("Bearer ci.wrong", Some(&jwt)).await,
+            Err(ProxyError::AuthInvalid)
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_users_and_tokens() {
+        let basic = format!("Basic {}", STANDARD.encode("mallory:wonderland"));
+        for header in [
+            basic.as_str(),
+            "Bearer nope.s3cret",
+            "Bearer s3cret",
+            "Token ci.s3cret",
+        ] {
+            assert!(
+                matches!(login(header, None).await, Err(ProxyError::AuthInvalid)),
+                "{header}"
+            );
+        }
+    }
+
+    fn load_config(toml: &str) -> Config {
+        toml::from_str(&format!("metrics_address = \"127.0.0.1:9090\"\n{toml}")).unwrap()
+    }
+
+    fn claimed(username: &str, roles: &[&str]) -> Identity {
+        Identity {
+            username: username.to_owned(),
+            roles: roles.iter().map(|role| (*role).to_owned()).collect(),
+        }
+    }
+
+    const ROLES: &str = r#"
+        [roles.reader]
+        allowed_calls = ["shop.Catalog/Get*"]
+        [roles.writer]
+        inherits = ["reader"]
+        allowed_calls = ["shop.Catalog/Put*"]
+
+        [users.alice]
+        roles = ["writer"]
+    "#;
+
+    #[test]
+    fn inherited_roles_grant_their_calls() {
+        let config = load_config(ROLES);
+        let alice = Identity::user("alice".to_owned());
+        assert!(authorize(&alice, "/shop.Catalog/GetItem", &config).is_ok());
+        assert!(authorize(&alice, "/shop.Catalog/PutItem", &config).is_ok());
+        assert_eq!(
+            denial(&alice, "/shop.Catalog/DeleteItem", &config),
+            "user 'alice' not allowed to call 'shop.Catalog/DeleteItem' (roles: writer, reader)"
+        );
+    }
+
+    #[test]
+    fn claimed_roles_admit_users_without_config() {
+        let config = load_config(ROLES);
+        let carol = claimed("carol", &["reader"]);
+        assert!(authorize(&carol, "/shop.Catalog/GetItem", &config).is_ok());
+        assert!(authorize(&carol, "/shop.Catalog/PutItem", &config).is_err());
+
+        // Claimed roles add to the configured ones.
+        let alice = claimed("alice", &["reader"]);
+        assert!(authorize(&alice, "/shop.Catalog/PutItem", &config).is_ok());
+    }
+
+    #[test]
+    fn unknown_claimed_roles_are_ignored() {
+        let config = load_config(ROLES);
+        let mallory = claimed("mallory", &["admin"]);
+        assert!(authorize(&mallory, "/shop.Catalog/GetItem", &config).is_err());
+
+        let carol = claimed("carol", &["admin", "reader"]);
+        assert!(authorize(&carol, "/shop.Catalog/GetItem", &config).is_ok());
+        assert!(authorize(&carol, "/shop.Catalog/PutItem", &config).is_err());
+
+        let nobody = Identity::user("nobody".to_owned());
+        assert_eq!(
+            denial(&nobody, "/shop.Catalog/GetItem", &config),
+            "no config for user 'nobody'"
+        );
+    }
+
+    fn denial(identity: &Identity, path: &str, config: &Config) -> String {
+        match authorize(identity, path, config) {
+            Err(ProxyError::AuthDenied(message)) => message,
+            other => panic!("expected {path} to be denied, got {other:?}"),
+        }
+    }
+
+    const RULES: &str = r#"
+        denied_calls = ["admin.*"]
+
+        [roles.auditor]
+        denied_calls = ["shop.Catalog/Delete*"]
+
+        [users.alice]
+        allowed_calls = ["*"]
+        denied_calls = ["shop.Orders/Cancel"]
+        roles = ["auditor"]
+    "#;
+
+    #[test]
+    fn deny_rules_beat_user_allow_rules() {
+        let config = load_config(RULES);
+        let alice = Identity::user("alice".to_owned());
+        assert!(authorize(&alice, "/shop.Catalog/GetItem", &config).is_ok());
+        assert_eq!(
+            denial(&alice, "/admin.Users/Create", &config),
+            "user 'alice' denied 'admin.Users/Create' by global rule 'admin.*'"
+        );
+        assert_eq!(
+            denial(&alice, "/shop.Orders/Cancel", &config),
+            "user 'alice' denied 'shop.Orders/Cancel' by user rule 'shop.Orders/Cancel'"
+        );
+        assert_eq!(
+            denial(&alice, "/shop.Catalog/DeleteItem", &config),
+            "user 'alice' denied 'shop.Catalog/DeleteItem' by role 'auditor' rule 'shop.Catalog/Delete*'"
+        );
+    }
+
+    #[test]
+    fn calls_matching_no_rule_are_denied() {
+        let config = load_config(
+            r#"
+            [users.bob]
+            allowed_calls = ["shop.Catalog/*"]
+            "#,
+        );
+        let bob = Identity::user("bob".to_owned());
+        assert!(authorize(&bob, "/shop.Catalog/GetItem", &config).is_ok());
+        assert_eq!(
+            denial(&bob, "/shop.Orders/Get", &config),
+            "user 'bob' not allowed to call 'shop.Orders/Get'"
+        );
+
+        let config = load_config("[users.bob]");
+        assert_eq!(
+            denial(&bob, "/shop.Catalog/GetItem", &config),
+            "user 'bob' not allowed to call 'shop.Catalog/GetItem'"
+        );
+    }
+
+    fn certificate(common_name: Option<&str>, subject_alt_names: &[&str]) -> ClientCertificate {
+        ClientCertificate {
+            common_name: common_name.map(str::to_owned),
+            subject_alt_names: subject_alt_names
+                .iter()
+                .map(|name| (*name).to_owned())
+                .collect(),
+        }
+    }
+
+    const CERT_USERS: &str = r#"
+        [users.alice]
+        [users."bob.example.com"]
+    "#;
+
+    #[test]
+    fn client_cert_common_name_names_the_user() {
+        let config = load_config(CERT_USERS);
+        let cert = certificate(Some("alice"), &["bob.example.com"]);
+        let identity = authenticate_client_cert(&cert, ClientIdentity::Cn, &config).unwrap();
+        assert_eq!(identity.username, "alice");
+        assert!(identity.roles.is_empty());
+    }
+
+    #[test]
+    fn client_cert_first_known_san_names_the_user() {
+        let config = load_config(CERT_USERS);
+        let cert = certificate(
+            Some("alice"),
+            &["mallory.example.com", "bob.example.com", "alice"],
+        );
+        let identity = authenticate_client_cert(&cert, ClientIdentity::San, &config).unwrap();
+        assert_eq!(identity.username, "bob.example.com");
+    }
+
+    #[test]
+    fn client_cert_matching_no_user_is_invalid() {
+        let config = load_config(CERT_USERS);
+        let cert = certificate(Some("mallory"), &["mallory.example.com"]);
+        for identity in [ClientIdentity::Cn, ClientIdentity::San] {
+            assert!(matches!(
+                authenticate_client_cert(&cert, identity, &config),
+                Err(ProxyError::AuthInvalid)
+            ));
+        }
+        // The CN is only consulted in CN mode, SANs only in SAN mode.
+        let cert = certificate(Some("bob.example.com"), &["alice"]);
+        assert!(authenticate_client_cert(&cert, ClientIdentity::San, &config).is_ok());
+        let cert = certificate(Some("mallory"), &["alice"]);
+        assert!(authenticate_client_cert(&cert, ClientIdentity::Cn, &config).is_err());
+    }
+
+    #[test]
+    fn client_cert_without_common_name() {
+        let config = load_config(CERT_USERS);
+        let cert = certificate(None, &["bob.example.com"]);
+        assert!(matches!(
+            authenticate_client_cert(&cert, ClientIdentity::Cn, &config),
+            Err(ProxyError::AuthInvalid)
+        ));
+        let identity = authenticate_client_cert(&cert, ClientIdentity::San, &config).unwrap();
+        assert_eq!(identity.username, "bob.example.com");
+    }
+
+    fn networks(sources: &[&str]) -> Vec<Cidr> {
+        sources
+            .iter()
+            .map(|source| Cidr::try_from((*source).to_owned()).unwrap())
+            .collect()
+    }
+
+    fn peer(address: &str) -> PeerAddr {
+        PeerAddr::Forwarded(address.parse().unwrap())
+    }
+
+    #[test]
+    fn no_lists_allow_everyone() {
+        assert!(check_networks(peer("192.0.2.1"), &[], &[]).is_ok());
+        assert!(check_networks(PeerAddr::Unix, &[], &[]).is_ok());
+    }
+
+    #[test]
+    fn allow_list_rejects_everything_else() {
+        let allowed = networks(&["10.0.0.0/8", "2001:db8::/32"]);
+        assert!(check_networks(peer("10.1.2.3"), &allowed, &[]).is_ok());
+        assert!(check_networks(peer("2001:db8::5"), &allowed, &[]).is_ok());
+        assert!(check_networks(peer("::ffff:10.1.2.3"), &allowed, &[]).is_ok());
+        assert_eq!(
+            check_networks(peer("192.0.2.1"), &allowed, &[]).unwrap_err(),
+            "is not in an allowed network"
+        );
+    }
+
+    #[test]
+    fn deny_overrides_allow() {
+        let allowed = networks(&["10.0.0.0/8"]);
+        let denied = networks(&["10.1.0.0/16"]);
+        assert!(check_networks(peer("10.2.0.1"), &allowed, &denied).is_ok());
+        assert_eq!(
+            check_networks(peer("10.1.0.1"), &allowed, &denied).unwrap_err(),
+            "is in denied network 10.1.0.0/16"
+        );
+        // A deny list alone leaves everyone else in.
+        assert!(check_networks(peer("192.0.2.1"), &[], &denied).is_ok());
+    }
+
+    #[test]
+    fn callers_without_an_address_are_in_no_network() {
+        let all = networks(&["0.0.0.0/0", "::/0"]);
+        assert!(check_networks(PeerAddr::Unix, &[], &all).is_ok());
+        assert!(check_networks(PeerAddr::Unix, &all, &[]).is_err());
+    }
 }
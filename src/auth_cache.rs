@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use hmac::{Hmac, Mac};
+use prometheus::IntCounter;
+use sha2::Sha256;
+
+use crate::config::AuthCacheConfig;
+
+type CacheKey = [u8; 32];
+
+/// Remembers successful argon2 verifications for a limited time.
+///
+/// Entries are keyed by an HMAC over the stored hash and the presented secret,
+/// using a key generated at startup, so the cache never holds a secret in a
+/// form that can be brute-forced offline. Including the stored hash means an
+/// entry stops matching as soon as the credential is changed. Failed
+/// verifications are never cached.
+pub struct VerifyCache {
+    key: CacheKey,
+    ttl: Duration,
+    max_entries: usize,
+    entries: Mutex<HashMap<CacheKey, Instant>>,
+    hits: IntCounter,
+    misses: IntCounter,
+}
+
+impl VerifyCache {
+    pub fn new(config: &AuthCacheConfig, hits: IntCounter, misses: IntCounter) -> Self {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        Self {
+            key,
+            ttl: Duration::from_secs(config.ttl_secs),
+            max_entries: config.max_entries,
+            entries: Mutex::new(HashMap::new()),
+            hits,
+            misses,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        !self.ttl.is_zero() && self.max_entries > 0
+    }
+
+    /// Runs `verify` unless the same secret was recently verified against the
+    /// same stored hash.
+    pub async fn verify<E>(
+        &self,
+        secret: &str,
+        stored_hash: &str,
+        verify: impl Future<Output = Result<(), E>>,
+    ) -> Result<(), E> {
+        if !self.enabled() {
+            return verify.await;
+        }
+
+        let key = self.cache_key(secret, stored_hash);
+        let now = Instant::now();
+
+        if self.lock().get(&key).is_some_and(|expires| *expires > now) {
+            self.hits.inc();
+            return Ok(());
+        }
+
+        self.misses.inc();
+        verify.await?;
+        self.insert(key, now + self.ttl);
+        Ok(())
+    }
+
+    fn insert(&self, key: CacheKey, expires: Instant) {
+        let mut entries = self.lock();
+
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            let now = Instant::now();
+            entries.retain(|_, expires| *expires > now);
+
+            if entries.len() >= self.max_entries {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, expires)| **expires)
+                    .map(|(key, _)| *key);
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+
+        entries.insert(key, expires);
+    }
+
+    fn cache_key(&self, secret: &str, stored_hash: &str) -> CacheKey {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(stored_hash.as_bytes());
+        mac.update(&[0]);
+        mac.update(secret.as_bytes());
+        mac.finalize().into_bytes().into()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<CacheKey, Instant>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    fn cache(ttl: Duration, max_entries: usize) -> VerifyCache {
+        let mut cache = VerifyCache::new(
+            &AuthCacheConfig::default(),
+            IntCounter::new("hits", "help").unwrap(),
+            IntCounter::new("misses", "help").unwrap(),
+        );
+        cache.ttl = ttl;
+        cache.max_entries = max_entries;
+        cache
+    }
+
+    /// Verifies through the cache, counting how often the real check ran.
+    async fn check(
+        cache: &VerifyCache,
+        secret: &str,
+        stored_hash: &str,
+        valid: bool,
+        calls: &Cell<u32>,
+    ) -> Result<(), ()> {
+        cache
+            .verify(secret, stored_hash, async {
+                calls.set(calls.get() + 1);
+                if valid { Ok(()) } else { Err(()) }
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn hits_within_ttl() {
+        let cache = cache(Duration::from_secs(60), 16);
+        let calls = Cell::new(0);
+        for _ in 0..3 {
+            assert!(check(&cache, "secret", "hash", true, &calls).await.is_ok());
+        }
+        assert_eq!(calls.get(), 1);
+        assert_eq!(cache.hits.get(), 2);
+        assert_eq!(cache.misses.get(), 1);
+
+        // Another secret is a separate entry.
+        assert!(check(&cache, "other", "hash", true, &calls).await.is_ok());
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn entries_expire() {
+        let cache = cache(Duration::from_millis(20), 16);
+        let calls = Cell::new(0);
+        assert!(check(&cache, "secret", "hash", true, &calls).await.is_ok());
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(
+            check(&cache, "secret", "hash", false, &calls)
+                .await
+                .is_err()
+        );
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn changed_hash_misses() {
+        let cache = cache(Duration::from_secs(60), 16);
+        let calls = Cell::new(0);
+        assert!(
+            check(&cache, "secret", "old hash", true, &calls)
+                .await
+                .is_ok()
+        );
+        assert!(
+            check(&cache, "secret", "new hash", false, &calls)
+                .await
+                .is_err()
+        );
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn failures_are_not_cached() {
+        let cache = cache(Duration::from_secs(60), 16);
+        let calls = Cell::new(0);
+        assert!(check(&cache, "wrong", "hash", false, &calls).await.is_err());
+        assert!(check(&cache, "wrong", "hash", false, &calls).await.is_err());
+        assert_eq!(calls.get(), 2);
+        assert!(cache.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn evicts_the_oldest_entry_at_capacity() {
+        let cache = cache(Duration::from_secs(60), 2);
+        let calls = Cell::new(0);
+        for secret in ["first", "second", "third"] {
+            assert!(check(&cache, secret, "hash", true, &calls).await.is_ok());
+            // Distinct expiry times make the oldest entry unambiguous.
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        assert_eq!(cache.lock().len(), 2);
+
+        assert!(check(&cache, "third", "hash", true, &calls).await.is_ok());
+        assert!(check(&cache, "second", "hash", true, &calls).await.is_ok());
+        assert_eq!(calls.get(), 3);
+        assert!(check(&cache, "first", "hash", true, &calls).await.is_ok());
+        assert_eq!(calls.get(), 4);
+    }
+
+    #[tokio::test]
+    async fn disabled_cache_always_verifies() {
+        let calls = Cell::new(0);
+        for cache in [cache(Duration::ZERO, 16), cache(Duration::from_secs(60), 0)] {
+            assert!(check(&cache, "secret", "hash", true, &calls).await.is_ok());
+            assert!(check(&cache, "secret", "hash", true, &calls).await.is_ok());
+        }
+        assert_eq!(calls.get(), 4);
+    }
+}
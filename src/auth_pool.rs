@@ -0,0 +1,164 @@
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Instant;
+
+use argon2::Argon2;
+use argon2::password_hash::{PasswordHash, PasswordVerifier};
+use prometheus::Histogram;
+use tokio::sync::oneshot;
+
+use crate::config::AuthPoolConfig;
+use crate::error::ProxyError;
+
+struct Job {
+    secret: String,
+    stored_hash: String,
+    queued_at: Instant,
+    reply: oneshot::Sender<bool>,
+}
+
+/// Runs argon2 verification on a fixed set of dedicated threads, so a burst
+/// of logins cannot stall the async runtime. The queue in front of the
+/// workers is bounded; once it is full callers are turned away instead of
+/// piling up.
+pub struct AuthPool {
+    sender: SyncSender<Job>,
+}
+
+impl AuthPool {
+    pub fn new(config: &AuthPoolConfig, queue_wait: Histogram) -> Result<Self, ProxyError> {
+        let (sender, receiver) = mpsc::sync_channel(config.queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for index in 0..config.concurrency {
+            let receiver = Arc::clone(&receiver);
+            let queue_wait = queue_wait.clone();
+            std::thread::Builder::new()
+                .name(format!("auth-pool-{index}"))
+                .spawn(move || worker(&receiver, &queue_wait))
+                .map_err(|e| ProxyError::AuthPoolStart(format!("worker thread: {e}")))?;
+        }
+
+        Ok(Self { sender })
+    }
+
+    pub async fn verify(&self, secret: &str, stored_hash: &str) -> Result<(), ProxyError> {
+        let (reply, result) = oneshot::channel();
+        let job = Job {
+            secret: secret.to_owned(),
+            stored_hash: stored_hash.to_owned(),
+            queued_at: Instant::now(),
+            reply,
+        };
+
+        match self.sender.try_send(job) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => return Err(ProxyError::AuthOverloaded),
+            Err(TrySendError::Disconnected(_)) => {
+                tracing::error!("auth pool has no workers left");
+                return Err(ProxyError::AuthUnavailable);
+            }
+        }
+
+        match result.await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(ProxyError::AuthInvalid),
+            // The worker died without answering.
+            Err(_) => Err(ProxyError::AuthUnavailable),
+        }
+    }
+}
+
+fn worker(receiver: &Mutex<Receiver<Job>>, queue_wait: &Histogram) {
+    loop {
+        let job = match receiver
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .recv()
+        {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+
+        queue_wait.observe(job.queued_at.elapsed().as_secs_f64());
+
+        // The request was cancelled while queued, don't burn a hash on it.
+        if job.reply.is_closed() {
+            continue;
+        }
+
+        let valid = verify_password(&job.secret, &job.stored_hash);
+        let _ = job.reply.send(valid);
+    }
+}
+
+fn verify_password(password: &str, stored_hash: &str) -> bool {
+    PasswordHash::new(stored_hash).is_ok_and(|parsed_hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &parsed_hash)
+            .is_ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::HistogramOpts;
+
+    use super::*;
+
+    fn queue_wait() -> Histogram {
+        Histogram::with_opts(HistogramOpts::new("queue_wait", "help")).unwrap()
+    }
+
+    fn pool(concurrency: usize, queue_depth: usize) -> AuthPool {
+        let config = AuthPoolConfig {
+            concurrency,
+            queue_depth,
+        };
+        AuthPool::new(&config, queue_wait()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn verifies_on_worker_threads() {
+        let pool = pool(2, 4);
+        // Argon2id with minimal cost parameters, for the password "secret".
+        let hash =
+            "$argon2id$v=19$m=8,t=1,p=1$c2FsdHNhbHQ$vk+JgWgZGbXHtEqPQPhzXbD+QxxkJR5Fq2AXLhDyYaY";
+        assert!(pool.verify("secret", hash).await.is_ok());
+        assert!(matches!(
+            pool.verify("wrong", hash).await,
+            Err(ProxyError::AuthInvalid)
+        ));
+        assert!(matches!(
+            pool.verify("secret", "not a hash").await,
+            Err(ProxyError::AuthInvalid)
+        ));
+    }
+
+    #[tokio::test]
+    async fn full_queue_is_resource_exhausted() {
+        // Keep the receiving end without a worker, so queued jobs stay put.
+        let (sender, _receiver) = mpsc::sync_channel(1);
+        let pool = AuthPool { sender };
+        let (reply, _result) = oneshot::channel();
+        pool.sender
+            .try_send(Job {
+                secret: String::new(),
+                stored_hash: String::new(),
+                queued_at: Instant::now(),
+                reply,
+            })
+            .unwrap();
+
+        let error = pool.verify("secret", "hash").await.unwrap_err();
+        assert!(matches!(error, ProxyError::AuthOverloaded));
+        assert_eq!(error.grpc_status_code(), 8);
+    }
+
+    #[tokio::test]
+    async fn pool_without_workers_is_unavailable() {
+        let error = pool(0, 1).verify("secret", "hash").await.unwrap_err();
+        assert!(matches!(error, ProxyError::AuthUnavailable));
+        assert_eq!(error.grpc_status_code(), 14);
+    }
+}
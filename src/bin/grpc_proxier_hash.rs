@@ -1,8 +1,24 @@
 use argon2::Argon2;
-use argon2::password_hash::{PasswordHasher, SaltString, rand_core::OsRng};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::password_hash::{PasswordHasher, SaltString};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use std::io::BufRead;
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [] => hash_stdin_password(),
+        [flag, name] if flag == "--token" => generate_token(name),
+        _ => {
+            eprintln!("Usage: grpc-proxier-hash            (hash a password read from stdin)");
+            eprintln!("       grpc-proxier-hash --token NAME  (generate an API token)");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn hash_stdin_password() {
     let password = std::io::stdin()
         .lock()
         .lines()
@@ -21,10 +37,29 @@ fn main() {
         std::process::exit(1);
     }
 
-    let salt = SaltString::generate(&mut OsRng);
-    let hash = Argon2::default()
-        .hash_password(password.as_bytes(), &salt)
-        .expect("failed to hash password");
+    println!("{}", hash(&password));
+}
 
-    println!("{hash}");
+/// Prints the bearer token for the client on the first line and the argon2
+/// hash of its secret for the credentials file on the second.
+fn generate_token(name: &str) {
+    if name.is_empty() || name.contains(['.', ':']) {
+        eprintln!("Error: token name must be non-empty and must not contain '.' or ':'");
+        std::process::exit(1);
+    }
+
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    let secret = URL_SAFE_NO_PAD.encode(secret);
+
+    println!("{name}.{secret}");
+    println!("{}", hash(&secret));
+}
+
+fn hash(secret: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(secret.as_bytes(), &salt)
+        .expect("failed to hash password")
+        .to_string()
 }
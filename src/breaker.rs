@@ -0,0 +1,427 @@
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use prometheus::{IntCounterVec, IntGauge};
+
+use crate::config::CircuitBreakerConfig;
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { probes: u32, successes: u32 },
+}
+
+impl Mode {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Closed => "closed",
+            Self::Open { .. } => "open",
+            Self::HalfOpen { .. } => "half_open",
+        }
+    }
+
+    /// Value of the `upstream_circuit_state` gauge.
+    fn gauge_value(self) -> i64 {
+        match self {
+            Self::Closed => 0,
+            Self::Open { .. } => 1,
+            Self::HalfOpen { .. } => 2,
+        }
+    }
+}
+
+struct State {
+    mode: Mode,
+    /// Bumped on every transition. Permits from an earlier epoch are stale:
+    /// their results and releases belong to a state that no longer exists.
+    epoch: u64,
+    consecutive_failures: u32,
+    window_start: Instant,
+    window_requests: u32,
+    window_failures: u32,
+}
+
+/// Stops sending calls to an endpoint that keeps failing. After `open_secs`
+/// a few probe calls are let through; if they all succeed the endpoint is
+/// back in use, otherwise the breaker opens again.
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    state: Mutex<State>,
+    gauge: IntGauge,
+    transitions: IntCounterVec,
+    /// `upstream` and `endpoint` label values.
+    labels: [String; 2],
+}
+
+impl CircuitBreaker {
+    pub fn new(
+        config: CircuitBreakerConfig,
+        gauge: IntGauge,
+        transitions: IntCounterVec,
+        labels: [String; 2],
+    ) -> Self {
+        gauge.set(Mode::Closed.gauge_value());
+        Self {
+            config,
+            state: Mutex::new(State {
+                mode: Mode::Closed,
+                epoch: 0,
+                consecutive_failures: 0,
+                window_start: Instant::now(),
+                window_requests: 0,
+                window_failures: 0,
+            }),
+            gauge,
+            transitions,
+            labels,
+        }
+    }
+
+    /// Whether a call could be let through right now. Used to pick among
+    /// endpoints; the call itself still needs [`CircuitBreaker::acquire`].
+    pub fn is_available(&self) -> bool {
+        match self.lock().mode {
+            Mode::Closed => true,
+            Mode::Open { until } => Instant::now() >= until,
+            Mode::HalfOpen { probes, .. } => probes < self.config.half_open_requests,
+        }
+    }
+
+    pub fn acquire(&self) -> Option<Permit<'_>> {
+        let mut state = self.lock();
+        let probe = match state.mode {
+            Mode::Closed => false,
+            Mode::Open { until } if Instant::now() >= until => {
+                self.transition(
+                    &mut state,
+                    Mode::HalfOpen {
+                        probes: 0,
+                        successes: 0,
+                    },
+                );
+                true
+            }
+            Mode::Open { .. } => return None,
+            Mode::HalfOpen { probes, .. } if probes >= self.config.half_open_requests => {
+                return None;
+            }
+            Mode::HalfOpen { .. } => true,
+        };
+        if let Mode::HalfOpen { probes, .. } = &mut state.mode
+            && probe
+        {
+            *probes += 1;
+        }
+
+        Some(Permit {
+            breaker: self,
+            epoch: state.epoch,
+            probe,
+            recorded: false,
+        })
+    }
+
+    fn record(&self, epoch: u64, probe: bool, success: bool) {
+        let mut state = self.lock();
+        // Calls that started before the breaker changed state don't count.
+        if epoch != state.epoch {
+            return;
+        }
+        match (state.mode, probe) {
+            (Mode::HalfOpen { probes, successes }, true) => {
+                if !success {
+                    self.open(&mut state);
+                } else if successes + 1 >= self.config.half_open_requests {
+                    self.transition(&mut state, Mode::Closed);
+                } else {
+                    state.mode = Mode::HalfOpen {
+                        probes: probes - 1,
+                        successes: successes + 1,
+                    };
+                }
+            }
+            (Mode::Closed, false) => {
+                let now = Instant::now();
+                if now.duration_since(state.window_start)
+                    >= Duration::from_secs(self.config.window_secs)
+                {
+                    state.window_start = now;
+                    state.window_requests = 0;
+                    state.window_failures = 0;
+                }
+                state.window_requests += 1;
+                if success {
+                    state.consecutive_failures = 0;
+                    return;
+                }
+                state.consecutive_failures += 1;
+                state.window_failures += 1;
+
+                let ratio_exceeded = self.config.failure_ratio.is_some_and(|ratio| {
+                    state.window_requests >= self.config.min_requests
+                        && f64::from(state.window_failures) / f64::from(state.window_requests)
+                            >= ratio
+                });
+                if state.consecutive_failures >= self.config.consecutive_failures || ratio_exceeded
+                {
+                    self.open(&mut state);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn release(&self, epoch: u64) {
+        let mut state = self.lock();
+        if epoch != state.epoch {
+            return;
+        }
+        if let Mode::HalfOpen { probes, .. } = &mut state.mode {
+            *probes = probes.saturating_sub(1);
+        }
+    }
+
+    fn open(&self, state: &mut State) {
+        let until = Instant::now() + Duration::from_secs(self.config.open_secs);
+        self.transition(state, Mode::Open { until });
+    }
+
+    fn transition(&self, state: &mut State, mode: Mode) {
+        let [upstream, endpoint] = &self.labels;
+        match mode {
+            Mode::Open { .. } => tracing::warn!(%upstream, %endpoint, "circuit breaker opened"),
+            _ => tracing::info!(%upstream, %endpoint, "circuit breaker {}", mode.name()),
+        }
+
+        state.mode = mode;
+        state.epoch += 1;
+        state.consecutive_failures = 0;
+        state.window_start = Instant::now();
+        state.window_requests = 0;
+        state.window_failures = 0;
+        self.gauge.set(mode.gauge_value());
+        self.transitions
+            .with_label_values(&[upstream, endpoint, mode.name()])
+            .inc();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A call let through by the breaker. Dropping it unrecorded, e.g. when the
+/// call is cancelled, frees a probe slot without counting as a result.
+pub struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    epoch: u64,
+    probe: bool,
+    recorded: bool,
+}
+
+impl Permit<'_> {
+    pub fn record(mut self, success: bool) {
+        self.recorded = true;
+        self.breaker.record(self.epoch, self.probe, success);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if !self.recorded && self.probe {
+            self.breaker.release(self.epoch);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::Opts;
+
+    use super::*;
+
+    fn build_breaker(toml: &str) -> CircuitBreaker {
+        let transitions = IntCounterVec::new(
+            Opts::new("transitions", "help"),
+            &["upstream", "endpoint", "state"],
+        )
+        .unwrap();
+        CircuitBreaker::new(
+            toml::from_str(toml).unwrap(),
+            IntGauge::new("state", "help").unwrap(),
+            transitions,
+            ["backend".to_owned(), "10.0.0.1:50051".to_owned()],
+        )
+    }
+
+    fn call(breaker: &CircuitBreaker, success: bool) {
+        breaker.acquire().unwrap().record(success);
+    }
+
+    fn mode(breaker: &CircuitBreaker) -> &'static str {
+        breaker.lock().mode.name()
+    }
+
+    /// Lets the open period run out.
+    fn expire(breaker: &CircuitBreaker) {
+        let mut state = breaker.lock();
+        assert!(matches!(state.mode, Mode::Open { .. }));
+        state.mode = Mode::Open {
+            until: Instant::now(),
+        };
+    }
+
+    fn transitions(breaker: &CircuitBreaker, state: &str) -> u64 {
+        breaker
+            .transitions
+            .with_label_values(&["backend", "10.0.0.1:50051", state])
+            .get()
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = build_breaker("consecutive_failures = 3");
+        call(&breaker, false);
+        call(&breaker, false);
+        call(&breaker, true);
+        call(&breaker, false);
+        call(&breaker, false);
+        assert_eq!(mode(&breaker), "closed");
+
+        call(&breaker, false);
+        assert_eq!(mode(&breaker), "open");
+        assert!(!breaker.is_available());
+        assert!(breaker.acquire().is_none());
+        assert_eq!(breaker.gauge.get(), 1);
+        assert_eq!(transitions(&breaker, "open"), 1);
+    }
+
+    #[test]
+    fn opens_on_failure_ratio_once_enough_calls_were_seen() {
+        let config = "consecutive_failures = 100\nfailure_ratio = 0.5\nmin_requests = 4";
+        let breaker = build_breaker(config);
+        for _ in 0..3 {
+            call(&breaker, false);
+        }
+        assert_eq!(mode(&breaker), "closed", "below min_requests");
+        call(&breaker, false);
+        assert_eq!(mode(&breaker), "open");
+
+        let breaker = build_breaker(config);
+        call(&breaker, true);
+        call(&breaker, false);
+        call(&breaker, true);
+        assert_eq!(mode(&breaker), "closed");
+        call(&breaker, false);
+        assert_eq!(mode(&breaker), "open");
+    }
+
+    #[test]
+    fn failure_ratio_window_rolls_over() {
+        let breaker = build_breaker(
+            "consecutive_failures = 100\nfailure_ratio = 0.5\nmin_requests = 4\nwindow_secs = 10",
+        );
+        call(&breaker, false);
+        call(&breaker, false);
+        call(&breaker, false);
+        breaker.lock().window_start -= Duration::from_secs(10);
+
+        // The failures above are forgotten: 1 of 4 fails in the new window.
+        call(&breaker, false);
+        call(&breaker, true);
+        call(&breaker, true);
+        call(&breaker, true);
+        assert_eq!(mode(&breaker), "closed");
+        let state = breaker.lock();
+        assert_eq!((state.window_requests, state.window_failures), (4, 1));
+    }
+
+    #[test]
+    fn half_opens_after_open_secs_with_a_probe_budget() {
+        let breaker =
+            build_breaker("consecutive_failures = 1\nopen_secs = 30\nhalf_open_requests = 2");
+        call(&breaker, false);
+        assert!(breaker.acquire().is_none());
+
+        expire(&breaker);
+        assert!(breaker.is_available());
+        let first = breaker.acquire().unwrap();
+        assert_eq!(mode(&breaker), "half_open");
+        assert_eq!(breaker.gauge.get(), 2);
+        let second = breaker.acquire().unwrap();
+        assert!(breaker.acquire().is_none());
+        assert!(!breaker.is_available());
+
+        // An unrecorded probe, e.g. a cancelled call, frees its slot.
+        drop(first);
+        assert!(breaker.is_available());
+        let third = breaker.acquire().unwrap();
+        assert!(breaker.acquire().is_none());
+        drop((second, third));
+    }
+
+    #[test]
+    fn closes_once_every_probe_succeeds() {
+        let breaker = build_breaker("consecutive_failures = 1\nhalf_open_requests = 2");
+        call(&breaker, false);
+        expire(&breaker);
+
+        call(&breaker, true);
+        assert_eq!(mode(&breaker), "half_open");
+        call(&breaker, true);
+        assert_eq!(mode(&breaker), "closed");
+        assert_eq!(breaker.gauge.get(), 0);
+        assert_eq!(transitions(&breaker, "closed"), 1);
+
+        // Closed again with a clean slate.
+        call(&breaker, false);
+        assert_eq!(mode(&breaker), "open");
+    }
+
+    #[test]
+    fn reopens_when_a_probe_fails() {
+        let breaker = build_breaker("consecutive_failures = 1\nhalf_open_requests = 3");
+        call(&breaker, false);
+        expire(&breaker);
+
+        call(&breaker, true);
+        call(&breaker, false);
+        assert_eq!(mode(&breaker), "open");
+        assert!(breaker.acquire().is_none());
+        assert_eq!(transitions(&breaker, "open"), 2);
+        assert_eq!(transitions(&breaker, "half_open"), 1);
+    }
+
+    #[test]
+    fn ignores_permits_from_an_earlier_state() {
+        let breaker = build_breaker("consecutive_failures = 1\nhalf_open_requests = 1");
+        let closed = breaker.acquire().unwrap();
+        call(&breaker, false);
+        expire(&breaker);
+
+        // A probe from the first half-open period outlives it.
+        let stale_probe = breaker.acquire().unwrap();
+        let failed_probe = {
+            let mut state = breaker.lock();
+            breaker.open(&mut state);
+            state.mode = Mode::Open {
+                until: Instant::now(),
+            };
+            state.epoch
+        };
+        assert!(failed_probe > stale_probe.epoch);
+
+        let probe = breaker.acquire().unwrap();
+        assert!(breaker.acquire().is_none());
+        // Neither the stale probe's release nor its result touch the new
+        // half-open period, and neither does the call from before it opened.
+        drop(stale_probe);
+        assert!(breaker.acquire().is_none());
+        closed.record(false);
+        assert_eq!(mode(&breaker), "half_open");
+
+        probe.record(true);
+        assert_eq!(mode(&breaker), "closed");
+    }
+}
@@ -0,0 +1,222 @@
+use std::fmt;
+use std::net::IpAddr;
+
+use serde::Deserialize;
+
+use crate::net::PeerAddr;
+
+/// An IPv4 or IPv6 network such as `10.0.0.0/8` or `2001:db8::/32`. A bare
+/// address is a network of one, and host bits are ignored. IPv4-mapped IPv6
+/// addresses match as IPv4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        ip.is_ipv4() == self.network.is_ipv4() && truncate(ip, self.prefix) == self.network
+    }
+}
+
+/// `ip` with every bit past the first `prefix` cleared.
+fn truncate(ip: IpAddr, prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V4((u32::from(ip) & mask).into())
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V6((u128::from(ip) & mask).into())
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        let (address, prefix) = match source.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (source.as_str(), None),
+        };
+        let address = address
+            .parse::<IpAddr>()
+            .map_err(|e| format!("invalid network '{source}': {e}"))?;
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) if prefix.bytes().all(|b| b.is_ascii_digit()) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("invalid prefix length in '{source}'"))?,
+            Some(_) => return Err(format!("invalid prefix length in '{source}'")),
+            None => max,
+        };
+
+        // An IPv4-mapped network is stored as the IPv4 network it maps.
+        let network = address.to_canonical();
+        let prefix = match (address, network) {
+            (IpAddr::V6(_), IpAddr::V4(_)) => prefix.checked_sub(96).ok_or_else(|| {
+                format!("IPv4-mapped network '{source}' needs a prefix length of at least 96")
+            })?,
+            _ => prefix,
+        };
+
+        Ok(Self {
+            network: truncate(network, prefix),
+            prefix,
+        })
+    }
+}
+
+/// A `trusted_proxies` entry: a network, or `unix` for every peer on a Unix
+/// socket.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum TrustedProxy {
+    Network(Cidr),
+    Unix,
+}
+
+impl TryFrom<String> for TrustedProxy {
+    type Error = String;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        if source == "unix" {
+            return Ok(Self::Unix);
+        }
+        Cidr::try_from(source).map(Self::Network)
+    }
+}
+
+impl TrustedProxy {
+    pub fn matches(&self, peer: PeerAddr) -> bool {
+        match (self, peer) {
+            (Self::Unix, PeerAddr::Unix) => true,
+            (Self::Network(network), peer) => peer.ip().is_some_and(|ip| network.contains(ip)),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(source: &str) -> Cidr {
+        Cidr::try_from(source.to_owned()).unwrap()
+    }
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn zero_prefix_matches_the_whole_family() {
+        let v4 = cidr("0.0.0.0/0");
+        assert!(v4.contains(ip("1.2.3.4")));
+        assert!(v4.contains(ip("255.255.255.255")));
+        assert!(!v4.contains(ip("2001:db8::1")));
+
+        let v6 = cidr("::/0");
+        assert!(v6.contains(ip("2001:db8::1")));
+        assert!(!v6.contains(ip("1.2.3.4")));
+    }
+
+    #[test]
+    fn full_prefix_matches_one_address() {
+        let v4 = cidr("192.0.2.1/32");
+        assert!(v4.contains(ip("192.0.2.1")));
+        assert!(!v4.contains(ip("192.0.2.2")));
+
+        let v6 = cidr("2001:db8::1/128");
+        assert!(v6.contains(ip("2001:db8::1")));
+        assert!(!v6.contains(ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn bare_address_is_a_full_prefix() {
+        assert_eq!(cidr("192.0.2.1"), cidr("192.0.2.1/32"));
+        assert_eq!(cidr("2001:db8::1"), cidr("2001:db8::1/128"));
+    }
+
+    #[test]
+    fn partial_prefixes() {
+        let v4 = cidr("10.0.0.0/8");
+        assert!(v4.contains(ip("10.255.0.1")));
+        assert!(!v4.contains(ip("11.0.0.0")));
+
+        let odd = cidr("192.0.2.128/25");
+        assert!(odd.contains(ip("192.0.2.200")));
+        assert!(!odd.contains(ip("192.0.2.127")));
+
+        let v6 = cidr("2001:db8::/32");
+        assert!(v6.contains(ip("2001:db8:ffff::1")));
+        assert!(!v6.contains(ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn host_bits_are_ignored() {
+        let network = cidr("10.1.2.3/8");
+        assert_eq!(network, cidr("10.0.0.0/8"));
+        assert_eq!(network.to_string(), "10.0.0.0/8");
+        assert!(network.contains(ip("10.200.0.1")));
+        assert_eq!(cidr("2001:db8::1/32").to_string(), "2001:db8::/32");
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_as_ipv4() {
+        let v4 = cidr("192.0.2.0/24");
+        assert!(v4.contains(ip("::ffff:192.0.2.7")));
+        assert!(!v4.contains(ip("::ffff:192.0.3.7")));
+
+        let mapped = cidr("::ffff:192.0.2.0/120");
+        assert_eq!(mapped, v4);
+        assert!(mapped.contains(ip("192.0.2.7")));
+        assert_eq!(cidr("::ffff:192.0.2.1"), cidr("192.0.2.1/32"));
+    }
+
+    #[test]
+    fn invalid_networks_are_rejected() {
+        for source in [
+            "",
+            "example.com",
+            "10.0.0/8",
+            "10.0.0.0/",
+            "10.0.0.0/33",
+            "10.0.0.0/-1",
+            "10.0.0.0/+8",
+            "10.0.0.0/8/8",
+            "10.0.0.0/ 8",
+            "::/129",
+            "::ffff:192.0.2.0/64",
+        ] {
+            assert!(Cidr::try_from(source.to_owned()).is_err(), "{source:?}");
+        }
+    }
+
+    #[test]
+    fn trusted_proxies() {
+        let unix = TrustedProxy::try_from("unix".to_owned()).unwrap();
+        let loopback = TrustedProxy::try_from("127.0.0.0/8".to_owned()).unwrap();
+        let peer = PeerAddr::Tcp("127.0.0.1:5000".parse().unwrap());
+
+        assert!(unix.matches(PeerAddr::Unix));
+        assert!(!unix.matches(peer));
+        assert!(loopback.matches(peer));
+        assert!(loopback.matches(PeerAddr::Forwarded(ip("127.1.1.1"))));
+        assert!(!loopback.matches(PeerAddr::Unix));
+        assert!(TrustedProxy::try_from("UNIX".to_owned()).is_err());
+    }
+}
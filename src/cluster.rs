@@ -0,0 +1,843 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use http::{HeaderMap, Request, Response};
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Body, Frame, Incoming, SizeHint};
+use prometheus::{IntCounterVec, IntGauge, IntGaugeVec};
+use tokio::task::JoinSet;
+
+use crate::breaker::CircuitBreaker;
+use crate::config::{HealthCheckConfig, LoadBalancing, UpstreamConfig};
+use crate::error::ProxyError;
+use crate::health;
+use crate::metrics::MetricsState;
+use crate::upstream::{self, RequestBody, UpstreamClient, UpstreamTls};
+
+/// A named upstream from `[upstreams]`: a set of endpoints and the strategy
+/// used to pick one per call.
+pub struct Cluster {
+    name: String,
+    config: UpstreamConfig,
+    tls: Option<UpstreamTls>,
+    endpoints: ArcSwap<Vec<Arc<Endpoint>>>,
+    next: AtomicUsize,
+    endpoint_count: IntGauge,
+    endpoint_healthy: IntGaugeVec,
+    circuit_state: IntGaugeVec,
+    circuit_transitions: IntCounterVec,
+}
+
+pub struct Endpoint {
+    address: String,
+    /// Scheme and `:authority` prepended to the request path.
+    origin: String,
+    client: UpstreamClient,
+    outstanding: AtomicUsize,
+    /// New endpoints start out healthy so they take traffic before the first
+    /// check, and stay that way if health checks are disabled.
+    healthy: AtomicBool,
+    /// Consecutive check results contradicting the current state.
+    streak: AtomicU32,
+    healthy_gauge: IntGauge,
+    breaker: Option<CircuitBreaker>,
+}
+
+impl Cluster {
+    fn new(
+        name: &str,
+        config: &UpstreamConfig,
+        metrics: &MetricsState,
+    ) -> Result<Self, ProxyError> {
+        // Endpoint lists get a TLS config per endpoint instead, so each one
+        // is verified against its own host name.
+        let tls = match (&config.tls, config.address.as_ref().or(config.dns.as_ref())) {
+            (Some(tls_config), Some(name)) => Some(upstream::build_tls(name, tls_config)?),
+            _ => None,
+        };
+
+        let cluster = Self {
+            name: name.to_owned(),
+            config: config.clone(),
+            tls,
+            endpoints: ArcSwap::from_pointee(Vec::new()),
+            next: AtomicUsize::new(0),
+            endpoint_count: metrics.upstream_endpoints.with_label_values(&[name]),
+            endpoint_healthy: metrics.upstream_endpoint_healthy.clone(),
+            circuit_state: metrics.upstream_circuit_state.clone(),
+            circuit_transitions: metrics.upstream_circuit_transitions_total.clone(),
+        };
+
+        let static_addresses = match &config.address {
+            Some(address) => vec![address.clone()],
+            None => config.endpoints.clone(),
+        };
+        cluster.set_endpoints(static_addresses)?;
+        Ok(cluster)
+    }
+
+    pub async fn send(
+        &self,
+        req: Request<RequestBody>,
+    ) -> Result<Response<UpstreamBody>, ProxyError> {
+        let endpoint = self.pick(req.headers()).ok_or_else(|| {
+            ProxyError::UpstreamConnect(format!(
+                "upstream '{}' has no available endpoints",
+                self.name
+            ))
+        })?;
+        endpoint.send(req).await
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn has_healthy_endpoint(&self) -> bool {
+        self.endpoints
+            .load()
+            .iter()
+            .any(|endpoint| endpoint.healthy.load(Ordering::Relaxed))
+    }
+
+    fn pick(&self, headers: &HeaderMap) -> Option<Arc<Endpoint>> {
+        // Endpoints with an open circuit breaker are skipped entirely, so
+        // calls fail fast while every breaker is open.
+        let all = self.endpoints.load();
+        let available: Vec<&Arc<Endpoint>> = all
+            .iter()
+            .filter(|endpoint| {
+                endpoint
+                    .breaker
+                    .as_ref()
+                    .is_none_or(CircuitBreaker::is_available)
+            })
+            .collect();
+        let mut endpoints: Vec<&Arc<Endpoint>> = available
+            .iter()
+            .copied()
+            .filter(|endpoint| endpoint.healthy.load(Ordering::Relaxed))
+            .collect();
+        // With every endpoint ejected, trying one beats failing every call.
+        if endpoints.is_empty() {
+            endpoints = available;
+        }
+        if endpoints.is_empty() {
+            return None;
+        }
+
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % endpoints.len();
+        let index = match self.config.load_balancing {
+            LoadBalancing::RoundRobin => start,
+            // Scanning from the round-robin position spreads calls over
+            // endpoints that are tied.
+            LoadBalancing::LeastRequest => (0..endpoints.len())
+                .map(|offset| (start + offset) % endpoints.len())
+                .min_by_key(|&index| endpoints[index].outstanding.load(Ordering::Relaxed))
+                .unwrap_or(start),
+            LoadBalancing::ConsistentHash => self
+                .config
+                .hash_key
+                .as_deref()
+                .and_then(|key| headers.get(key))
+                .map_or(start, |value| rendezvous(value.as_bytes(), &endpoints)),
+        };
+
+        Some(Arc::clone(endpoints[index]))
+    }
+
+    /// Replaces the endpoint set, keeping the existing endpoints (and their
+    /// connections) for addresses that are still present.
+    fn set_endpoints(&self, mut addresses: Vec<String>) -> Result<(), ProxyError> {
+        addresses.sort();
+        addresses.dedup();
+
+        let current = self.endpoints.load();
+        if current.len() == addresses.len()
+            && current
+                .iter()
+                .zip(&addresses)
+                .all(|(endpoint, address)| endpoint.address == *address)
+        {
+            return Ok(());
+        }
+
+        let endpoints = addresses
+            .iter()
+            .map(
+                |address| match current.iter().find(|endpoint| endpoint.address == *address) {
+                    Some(endpoint) => Ok(Arc::clone(endpoint)),
+                    None => self.new_endpoint(address).map(Arc::new),
+                },
+            )
+            .collect::<Result<Vec<_>, ProxyError>>()?;
+
+        for removed in current
+            .iter()
+            .filter(|endpoint| !addresses.contains(&endpoint.address))
+        {
+            let _ = self
+                .endpoint_healthy
+                .remove_label_values(&[&self.name, &removed.address]);
+            let _ = self
+                .circuit_state
+                .remove_label_values(&[&self.name, &removed.address]);
+            for state in ["closed", "open", "half_open"] {
+                let _ = self.circuit_transitions.remove_label_values(&[
+                    &self.name,
+                    &removed.address,
+                    state,
+                ]);
+            }
+        }
+
+        tracing::info!(upstream = %self.name, endpoints = ?addresses, "updated upstream endpoints");
+        self.endpoint_count.set(endpoints.len() as i64);
+        self.endpoints.store(Arc::new(endpoints));
+        Ok(())
+    }
+
+    fn new_endpoint(&self, address: &str) -> Result<Endpoint, ProxyError> {
+        // Resolved endpoints are addressed by the DNS name, static ones by
+        // their own address.
+        let name = self.config.dns.as_deref().unwrap_or(address);
+        let tls = match (&self.tls, &self.config.tls) {
+            (Some(tls), _) => Some(tls.clone()),
+            (None, Some(tls_config)) => Some(upstream::build_tls(address, tls_config)?),
+            (None, None) => None,
+        };
+
+        let origin = match &self.config.tls {
+            Some(tls_config) => format!(
+                "https://{}",
+                tls_config
+                    .authority
+                    .as_deref()
+                    .unwrap_or(upstream::authority_of(name))
+            ),
+            None => format!("http://{}", upstream::authority_of(name)),
+        };
+
+        let healthy_gauge = self
+            .endpoint_healthy
+            .with_label_values(&[&self.name, address]);
+        healthy_gauge.set(1);
+
+        let breaker = self.config.circuit_breaker.clone().map(|config| {
+            CircuitBreaker::new(
+                config,
+                self.circuit_state.with_label_values(&[&self.name, address]),
+                self.circuit_transitions.clone(),
+                [self.name.clone(), address.to_owned()],
+            )
+        });
+
+        Ok(Endpoint {
+            address: address.to_owned(),
+            origin,
+            client: upstream::build_client(address, tls),
+            outstanding: AtomicUsize::new(0),
+            healthy: AtomicBool::new(true),
+            streak: AtomicU32::new(0),
+            healthy_gauge,
+            breaker,
+        })
+    }
+
+    async fn resolve(&self) -> Result<(), ProxyError> {
+        let Some(dns) = &self.config.dns else {
+            return Ok(());
+        };
+
+        let addresses: Vec<String> = tokio::net::lookup_host(dns.as_str())
+            .await
+            .map_err(|e| ProxyError::UpstreamConnect(format!("resolving {dns}: {e}")))?
+            .map(|address| address.to_string())
+            .collect();
+        if addresses.is_empty() {
+            return Err(ProxyError::UpstreamConnect(format!(
+                "resolving {dns}: no addresses"
+            )));
+        }
+
+        self.set_endpoints(addresses)
+    }
+}
+
+/// Picks the endpoint with the highest hash of key and address, so only keys
+/// mapped to a removed endpoint move when the endpoint set changes. The hash
+/// is fixed rather than `std`'s, so every proxy build and replica sends a key
+/// to the same endpoint.
+fn rendezvous(key: &[u8], endpoints: &[&Arc<Endpoint>]) -> usize {
+    endpoints
+        .iter()
+        .enumerate()
+        .max_by_key(|(_, endpoint)| fnv1a(&[key, &[0], endpoint.address.as_bytes()]))
+        .map_or(0, |(index, _)| index)
+}
+
+/// 64-bit FNV-1a over the concatenated `parts`.
+fn fnv1a(parts: &[&[u8]]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    parts
+        .iter()
+        .flat_map(|part| part.iter())
+        .fold(OFFSET_BASIS, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+        })
+}
+
+impl Endpoint {
+    async fn send(
+        self: Arc<Self>,
+        mut req: Request<RequestBody>,
+    ) -> Result<Response<UpstreamBody>, ProxyError> {
+        let guard = OutstandingGuard::new(Arc::clone(&self));
+
+        let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+        *req.uri_mut() = format!("{}{path}", self.origin)
+            .parse()
+            .map_err(|e| ProxyError::UpstreamConnect(format!("invalid upstream URI: {e}")))?;
+
+        let permit = match &self.breaker {
+            Some(breaker) => Some(breaker.acquire().ok_or_else(|| {
+                ProxyError::UpstreamConnect(format!("{}: circuit breaker open", self.address))
+            })?),
+            None => None,
+        };
+
+        let response = self.client.request(req).await;
+        if let Some(permit) = permit {
+            permit.record(response.is_ok());
+        }
+        let response =
+            response.map_err(|e| ProxyError::UpstreamRequest(format!("{}: {e}", self.address)))?;
+
+        Ok(response.map(|body| UpstreamBody {
+            inner: body,
+            _guard: guard,
+        }))
+    }
+
+    /// Calls `grpc.health.v1.Health/Check` and requires a SERVING answer.
+    async fn check(&self, config: &HealthCheckConfig) -> Result<(), String> {
+        let body = Full::new(health::encode_check_request(&config.service))
+            .map_err(|never| match never {})
+            .boxed();
+        let request = Request::post(format!("{}{}", self.origin, health::CHECK_PATH))
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .body(body)
+            .map_err(|e| e.to_string())?;
+
+        let check = async {
+            let response = self
+                .client
+                .request(request)
+                .await
+                .map_err(|e| e.to_string())?;
+            let (parts, body) = response.into_parts();
+            let body = body.collect().await.map_err(|e| e.to_string())?;
+            Ok::<_, String>((parts, body))
+        };
+        let (parts, body) = tokio::time::timeout(Duration::from_secs(config.timeout_secs), check)
+            .await
+            .map_err(|_| "timed out".to_owned())??;
+
+        // Trailers-only responses carry the status in the headers.
+        let grpc_status = body
+            .trailers()
+            .and_then(|trailers| trailers.get("grpc-status"))
+            .or_else(|| parts.headers.get("grpc-status"))
+            .and_then(|status| status.to_str().ok());
+        if grpc_status != Some("0") {
+            return Err(format!("grpc-status {}", grpc_status.unwrap_or("missing")));
+        }
+
+        match health::decode_check_response(&body.to_bytes()) {
+            Some(health::SERVING) => Ok(()),
+            Some(status) => Err(format!("serving status {status}")),
+            None => Err("malformed response".to_owned()),
+        }
+    }
+
+    /// Flips the endpoint in or out of rotation once enough consecutive
+    /// results disagree with its current state.
+    fn record_check(&self, upstream: &str, config: &HealthCheckConfig, result: Result<(), String>) {
+        let healthy = self.healthy.load(Ordering::Relaxed);
+        if result.is_ok() == healthy {
+            self.streak.store(0, Ordering::Relaxed);
+            return;
+        }
+
+        let threshold = if healthy {
+            config.unhealthy_threshold
+        } else {
+            config.healthy_threshold
+        };
+        if self.streak.fetch_add(1, Ordering::Relaxed) + 1 < threshold {
+            return;
+        }
+
+        self.streak.store(0, Ordering::Relaxed);
+        self.healthy.store(!healthy, Ordering::Relaxed);
+        self.healthy_gauge.set(i64::from(!healthy));
+        match result {
+            Ok(()) => tracing::info!(%upstream, endpoint = %self.address, "endpoint healthy again"),
+            Err(e) => {
+                tracing::warn!(%upstream, endpoint = %self.address, "endpoint unhealthy: {e}")
+            }
+        }
+    }
+}
+
+/// Counts a call as outstanding on its endpoint until the response body has
+/// been dropped, so long-running streams weigh on least-request balancing.
+struct OutstandingGuard {
+    endpoint: Arc<Endpoint>,
+}
+
+impl OutstandingGuard {
+    fn new(endpoint: Arc<Endpoint>) -> Self {
+        endpoint.outstanding.fetch_add(1, Ordering::Relaxed);
+        Self { endpoint }
+    }
+}
+
+impl Drop for OutstandingGuard {
+    fn drop(&mut self) {
+        self.endpoint.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct UpstreamBody {
+    inner: Incoming,
+    _guard: OutstandingGuard,
+}
+
+impl Body for UpstreamBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+pub async fn build_clusters(
+    upstreams: &HashMap<String, UpstreamConfig>,
+    metrics: &MetricsState,
+) -> Result<HashMap<String, Arc<Cluster>>, ProxyError> {
+    let mut clusters = HashMap::new();
+    for (name, config) in upstreams {
+        let cluster = Cluster::new(name, config, metrics)?;
+        // A failed lookup is not fatal: the refresh task keeps retrying and
+        // calls fail with UNAVAILABLE meanwhile.
+        if let Err(e) = cluster.resolve().await {
+            tracing::warn!(upstream = %name, "{e}");
+        }
+        clusters.insert(name.clone(), Arc::new(cluster));
+    }
+    Ok(clusters)
+}
+
+/// Periodically re-resolves DNS upstreams. Static upstreams return at once.
+pub async fn refresh_endpoints(cluster: Arc<Cluster>) {
+    if cluster.config.dns.is_none() {
+        return;
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_secs(cluster.config.dns_refresh_secs));
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        if let Err(e) = cluster.resolve().await {
+            tracing::warn!(upstream = %cluster.name, "keeping previous endpoints: {e}");
+        }
+    }
+}
+
+/// Probes every endpoint of the cluster each `health_check.interval_secs`.
+/// Returns at once if health checks are not configured.
+pub async fn check_health(cluster: Arc<Cluster>) {
+    let Some(config) = cluster.config.health_check.clone() else {
+        return;
+    };
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    loop {
+        interval.tick().await;
+
+        let mut checks = JoinSet::new();
+        for endpoint in cluster.endpoints.load().iter() {
+            let endpoint = Arc::clone(endpoint);
+            let config = config.clone();
+            checks.spawn(async move {
+                let result = endpoint.check(&config).await;
+                (endpoint, result)
+            });
+        }
+
+        while let Some(joined) = checks.join_next().await {
+            if let Ok((endpoint, result)) = joined {
+                if let Err(e) = &result {
+                    tracing::debug!(upstream = %cluster.name, endpoint = %endpoint.address, "health check failed: {e}");
+                }
+                endpoint.record_check(&cluster.name, &config, result);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Mutex;
+
+    use hyper::service::service_fn;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+
+    use super::*;
+
+    /// A local h2 server standing in for an upstream. `respond` gets the
+    /// number of the request, counting from 0, and returns how long to wait
+    /// before answering and the answer.
+    pub(crate) struct StubUpstream {
+        pub cluster: Arc<Cluster>,
+        /// Request bodies received so far.
+        pub bodies: Arc<Mutex<Vec<Bytes>>>,
+    }
+
+    pub(crate) async fn stub_upstream<F>(respond: F) -> StubUpstream
+    where
+        F: Fn(usize) -> (Duration, Response<Full<Bytes>>) + Send + Sync + 'static,
+    {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let respond = Arc::new(respond);
+
+        let received = Arc::clone(&bodies);
+        tokio::spawn(async move {
+            loop {
+                let Ok((stream, _)) = listener.accept().await else {
+                    return;
+                };
+                let received = Arc::clone(&received);
+                let respond = Arc::clone(&respond);
+                let service = service_fn(move |req: Request<Incoming>| {
+                    let received = Arc::clone(&received);
+                    let respond = Arc::clone(&respond);
+                    async move {
+                        let body = req.into_body().collect().await?.to_bytes();
+                        let index = {
+                            let mut received = received.lock().unwrap();
+                            received.push(body);
+                            received.len() - 1
+                        };
+                        let (delay, response) = respond(index);
+                        tokio::time::sleep(delay).await;
+                        Ok::<_, hyper::Error>(response)
+                    }
+                });
+                tokio::spawn(
+                    hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), service),
+                );
+            }
+        });
+
+        StubUpstream {
+            cluster: Arc::new(cluster(&format!("address = \"{address}\""))),
+            bodies,
+        }
+    }
+
+    /// A response carrying `grpc-status` in its headers, with `message` as
+    /// the body.
+    pub(crate) fn grpc_response(status: u8, message: &'static [u8]) -> Response<Full<Bytes>> {
+        Response::builder()
+            .header("content-type", "application/grpc")
+            .header("grpc-status", status.to_string())
+            .body(Full::new(Bytes::from_static(message)))
+            .unwrap()
+    }
+
+    const ENDPOINTS: &str = r#"endpoints = ["10.0.0.1:50051", "10.0.0.2:50051", "10.0.0.3:50051"]"#;
+
+    fn cluster(toml: &str) -> Cluster {
+        let config: UpstreamConfig = toml::from_str(toml).unwrap();
+        Cluster::new("backend", &config, &MetricsState::new().unwrap()).unwrap()
+    }
+
+    fn endpoint(cluster: &Cluster, address: &str) -> Arc<Endpoint> {
+        let endpoints = cluster.endpoints.load();
+        let endpoint = endpoints
+            .iter()
+            .find(|endpoint| endpoint.address == address)
+            .unwrap();
+        Arc::clone(endpoint)
+    }
+
+    fn picks(cluster: &Cluster, count: usize) -> Vec<String> {
+        (0..count)
+            .map(|_| cluster.pick(&HeaderMap::new()).unwrap().address.clone())
+            .collect()
+    }
+
+    fn trip_breaker(endpoint: &Endpoint) {
+        let breaker = endpoint.breaker.as_ref().unwrap();
+        breaker.acquire().unwrap().record(false);
+        assert!(!breaker.is_available());
+    }
+
+    #[tokio::test]
+    async fn round_robin_spreads_calls_evenly() {
+        let cluster = cluster(ENDPOINTS);
+        let mut picked = picks(&cluster, 6);
+        picked.sort();
+        assert_eq!(
+            picked,
+            [
+                "10.0.0.1:50051",
+                "10.0.0.1:50051",
+                "10.0.0.2:50051",
+                "10.0.0.2:50051",
+                "10.0.0.3:50051",
+                "10.0.0.3:50051",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn least_request_picks_the_idlest_endpoint() {
+        let cluster = cluster(&format!("{ENDPOINTS}\nload_balancing = \"least_request\""));
+        endpoint(&cluster, "10.0.0.1:50051")
+            .outstanding
+            .store(3, Ordering::Relaxed);
+        endpoint(&cluster, "10.0.0.2:50051")
+            .outstanding
+            .store(1, Ordering::Relaxed);
+        endpoint(&cluster, "10.0.0.3:50051")
+            .outstanding
+            .store(2, Ordering::Relaxed);
+        assert!(
+            picks(&cluster, 4)
+                .iter()
+                .all(|address| address == "10.0.0.2:50051")
+        );
+
+        // Ties are broken round-robin.
+        endpoint(&cluster, "10.0.0.3:50051")
+            .outstanding
+            .store(1, Ordering::Relaxed);
+        let mut picked = picks(&cluster, 4);
+        picked.sort();
+        picked.dedup();
+        assert_eq!(picked, ["10.0.0.2:50051", "10.0.0.3:50051"]);
+    }
+
+    #[tokio::test]
+    async fn skips_endpoints_with_an_open_breaker() {
+        let cluster = cluster(&format!(
+            "{ENDPOINTS}\n[circuit_breaker]\nconsecutive_failures = 1\nopen_secs = 60"
+        ));
+        trip_breaker(&endpoint(&cluster, "10.0.0.1:50051"));
+        let picked = picks(&cluster, 6);
+        assert!(!picked.contains(&"10.0.0.1:50051".to_owned()));
+
+        trip_breaker(&endpoint(&cluster, "10.0.0.2:50051"));
+        trip_breaker(&endpoint(&cluster, "10.0.0.3:50051"));
+        assert!(cluster.pick(&HeaderMap::new()).is_none());
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_unhealthy_endpoints() {
+        let cluster = cluster(&format!(
+            "{ENDPOINTS}\n[circuit_breaker]\nconsecutive_failures = 1\nopen_secs = 60"
+        ));
+        for endpoint in cluster.endpoints.load().iter() {
+            endpoint.healthy.store(false, Ordering::Relaxed);
+        }
+        endpoint(&cluster, "10.0.0.3:50051")
+            .healthy
+            .store(true, Ordering::Relaxed);
+        assert!(
+            picks(&cluster, 3)
+                .iter()
+                .all(|address| address == "10.0.0.3:50051")
+        );
+
+        // With no healthy endpoint left, any endpoint whose breaker lets
+        // calls through is used.
+        trip_breaker(&endpoint(&cluster, "10.0.0.3:50051"));
+        let mut picked = picks(&cluster, 4);
+        picked.sort();
+        picked.dedup();
+        assert_eq!(picked, ["10.0.0.1:50051", "10.0.0.2:50051"]);
+    }
+
+    #[tokio::test]
+    async fn set_endpoints_keeps_existing_endpoints() {
+        let cluster = cluster(ENDPOINTS);
+        let kept = endpoint(&cluster, "10.0.0.2:50051");
+        kept.healthy.store(false, Ordering::Relaxed);
+        let before = Arc::clone(&cluster.endpoints.load());
+
+        // Same addresses in another order: nothing changes.
+        cluster
+            .set_endpoints(vec![
+                "10.0.0.3:50051".to_owned(),
+                "10.0.0.2:50051".to_owned(),
+                "10.0.0.1:50051".to_owned(),
+                "10.0.0.2:50051".to_owned(),
+            ])
+            .unwrap();
+        assert!(Arc::ptr_eq(&before, &cluster.endpoints.load()));
+
+        cluster
+            .set_endpoints(vec![
+                "10.0.0.2:50051".to_owned(),
+                "10.0.0.4:50051".to_owned(),
+            ])
+            .unwrap();
+        let after = cluster.endpoints.load();
+        let addresses: Vec<&str> = after
+            .iter()
+            .map(|endpoint| endpoint.address.as_str())
+            .collect();
+        assert_eq!(addresses, ["10.0.0.2:50051", "10.0.0.4:50051"]);
+        assert!(Arc::ptr_eq(&after[0], &kept));
+        assert!(!after[0].healthy.load(Ordering::Relaxed));
+        assert!(after[1].healthy.load(Ordering::Relaxed));
+    }
+
+    fn hashed_pick(cluster: &Cluster, key: &str) -> String {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-tenant", key.parse().unwrap());
+        cluster.pick(&headers).unwrap().address.clone()
+    }
+
+    const HASHED: &str = r#"
+        endpoints = ["10.0.0.1:50051", "10.0.0.2:50051", "10.0.0.3:50051", "10.0.0.4:50051"]
+        load_balancing = "consistent_hash"
+        hash_key = "x-tenant"
+    "#;
+
+    #[tokio::test]
+    async fn consistent_hash_is_stable_per_key() {
+        let cluster = cluster(HASHED);
+        let keys: Vec<String> = (0..64).map(|key| format!("tenant-{key}")).collect();
+        let first: Vec<String> = keys.iter().map(|key| hashed_pick(&cluster, key)).collect();
+        for _ in 0..3 {
+            let again: Vec<String> = keys.iter().map(|key| hashed_pick(&cluster, key)).collect();
+            assert_eq!(again, first);
+        }
+
+        let mut used = first.clone();
+        used.sort();
+        used.dedup();
+        assert_eq!(used.len(), 4, "64 keys should reach every endpoint");
+    }
+
+    #[tokio::test]
+    async fn removing_an_endpoint_only_moves_its_keys() {
+        let cluster = cluster(HASHED);
+        let keys: Vec<String> = (0..256).map(|key| format!("tenant-{key}")).collect();
+        let before: Vec<String> = keys.iter().map(|key| hashed_pick(&cluster, key)).collect();
+
+        cluster
+            .set_endpoints(vec![
+                "10.0.0.1:50051".to_owned(),
+                "10.0.0.2:50051".to_owned(),
+                "10.0.0.4:50051".to_owned(),
+            ])
+            .unwrap();
+        let mut moved = 0;
+        for (key, old) in keys.iter().zip(&before) {
+            let new = hashed_pick(&cluster, key);
+            if old == "10.0.0.3:50051" {
+                moved += 1;
+                assert_ne!(new, *old);
+            } else {
+                assert_eq!(new, *old, "{key} moved off a remaining endpoint");
+            }
+        }
+        assert!(moved > 0);
+    }
+
+    #[test]
+    fn fnv1a_matches_the_reference_vectors() {
+        assert_eq!(fnv1a(&[]), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(&[b"a"]), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(&[b"foo", b"", b"bar"]), 0x8594_4171_f739_67e8);
+    }
+
+    #[tokio::test]
+    async fn consistent_hash_mapping_is_pinned() {
+        // Changing these means every deployed key moves to another endpoint.
+        let cluster = cluster(HASHED);
+        for (key, address) in [
+            ("alice", "10.0.0.3:50051"),
+            ("bob", "10.0.0.4:50051"),
+            ("dave", "10.0.0.1:50051"),
+            ("heidi", "10.0.0.2:50051"),
+        ] {
+            assert_eq!(hashed_pick(&cluster, key), address, "{key}");
+        }
+    }
+
+    #[tokio::test]
+    async fn calls_without_the_hash_key_are_spread() {
+        let cluster = cluster(HASHED);
+        let mut picked = picks(&cluster, 8);
+        picked.sort();
+        picked.dedup();
+        assert_eq!(picked.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn health_checks_flip_state_after_thresholds() {
+        let cluster = cluster(ENDPOINTS);
+        let config: HealthCheckConfig =
+            toml::from_str("unhealthy_threshold = 2\nhealthy_threshold = 3").unwrap();
+        let endpoint = endpoint(&cluster, "10.0.0.1:50051");
+        let check = |result: Result<(), &str>| {
+            endpoint.record_check("backend", &config, result.map_err(str::to_owned));
+            endpoint.healthy.load(Ordering::Relaxed)
+        };
+
+        // A success in between resets the failure streak.
+        assert!(check(Err("down")));
+        assert!(check(Ok(())));
+        assert!(check(Err("down")));
+        assert!(!check(Err("down")));
+        assert_eq!(endpoint.healthy_gauge.get(), 0);
+
+        assert!(!check(Ok(())));
+        assert!(!check(Ok(())));
+        assert!(!check(Err("down")));
+        assert!(!check(Ok(())));
+        assert!(!check(Ok(())));
+        assert!(check(Ok(())));
+        assert_eq!(endpoint.healthy_gauge.get(), 1);
+    }
+}
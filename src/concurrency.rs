@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use prometheus::IntGaugeVec;
+
+use crate::config::Config;
+use crate::error::ProxyError;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum CallKey {
+    User(String),
+    Method(String),
+}
+
+struct Counts {
+    open: Mutex<HashMap<CallKey, usize>>,
+    in_flight: IntGaugeVec,
+}
+
+impl Counts {
+    fn lock(&self) -> MutexGuard<'_, HashMap<CallKey, usize>> {
+        self.open.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Counts open calls per user and per `[[concurrency_limits.methods]]` rule,
+/// and turns calls away once a limit is reached. A call stays open until its
+/// response body is dropped, so streams count for as long as they run.
+pub struct ConcurrencyLimiter {
+    counts: Arc<Counts>,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(in_flight: IntGaugeVec) -> Self {
+        Self {
+            counts: Arc::new(Counts {
+                open: Mutex::new(HashMap::new()),
+                in_flight,
+            }),
+        }
+    }
+
+    pub fn acquire(
+        &self,
+        config: &Config,
+        user: &str,
+        call: &str,
+    ) -> Result<CallPermit, ProxyError> {
+        let limits = &config.concurrency_limits;
+        let user_limit = config
+            .users
+            .get(user)
+            .and_then(|user| user.max_concurrent_calls)
+            .or(limits.per_user);
+
+        // The user is always counted, for the in-flight gauge.
+        let mut keys = vec![(CallKey::User(user.to_owned()), user_limit)];
+        for method in &limits.methods {
+            if method.call.matches(call) {
+                keys.push((CallKey::Method(method.call.to_string()), Some(method.max)));
+            }
+        }
+
+        let mut open = self.counts.lock();
+        for (key, limit) in &keys {
+            let count = open.get(key).copied().unwrap_or(0);
+            if limit.is_some_and(|limit| count >= limit) {
+                return Err(ProxyError::ConcurrencyLimited {
+                    user: user.to_owned(),
+                    limit: match key {
+                        CallKey::User(_) => "user",
+                        CallKey::Method(_) => "method",
+                    },
+                });
+            }
+        }
+        for (key, _) in &keys {
+            *open.entry(key.clone()).or_insert(0) += 1;
+        }
+        self.counts.in_flight.with_label_values(&[user]).inc();
+
+        Ok(CallPermit {
+            counts: Arc::clone(&self.counts),
+            user: user.to_owned(),
+            keys: keys.into_iter().map(|(key, _)| key).collect(),
+        })
+    }
+}
+
+/// Releases the call's slots when dropped.
+pub struct CallPermit {
+    counts: Arc<Counts>,
+    user: String,
+    keys: Vec<CallKey>,
+}
+
+impl Drop for CallPermit {
+    fn drop(&mut self) {
+        let mut open = self.counts.lock();
+        for key in &self.keys {
+            if let Some(count) = open.get_mut(key) {
+                *count -= 1;
+                if *count == 0 {
+                    open.remove(key);
+                }
+            }
+        }
+
+        // Drop idle users from the gauge, so one-off JWT subjects don't pile
+        // up as label values.
+        if open.contains_key(&CallKey::User(self.user.clone())) {
+            self.counts.in_flight.with_label_values(&[&self.user]).dec();
+        } else {
+            let _ = self.counts.in_flight.remove_label_values(&[&self.user]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::Opts;
+    use prometheus::core::Collector;
+
+    use super::*;
+
+    fn load_config(toml: &str) -> Config {
+        toml::from_str(&format!("metrics_address = \"127.0.0.1:9090\"\n{toml}")).unwrap()
+    }
+
+    fn limiter() -> ConcurrencyLimiter {
+        ConcurrencyLimiter::new(
+            IntGaugeVec::new(Opts::new("in_flight", "help"), &["user"]).unwrap(),
+        )
+    }
+
+    fn limited_by(result: Result<CallPermit, ProxyError>) -> Option<&'static str> {
+        match result {
+            Ok(_) => None,
+            Err(ProxyError::ConcurrencyLimited { limit, .. }) => Some(limit),
+            Err(e) => panic!("unexpected error: {e}"),
+        }
+    }
+
+    /// The in-flight gauge for `user`, or `None` if it has no label value.
+    fn in_flight(limiter: &ConcurrencyLimiter, user: &str) -> Option<i64> {
+        limiter.counts.in_flight.collect()[0]
+            .get_metric()
+            .iter()
+            .find(|metric| metric.get_label()[0].value() == user)
+            .map(|metric| metric.get_gauge().value() as i64)
+    }
+
+    #[test]
+    fn rejects_calls_over_the_user_limit() {
+        let config = load_config(
+            r#"
+            [concurrency_limits]
+            per_user = 2
+            [users.bob]
+            max_concurrent_calls = 1
+            "#,
+        );
+        let limiter = limiter();
+        let first = limiter.acquire(&config, "alice", "a.S/M").unwrap();
+        let _second = limiter.acquire(&config, "alice", "a.S/M").unwrap();
+        assert_eq!(
+            limited_by(limiter.acquire(&config, "alice", "a.S/M")),
+            Some("user")
+        );
+
+        let _bob = limiter.acquire(&config, "bob", "a.S/M").unwrap();
+        assert_eq!(
+            limited_by(limiter.acquire(&config, "bob", "a.S/M")),
+            Some("user")
+        );
+
+        drop(first);
+        assert!(limiter.acquire(&config, "alice", "a.S/M").is_ok());
+    }
+
+    #[test]
+    fn rejects_calls_over_the_method_limit() {
+        let config = load_config(
+            r#"
+            [[concurrency_limits.methods]]
+            call = "shop.Reports/*"
+            max = 1
+            "#,
+        );
+        let limiter = limiter();
+        let report = limiter
+            .acquire(&config, "alice", "shop.Reports/Export")
+            .unwrap();
+        assert_eq!(
+            limited_by(limiter.acquire(&config, "bob", "shop.Reports/Summary")),
+            Some("method")
+        );
+        // Other methods and users without a limit are unaffected.
+        let _others: Vec<CallPermit> = (0..10)
+            .map(|_| limiter.acquire(&config, "bob", "shop.Catalog/Get").unwrap())
+            .collect();
+
+        drop(report);
+        assert!(
+            limiter
+                .acquire(&config, "bob", "shop.Reports/Summary")
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn rejected_calls_take_no_slot() {
+        let config = load_config(
+            r#"
+            [concurrency_limits]
+            per_user = 1
+            [[concurrency_limits.methods]]
+            call = "shop.*"
+            max = 2
+            "#,
+        );
+        let limiter = limiter();
+        let _alice = limiter.acquire(&config, "alice", "shop.A/M").unwrap();
+        assert!(limiter.acquire(&config, "alice", "shop.A/M").is_err());
+        assert!(limiter.acquire(&config, "alice", "shop.A/M").is_err());
+        // Alice's rejected calls did not use up the method's second slot.
+        assert!(limiter.acquire(&config, "bob", "shop.A/M").is_ok());
+    }
+
+    #[test]
+    fn gauge_follows_open_calls() {
+        let config = load_config("");
+        let limiter = limiter();
+        let first = limiter.acquire(&config, "alice", "a.S/M").unwrap();
+        let second = limiter.acquire(&config, "alice", "a.S/M").unwrap();
+        assert_eq!(in_flight(&limiter, "alice"), Some(2));
+
+        drop(first);
+        assert_eq!(in_flight(&limiter, "alice"), Some(1));
+        drop(second);
+        assert!(limiter.counts.lock().is_empty());
+        assert_eq!(in_flight(&limiter, "alice"), None);
+    }
+}
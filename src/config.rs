@@ -1,33 +1,1118 @@
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet};
 
+use jsonwebtoken::Algorithm;
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 
+use crate::cidr::{Cidr, TrustedProxy};
 use crate::error::ProxyError;
+use crate::net::ListenAddress;
+use crate::pattern::CallPattern;
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
-    pub listen_address: SocketAddr,
-    pub upstream_address: String,
-    pub metrics_address: SocketAddr,
+    /// Shorthand for a single `[[listeners]]` entry, together with `tls`,
+    /// `listen_socket` and `proxy_protocol`. Moved into `listeners` on load.
+    #[serde(default)]
+    pub listen_address: Option<ListenAddress>,
+    #[serde(default)]
+    pub listen_socket: UnixSocketConfig,
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    /// Proxies whose `x-forwarded-for`/`x-real-ip` headers name the client.
+    #[serde(default)]
+    pub trusted_proxies: Vec<TrustedProxy>,
+    pub metrics_address: ListenAddress,
+    #[serde(default)]
+    pub metrics_socket: UnixSocketConfig,
+    /// Shorthand for `[upstreams.default]`. Moved into `upstreams` on load.
+    #[serde(default)]
+    pub upstream_address: Option<String>,
+    #[serde(default)]
+    pub upstream_tls: Option<UpstreamTlsConfig>,
+    #[serde(default)]
+    pub upstreams: HashMap<String, UpstreamConfig>,
+    /// Checked in order; the first route matching the call's service wins.
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    /// Upstream for calls matching no route. Defaults to "default" if such an
+    /// upstream exists.
+    #[serde(default)]
+    pub default_upstream: Option<String>,
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub jwt: Option<JwtConfig>,
+    #[serde(default)]
+    pub auth_cache: AuthCacheConfig,
+    #[serde(default)]
+    pub auth_pool: AuthPoolConfig,
+    #[serde(default)]
+    pub reload: ReloadConfig,
+    #[serde(default)]
+    pub health: HealthConfig,
+    #[serde(default)]
+    pub rate_limits: RateLimitsConfig,
+    #[serde(default)]
+    pub concurrency_limits: ConcurrencyLimitsConfig,
+    #[serde(default)]
+    pub deadlines: DeadlinesConfig,
+    /// Retry policies, for idempotent methods only. The first matching entry
+    /// applies.
+    #[serde(default)]
+    pub retries: Vec<RetryPolicy>,
+    /// How long open streams may run after SIGTERM/SIGINT before the
+    /// remaining connections are closed.
+    #[serde(default = "default_drain_timeout_secs")]
+    pub drain_timeout_secs: u64,
+    /// Calls denied to every user, regardless of any allow rule.
+    #[serde(default)]
+    pub denied_calls: Vec<CallPattern>,
+    /// If set, calls are only accepted from these networks.
+    #[serde(default)]
+    pub allowed_networks: Vec<Cidr>,
+    /// Calls from these networks are rejected, even if allowed above.
+    #[serde(default)]
+    pub denied_networks: Vec<Cidr>,
+    #[serde(default)]
+    pub roles: HashMap<String, RoleConfig>,
     #[serde(default)]
     pub users: HashMap<String, UserConfig>,
+    /// SHA-256 of the config file, exported as a metric label.
+    #[serde(skip)]
+    pub hash: String,
+}
+
+impl Config {
+    /// Expands roles through `inherits`, returning each role once in the order
+    /// it was reached. Unknown role names are skipped.
+    pub fn expand_roles<'a>(&'a self, roles: impl IntoIterator<Item = &'a str>) -> Vec<&'a str> {
+        let mut expanded = Vec::new();
+        let mut pending: Vec<&str> = roles.into_iter().collect();
+        pending.reverse();
+
+        while let Some(name) = pending.pop() {
+            if expanded.contains(&name) {
+                continue;
+            }
+            let Some((name, role)) = self.roles.get_key_value(name) else {
+                continue;
+            };
+            expanded.push(name.as_str());
+            pending.extend(role.inherits.iter().rev().map(String::as_str));
+        }
+
+        expanded
+    }
+
+    /// Name of the upstream that receives calls to `service`.
+    pub fn route(&self, service: &str) -> Option<&str> {
+        self.routes
+            .iter()
+            .find(|route| route.service.matches(service))
+            .map(|route| route.upstream.as_str())
+            .or(self.default_upstream.as_deref())
+    }
+
+    /// Turns `listen_address` and the settings that go with it into a
+    /// listener, so the rest of the proxy only deals with `listeners`.
+    fn normalize_listeners(&mut self) -> Result<(), String> {
+        match self.listen_address.take() {
+            Some(address) => {
+                if !self.listeners.is_empty() {
+                    return Err(
+                        "listen_address conflicts with [[listeners]], set only one".to_owned()
+                    );
+                }
+                self.listeners.push(ListenerConfig {
+                    address,
+                    tls: self.tls.take(),
+                    auth: ListenerAuth::default(),
+                    socket: std::mem::take(&mut self.listen_socket),
+                    proxy_protocol: std::mem::take(&mut self.proxy_protocol),
+                });
+            }
+            None if self.tls.is_some()
+                || self.listen_socket != UnixSocketConfig::default()
+                || self.proxy_protocol =>
+            {
+                return Err(
+                    "tls, listen_socket and proxy_protocol require listen_address, set them per [[listeners]] entry instead"
+                        .to_owned(),
+                );
+            }
+            None => {}
+        }
+
+        if self.listeners.is_empty() {
+            return Err("no listener configured, set listen_address or [[listeners]]".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Turns `upstream_address`/`upstream_tls` into an upstream named
+    /// "default", so the rest of the proxy only deals with `upstreams`.
+    fn normalize_upstreams(&mut self) -> Result<(), String> {
+        match self.upstream_address.take() {
+            Some(address) => {
+                if self.upstreams.contains_key(DEFAULT_UPSTREAM) {
+                    return Err(
+                        "upstream_address conflicts with [upstreams.default], set only one"
+                            .to_owned(),
+                    );
+                }
+                let tls = self.upstream_tls.take();
+                self.upstreams.insert(
+                    DEFAULT_UPSTREAM.to_owned(),
+                    UpstreamConfig::single(address, tls),
+                );
+            }
+            None if self.upstream_tls.is_some() => {
+                return Err("upstream_tls requires upstream_address".to_owned());
+            }
+            None => {}
+        }
+
+        if self.default_upstream.is_none() && self.upstreams.contains_key(DEFAULT_UPSTREAM) {
+            self.default_upstream = Some(DEFAULT_UPSTREAM.to_owned());
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.auth_pool.concurrency == 0 {
+            return Err("auth_pool.concurrency must be at least 1".to_owned());
+        }
+        if self.auth_pool.queue_depth == 0 {
+            return Err("auth_pool.queue_depth must be at least 1".to_owned());
+        }
+
+        for listener in &self.listeners {
+            listener
+                .validate()
+                .map_err(|e| format!("listener {}: {e}", listener.address))?;
+        }
+        self.metrics_socket
+            .validate(&self.metrics_address)
+            .map_err(|e| format!("metrics_socket: {e}"))?;
+
+        if self.upstreams.is_empty() {
+            return Err("no upstream configured, set upstream_address or [upstreams]".to_owned());
+        }
+        for (name, upstream) in &self.upstreams {
+            upstream
+                .validate()
+                .map_err(|e| format!("upstream '{name}': {e}"))?;
+        }
+        if let Some(name) = &self.default_upstream
+            && !self.upstreams.contains_key(name)
+        {
+            return Err(format!(
+                "default_upstream references unknown upstream '{name}'"
+            ));
+        }
+        for route in &self.routes {
+            if !self.upstreams.contains_key(&route.upstream) {
+                return Err(format!(
+                    "route '{}' references unknown upstream '{}'",
+                    route.service, route.upstream
+                ));
+            }
+        }
+
+        if let Some(jwt) = &self.jwt {
+            jwt.validate().map_err(|e| format!("jwt: {e}"))?;
+        }
+        self.rate_limits.validate()?;
+        self.concurrency_limits.validate()?;
+        self.deadlines.validate()?;
+        for policy in &self.retries {
+            policy
+                .validate()
+                .map_err(|e| format!("retry policy for '{}': {e}", policy.call))?;
+        }
+
+        for (username, user) in &self.users {
+            if let Some(limit) = &user.rate_limit {
+                limit
+                    .validate()
+                    .map_err(|e| format!("user '{username}' rate_limit: {e}"))?;
+            }
+            if let Some(deadline) = &user.deadline {
+                deadline
+                    .validate()
+                    .map_err(|e| format!("user '{username}' deadline: {e}"))?;
+            }
+            if user.max_concurrent_calls == Some(0) {
+                return Err(format!(
+                    "user '{username}': max_concurrent_calls must be at least 1"
+                ));
+            }
+            for role in &user.roles {
+                if !self.roles.contains_key(role) {
+                    return Err(format!(
+                        "user '{username}' references unknown role '{role}'"
+                    ));
+                }
+            }
+        }
+
+        for (name, role) in &self.roles {
+            for parent in &role.inherits {
+                if !self.roles.contains_key(parent) {
+                    return Err(format!("role '{name}' inherits unknown role '{parent}'"));
+                }
+            }
+            self.check_inheritance_cycle(name, &mut Vec::new(), &mut HashSet::new())?;
+        }
+
+        Ok(())
+    }
+
+    fn check_inheritance_cycle<'a>(
+        &'a self,
+        name: &'a str,
+        path: &mut Vec<&'a str>,
+        done: &mut HashSet<&'a str>,
+    ) -> Result<(), String> {
+        if path.contains(&name) {
+            path.push(name);
+            return Err(format!("role inheritance cycle: {}", path.join(" -> ")));
+        }
+        if !done.insert(name) {
+            return Ok(());
+        }
+
+        path.push(name);
+        if let Some(role) = self.roles.get(name) {
+            for parent in &role.inherits {
+                self.check_inheritance_cycle(parent, path, done)?;
+            }
+        }
+        path.pop();
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    /// How often the certificate and key files are checked for changes.
+    #[serde(default = "default_tls_reload_interval_secs")]
+    pub reload_interval_secs: u64,
+    /// CA bundle used to verify client certificates. Enables mutual TLS.
+    #[serde(default)]
+    pub client_ca_path: Option<String>,
+    #[serde(default)]
+    pub client_auth: ClientAuthMode,
+    #[serde(default)]
+    pub client_identity: ClientIdentity,
+}
+
+impl TlsConfig {
+    fn validate(&self) -> Result<(), String> {
+        if self.reload_interval_secs == 0 {
+            return Err("reload_interval_secs must be at least 1".to_owned());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuthMode {
+    /// Clients without a certificate may still authenticate with a header.
+    #[default]
+    Optional,
+    /// The TLS handshake fails without a valid client certificate.
+    Required,
+}
+
+/// Which part of a verified client certificate names the user.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientIdentity {
+    /// The subject common name.
+    #[default]
+    Cn,
+    /// The first DNS, email or URI subject alternative name that matches a
+    /// configured user.
+    San,
+}
+
+fn default_tls_reload_interval_secs() -> u64 {
+    60
+}
+
+const DEFAULT_UPSTREAM: &str = "default";
+
+/// A socket the proxy accepts calls on.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ListenerConfig {
+    pub address: ListenAddress,
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub auth: ListenerAuth,
+    /// Mode and ownership of a `unix:` socket.
+    #[serde(default)]
+    pub socket: UnixSocketConfig,
+    /// Every connection starts with a PROXY protocol v1 or v2 header naming
+    /// the client.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+}
+
+impl ListenerConfig {
+    fn validate(&self) -> Result<(), String> {
+        self.socket
+            .validate(&self.address)
+            .map_err(|e| format!("socket: {e}"))?;
+        if let Some(tls) = &self.tls {
+            tls.validate().map_err(|e| format!("tls: {e}"))?;
+        }
+        if self.auth == ListenerAuth::ClientCert
+            && self
+                .tls
+                .as_ref()
+                .is_none_or(|tls| tls.client_ca_path.is_none())
+        {
+            return Err("auth = \"client_cert\" requires tls.client_ca_path".to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// How calls arriving on a listener are authenticated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ListenerAuth {
+    /// An authorization header or, with mutual TLS, a client certificate.
+    #[default]
+    Credentials,
+    /// Only a client certificate; authorization headers are ignored.
+    ClientCert,
+    /// No authentication, as with `NO_AUTH`, for sockets only trusted
+    /// clients can reach.
+    #[serde(rename = "none")]
+    Disabled,
+}
+
+/// Permissions for a Unix socket the proxy listens on. Unset fields keep
+/// what the process umask and user give the file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct UnixSocketConfig {
+    /// File mode, e.g. `0o660`.
+    #[serde(default)]
+    pub mode: Option<u32>,
+    /// Numeric user and group ids to give the socket file.
+    #[serde(default)]
+    pub owner: Option<u32>,
+    #[serde(default)]
+    pub group: Option<u32>,
+}
+
+impl UnixSocketConfig {
+    fn validate(&self, address: &ListenAddress) -> Result<(), String> {
+        if !address.is_unix() && *self != Self::default() {
+            return Err("only applies to unix: addresses".to_owned());
+        }
+        if self.mode.is_some_and(|mode| mode > 0o7777) {
+            return Err("mode must be at most 0o7777".to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// An upstream cluster. Exactly one of `address`, `endpoints` or `dns` names
+/// its endpoints.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct UpstreamConfig {
+    /// A single `host:port`, or `unix:/path` for a Unix domain socket.
+    #[serde(default)]
+    pub address: Option<String>,
+    /// Several `host:port` or `unix:/path` endpoints to balance across.
+    #[serde(default)]
+    pub endpoints: Vec<String>,
+    /// `host:port` resolved to one endpoint per A/AAAA record, re-resolved
+    /// every `dns_refresh_secs`.
+    #[serde(default)]
+    pub dns: Option<String>,
+    #[serde(default = "default_dns_refresh_secs")]
+    pub dns_refresh_secs: u64,
+    #[serde(default)]
+    pub load_balancing: LoadBalancing,
+    /// Request metadata key hashed by `consistent_hash`.
+    #[serde(default)]
+    pub hash_key: Option<String>,
+    #[serde(default)]
+    pub health_check: Option<HealthCheckConfig>,
+    #[serde(default)]
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    #[serde(default)]
+    pub tls: Option<UpstreamTlsConfig>,
+}
+
+impl UpstreamConfig {
+    fn single(address: String, tls: Option<UpstreamTlsConfig>) -> Self {
+        Self {
+            address: Some(address),
+            endpoints: Vec::new(),
+            dns: None,
+            dns_refresh_secs: default_dns_refresh_secs(),
+            load_balancing: LoadBalancing::default(),
+            hash_key: None,
+            health_check: None,
+            circuit_breaker: None,
+            tls,
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let sources = [
+            self.address.is_some(),
+            !self.endpoints.is_empty(),
+            self.dns.is_some(),
+        ];
+        if sources.iter().filter(|set| **set).count() != 1 {
+            return Err("exactly one of address, endpoints or dns must be set".to_owned());
+        }
+        if self
+            .dns
+            .as_ref()
+            .is_some_and(|dns| dns.starts_with("unix:"))
+        {
+            return Err("dns cannot be a unix: address".to_owned());
+        }
+        if self.dns.is_some() && self.dns_refresh_secs == 0 {
+            return Err("dns_refresh_secs must be at least 1".to_owned());
+        }
+        if self.load_balancing == LoadBalancing::ConsistentHash && self.hash_key.is_none() {
+            return Err("consistent_hash requires hash_key".to_owned());
+        }
+        if let Some(health_check) = &self.health_check {
+            health_check
+                .validate()
+                .map_err(|e| format!("health_check: {e}"))?;
+        }
+        if let Some(circuit_breaker) = &self.circuit_breaker {
+            circuit_breaker
+                .validate()
+                .map_err(|e| format!("circuit_breaker: {e}"))?;
+        }
+        Ok(())
+    }
+}
+
+/// How RPCs are spread over the endpoints of an upstream. Each endpoint has
+/// its own HTTP/2 connection, so balancing happens per call, not per
+/// connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalancing {
+    #[default]
+    RoundRobin,
+    /// The endpoint with the fewest calls in flight.
+    LeastRequest,
+    /// Calls with the same `hash_key` metadata value go to the same endpoint
+    /// while the endpoint set is unchanged. Calls without it are spread
+    /// round-robin.
+    ConsistentHash,
+}
+
+fn default_dns_refresh_secs() -> u64 {
+    30
+}
+
+/// Active `grpc.health.v1.Health/Check` probing of every endpoint. An
+/// endpoint leaves rotation after `unhealthy_threshold` failed checks in a row
+/// and returns after `healthy_threshold` successful ones.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct HealthCheckConfig {
+    #[serde(default = "default_health_check_interval_secs")]
+    pub interval_secs: u64,
+    #[serde(default = "default_health_check_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Service name sent in the check. Empty asks about the server as a whole.
+    #[serde(default)]
+    pub service: String,
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+    #[serde(default = "default_healthy_threshold")]
+    pub healthy_threshold: u32,
+}
+
+impl HealthCheckConfig {
+    fn validate(&self) -> Result<(), String> {
+        if self.interval_secs == 0 || self.timeout_secs == 0 {
+            return Err("interval_secs and timeout_secs must be at least 1".to_owned());
+        }
+        if self.unhealthy_threshold == 0 || self.healthy_threshold == 0 {
+            return Err("thresholds must be at least 1".to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// Per-endpoint circuit breaker. It opens after `consecutive_failures`
+/// failed calls in a row, or once `failure_ratio` of the calls in a
+/// `window_secs` window have failed (with at least `min_requests` calls).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CircuitBreakerConfig {
+    #[serde(default = "default_breaker_consecutive_failures")]
+    pub consecutive_failures: u32,
+    #[serde(default)]
+    pub failure_ratio: Option<f64>,
+    #[serde(default = "default_breaker_min_requests")]
+    pub min_requests: u32,
+    #[serde(default = "default_breaker_window_secs")]
+    pub window_secs: u64,
+    /// How long an open breaker rejects calls before letting probes through.
+    #[serde(default = "default_breaker_open_secs")]
+    pub open_secs: u64,
+    /// Probe calls that must all succeed to close the breaker again.
+    #[serde(default = "default_breaker_half_open_requests")]
+    pub half_open_requests: u32,
+}
+
+impl CircuitBreakerConfig {
+    fn validate(&self) -> Result<(), String> {
+        if self.consecutive_failures == 0 || self.min_requests == 0 {
+            return Err("consecutive_failures and min_requests must be at least 1".to_owned());
+        }
+        if self.window_secs == 0 || self.open_secs == 0 {
+            return Err("window_secs and open_secs must be at least 1".to_owned());
+        }
+        if self.half_open_requests == 0 {
+            return Err("half_open_requests must be at least 1".to_owned());
+        }
+        if self
+            .failure_ratio
+            .is_some_and(|ratio| !(ratio > 0.0 && ratio <= 1.0))
+        {
+            return Err("failure_ratio must be above 0 and at most 1".to_owned());
+        }
+        Ok(())
+    }
+}
+
+fn default_breaker_consecutive_failures() -> u32 {
+    5
+}
+
+fn default_breaker_min_requests() -> u32 {
+    20
+}
+
+fn default_breaker_window_secs() -> u64 {
+    10
+}
+
+fn default_breaker_open_secs() -> u64 {
+    30
+}
+
+fn default_breaker_half_open_requests() -> u32 {
+    1
+}
+
+fn default_health_check_interval_secs() -> u64 {
+    10
+}
+
+fn default_health_check_timeout_secs() -> u64 {
+    2
+}
+
+fn default_unhealthy_threshold() -> u32 {
+    3
+}
+
+fn default_healthy_threshold() -> u32 {
+    2
+}
+
+/// Sends calls whose service (`package.Service`) matches `service` to the
+/// named upstream. `service` accepts the same patterns as `allowed_calls`,
+/// e.g. `billing.*`.
+#[derive(Debug, Deserialize)]
+pub struct RouteConfig {
+    pub service: CallPattern,
+    pub upstream: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct UpstreamTlsConfig {
+    /// CA bundle used to verify the upstream. Defaults to the Mozilla roots.
+    #[serde(default)]
+    pub ca_path: Option<String>,
+    /// Client certificate presented to the upstream for mutual TLS.
+    #[serde(default)]
+    pub cert_path: Option<String>,
+    #[serde(default)]
+    pub key_path: Option<String>,
+    /// Name sent as SNI and checked against the upstream certificate.
+    /// Defaults to the host part of the upstream address.
+    #[serde(default)]
+    pub server_name: Option<String>,
+    /// `:authority` sent to the upstream. Defaults to the upstream address.
+    #[serde(default)]
+    pub authority: Option<String>,
+}
+
+/// Accepts `authorization: Bearer <jwt>`. Exactly one key source must be set.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct JwtConfig {
+    pub algorithms: Vec<Algorithm>,
+    #[serde(default)]
+    pub issuer: Option<String>,
+    #[serde(default)]
+    pub audience: Vec<String>,
+    #[serde(default = "default_jwt_username_claim")]
+    pub username_claim: String,
+    /// Claim holding additional role names, as an array or a space-separated
+    /// string. Roles not defined in `[roles]` are ignored.
+    #[serde(default)]
+    pub roles_claim: Option<String>,
+    #[serde(default = "default_jwt_leeway_secs")]
+    pub leeway_secs: u64,
+    /// PEM public key (RSA, EC or Ed25519).
+    #[serde(default)]
+    pub key_path: Option<String>,
+    /// File containing the shared secret for HMAC algorithms.
+    #[serde(default)]
+    pub secret_path: Option<String>,
+    #[serde(default)]
+    pub jwks_path: Option<String>,
+    /// JWKS endpoint, https only.
+    #[serde(default)]
+    pub jwks_url: Option<String>,
+    #[serde(default = "default_jwks_refresh_secs")]
+    pub jwks_refresh_secs: u64,
+}
+
+impl JwtConfig {
+    fn validate(&self) -> Result<(), String> {
+        if (self.jwks_path.is_some() || self.jwks_url.is_some()) && self.jwks_refresh_secs == 0 {
+            return Err("jwks_refresh_secs must be at least 1".to_owned());
+        }
+        // The JWKS decides which tokens are accepted, so it must not be
+        // fetched over a connection anyone on the path can rewrite.
+        if let Some(url) = &self.jwks_url
+            && !url.starts_with("https://")
+        {
+            return Err(format!("jwks_url '{url}' must use https"));
+        }
+        Ok(())
+    }
+}
+
+fn default_jwt_username_claim() -> String {
+    "sub".to_owned()
+}
+
+fn default_jwt_leeway_secs() -> u64 {
+    60
+}
+
+fn default_jwks_refresh_secs() -> u64 {
+    300
+}
+
+/// Cache of successful password and API token verifications. Set `ttl_secs`
+/// to 0 to verify every request with argon2.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AuthCacheConfig {
+    #[serde(default = "default_auth_cache_ttl_secs")]
+    pub ttl_secs: u64,
+    #[serde(default = "default_auth_cache_max_entries")]
+    pub max_entries: usize,
+}
+
+impl Default for AuthCacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: default_auth_cache_ttl_secs(),
+            max_entries: default_auth_cache_max_entries(),
+        }
+    }
+}
+
+fn default_auth_cache_ttl_secs() -> u64 {
+    300
+}
+
+fn default_auth_cache_max_entries() -> usize {
+    10_000
+}
+
+/// Dedicated threads running argon2 verification. Requests beyond
+/// `concurrency` busy workers wait in a queue of `queue_depth` entries and are
+/// rejected with RESOURCE_EXHAUSTED once it is full.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AuthPoolConfig {
+    #[serde(default = "default_auth_pool_concurrency")]
+    pub concurrency: usize,
+    #[serde(default = "default_auth_pool_queue_depth")]
+    pub queue_depth: usize,
+}
+
+impl Default for AuthPoolConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: default_auth_pool_concurrency(),
+            queue_depth: default_auth_pool_queue_depth(),
+        }
+    }
+}
+
+fn default_auth_pool_concurrency() -> usize {
+    std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+}
+
+fn default_auth_pool_queue_depth() -> usize {
+    256
+}
+
+fn default_drain_timeout_secs() -> u64 {
+    30
+}
+
+/// Config and credentials are always reloaded on SIGHUP. A non-zero
+/// `watch_interval_secs` also reloads them when either file changes.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct ReloadConfig {
+    #[serde(default)]
+    pub watch_interval_secs: u64,
+}
+
+/// Token-bucket limits on calls. A call must pass every limit that applies to
+/// it: the global one, the caller's and each matching method rule.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RateLimitsConfig {
+    #[serde(default)]
+    pub global: Option<RateLimit>,
+    /// Applies to each user separately, unless the user sets `rate_limit`.
+    #[serde(default)]
+    pub per_user: Option<RateLimit>,
+    #[serde(default)]
+    pub methods: Vec<MethodRateLimit>,
+}
+
+impl RateLimitsConfig {
+    fn validate(&self) -> Result<(), String> {
+        if let Some(limit) = &self.global {
+            limit
+                .validate()
+                .map_err(|e| format!("rate_limits.global: {e}"))?;
+        }
+        if let Some(limit) = &self.per_user {
+            limit
+                .validate()
+                .map_err(|e| format!("rate_limits.per_user: {e}"))?;
+        }
+        for method in &self.methods {
+            method
+                .limit
+                .validate()
+                .map_err(|e| format!("rate limit for '{}': {e}", method.call))?;
+        }
+        Ok(())
+    }
+}
+
+/// A bucket refilled at `rate` calls per second, holding up to `burst`
+/// calls (default: `rate`, at least 1).
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimit {
+    pub rate: f64,
+    #[serde(default)]
+    pub burst: Option<u32>,
+}
+
+impl RateLimit {
+    pub fn capacity(&self) -> f64 {
+        self.burst.map_or(self.rate.ceil().max(1.0), f64::from)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !(self.rate > 0.0 && self.rate.is_finite()) {
+            return Err("rate must be a positive number".to_owned());
+        }
+        if self.burst == Some(0) {
+            return Err("burst must be at least 1".to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// One bucket shared by all calls matching `call`, across users.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MethodRateLimit {
+    pub call: CallPattern,
+    #[serde(flatten)]
+    pub limit: RateLimit,
+}
+
+/// Caps on calls open at the same time. A streaming call stays open until
+/// its response ends.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ConcurrencyLimitsConfig {
+    /// Applies to each user separately, unless the user sets
+    /// `max_concurrent_calls`.
+    #[serde(default)]
+    pub per_user: Option<usize>,
+    #[serde(default)]
+    pub methods: Vec<MethodConcurrencyLimit>,
+}
+
+impl ConcurrencyLimitsConfig {
+    fn validate(&self) -> Result<(), String> {
+        if self.per_user == Some(0) {
+            return Err("concurrency_limits.per_user must be at least 1".to_owned());
+        }
+        if let Some(method) = self.methods.iter().find(|method| method.max == 0) {
+            return Err(format!(
+                "concurrency limit for '{}' must be at least 1",
+                method.call
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// One limit shared by all calls matching `call`, across users.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MethodConcurrencyLimit {
+    pub call: CallPattern,
+    pub max: usize,
+}
+
+/// Deadlines enforced by the proxy. For each setting, the first matching
+/// method rule wins over the user's `deadline`, which wins over the
+/// top-level value.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DeadlinesConfig {
+    #[serde(flatten)]
+    pub deadline: DeadlineConfig,
+    #[serde(default)]
+    pub methods: Vec<MethodDeadline>,
+}
+
+impl DeadlinesConfig {
+    fn validate(&self) -> Result<(), String> {
+        self.deadline
+            .validate()
+            .map_err(|e| format!("deadlines: {e}"))?;
+        for method in &self.methods {
+            method
+                .deadline
+                .validate()
+                .map_err(|e| format!("deadline for '{}': {e}", method.call))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DeadlineConfig {
+    /// Applied to calls without a `grpc-timeout`.
+    #[serde(default)]
+    pub default_ms: Option<u64>,
+    /// Longer timeouts, and calls without any, are cut to this.
+    #[serde(default)]
+    pub max_ms: Option<u64>,
+}
+
+impl DeadlineConfig {
+    fn validate(&self) -> Result<(), String> {
+        if self.default_ms == Some(0) || self.max_ms == Some(0) {
+            return Err("default_ms and max_ms must be at least 1".to_owned());
+        }
+        if let (Some(default_ms), Some(max_ms)) = (self.default_ms, self.max_ms)
+            && default_ms > max_ms
+        {
+            return Err(format!(
+                "default_ms ({default_ms}) must not exceed max_ms ({max_ms})"
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MethodDeadline {
+    pub call: CallPattern,
+    #[serde(flatten)]
+    pub deadline: DeadlineConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RetryPolicy {
+    pub call: CallPattern,
+    /// Attempts in total, including the first one.
+    #[serde(default = "default_retry_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "default_retry_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    #[serde(default = "default_retry_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+    #[serde(default = "default_retry_backoff_multiplier")]
+    pub backoff_multiplier: f64,
+    /// Statuses, besides connection failures, that make an attempt worth
+    /// repeating. Only answers without a response message are retried.
+    #[serde(default = "default_retryable_status_codes")]
+    pub retryable_status_codes: Vec<GrpcCode>,
+    /// Requests with larger bodies are sent once, without retries.
+    #[serde(default = "default_retry_max_buffer_bytes")]
+    pub max_buffer_bytes: usize,
+    /// Sends another attempt whenever this long passes without an answer,
+    /// instead of waiting for a failure.
+    #[serde(default)]
+    pub hedging_delay_ms: Option<u64>,
+    /// Whether matching methods may stream requests. If false the request
+    /// body is always read to its end, up to `max_buffer_bytes`, however long
+    /// the client pauses between frames.
+    #[serde(default = "default_retry_client_streaming")]
+    pub client_streaming: bool,
+}
+
+impl RetryPolicy {
+    fn validate(&self) -> Result<(), String> {
+        if self.max_attempts == 0 {
+            return Err("max_attempts must be at least 1".to_owned());
+        }
+        if self.initial_backoff_ms > self.max_backoff_ms {
+            return Err(format!(
+                "initial_backoff_ms ({}) must not exceed max_backoff_ms ({})",
+                self.initial_backoff_ms, self.max_backoff_ms
+            ));
+        }
+        if !(self.backoff_multiplier >= 1.0 && self.backoff_multiplier.is_finite()) {
+            return Err("backoff_multiplier must be at least 1".to_owned());
+        }
+        if self.hedging_delay_ms == Some(0) {
+            return Err("hedging_delay_ms must be at least 1".to_owned());
+        }
+        Ok(())
+    }
+}
+
+fn default_retry_max_attempts() -> u32 {
+    3
+}
+
+fn default_retry_initial_backoff_ms() -> u64 {
+    50
+}
+
+fn default_retry_max_backoff_ms() -> u64 {
+    1000
+}
+
+fn default_retry_backoff_multiplier() -> f64 {
+    2.0
+}
+
+fn default_retryable_status_codes() -> Vec<GrpcCode> {
+    vec![GrpcCode::Unavailable]
+}
+
+fn default_retry_client_streaming() -> bool {
+    true
+}
+
+fn default_retry_max_buffer_bytes() -> usize {
+    64 * 1024
+}
+
+/// gRPC status codes by their canonical names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum GrpcCode {
+    Ok = 0,
+    Cancelled = 1,
+    Unknown = 2,
+    InvalidArgument = 3,
+    DeadlineExceeded = 4,
+    NotFound = 5,
+    AlreadyExists = 6,
+    PermissionDenied = 7,
+    ResourceExhausted = 8,
+    FailedPrecondition = 9,
+    Aborted = 10,
+    OutOfRange = 11,
+    Unimplemented = 12,
+    Internal = 13,
+    Unavailable = 14,
+    DataLoss = 15,
+    Unauthenticated = 16,
+}
+
+/// The health service answered by the proxy itself.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct HealthConfig {
+    /// Report NOT_SERVING while an upstream has no healthy endpoint, instead
+    /// of only while shutting down.
+    #[serde(default)]
+    pub check_upstreams: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoleConfig {
+    #[serde(default)]
+    pub allowed_calls: Vec<CallPattern>,
+    #[serde(default)]
+    pub denied_calls: Vec<CallPattern>,
+    /// Roles whose allowed calls are granted as well.
+    #[serde(default)]
+    pub inherits: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct UserConfig {
-    pub allowed_calls: Vec<String>,
+    #[serde(default)]
+    pub allowed_calls: Vec<CallPattern>,
+    #[serde(default)]
+    pub denied_calls: Vec<CallPattern>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// Networks this user may call from, on top of the global lists.
+    #[serde(default)]
+    pub allowed_networks: Vec<Cidr>,
+    #[serde(default)]
+    pub denied_networks: Vec<Cidr>,
+    /// Replaces `rate_limits.per_user` for this user.
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    /// Replaces `concurrency_limits.per_user` for this user.
+    #[serde(default)]
+    pub max_concurrent_calls: Option<usize>,
+    #[serde(default)]
+    pub deadline: Option<DeadlineConfig>,
 }
 
 #[derive(Debug)]
 pub struct Credentials {
     pub users: HashMap<String, String>,
+    /// API tokens keyed by token name.
+    pub tokens: HashMap<String, ApiToken>,
+}
+
+#[derive(Debug)]
+pub struct ApiToken {
+    pub username: String,
+    pub hash: String,
 }
 
 impl Credentials {
     pub fn empty() -> Self {
         Self {
             users: HashMap::new(),
+            tokens: HashMap::new(),
         }
     }
 }
@@ -35,7 +1120,15 @@ impl Credentials {
 pub fn load_config(path: &str) -> Result<Config, ProxyError> {
     let content = std::fs::read_to_string(path)
         .map_err(|e| ProxyError::ConfigLoad(format!("{path}: {e}")))?;
-    toml::from_str(&content).map_err(|e| ProxyError::ConfigLoad(format!("{path}: {e}")))
+    let mut config: Config =
+        toml::from_str(&content).map_err(|e| ProxyError::ConfigLoad(format!("{path}: {e}")))?;
+    config
+        .normalize_listeners()
+        .and_then(|()| config.normalize_upstreams())
+        .and_then(|()| config.validate())
+        .map_err(|e| ProxyError::ConfigLoad(format!("{path}: {e}")))?;
+    config.hash = format!("{:x}", Sha256::digest(content.as_bytes()));
+    Ok(config)
 }
 
 pub fn load_credentials(path: &str) -> Result<Credentials, ProxyError> {
@@ -43,19 +1136,356 @@ pub fn load_credentials(path: &str) -> Result<Credentials, ProxyError> {
         .map_err(|e| ProxyError::CredentialsLoad(format!("{path}: {e}")))?;
 
     let mut users = HashMap::new();
+    let mut tokens = HashMap::new();
     for (line_num, line) in content.lines().enumerate() {
         let line = line.trim();
         if line.is_empty() || line.starts_with('#') {
             continue;
         }
-        let (username, hash) = line.split_once(':').ok_or_else(|| {
-            ProxyError::CredentialsLoad(format!(
-                "line {}: expected 'username:argon2_hash' format",
-                line_num + 1
-            ))
-        })?;
-        users.insert(username.to_owned(), hash.to_owned());
+
+        // argon2 hashes never contain ':', so the field count tells the two
+        // line formats apart.
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields.as_slice() {
+            [username, hash] => {
+                users.insert((*username).to_owned(), (*hash).to_owned());
+            }
+            ["token", name, username, hash] => {
+                if name.is_empty() || name.contains('.') {
+                    return Err(ProxyError::CredentialsLoad(format!(
+                        "line {}: token name must be non-empty and must not contain '.'",
+                        line_num + 1
+                    )));
+                }
+                let token = ApiToken {
+                    username: (*username).to_owned(),
+                    hash: (*hash).to_owned(),
+                };
+                if tokens.insert((*name).to_owned(), token).is_some() {
+                    return Err(ProxyError::CredentialsLoad(format!(
+                        "line {}: duplicate token name '{name}'",
+                        line_num + 1
+                    )));
+                }
+            }
+            _ => {
+                return Err(ProxyError::CredentialsLoad(format!(
+                    "line {}: expected 'username:argon2_hash' or 'token:name:username:argon2_hash' format",
+                    line_num + 1
+                )));
+            }
+        }
+    }
+
+    Ok(Credentials { users, tokens })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn credentials(content: &str) -> Result<Credentials, ProxyError> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "grpc-proxier-credentials-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, content).unwrap();
+        let result = load_credentials(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    fn parse(toml: &str) -> Config {
+        let mut config: Config = toml::from_str(&format!(
+            "listen_address = \"127.0.0.1:50051\"\nmetrics_address = \"127.0.0.1:9090\"\nupstream_address = \"127.0.0.1:50052\"\n{toml}"
+        ))
+        .unwrap();
+        config.normalize_listeners().unwrap();
+        config.normalize_upstreams().unwrap();
+        config
+    }
+
+    const ROLES: &str = r#"
+        [roles.admin]
+        inherits = ["writer", "auditor"]
+        [roles.writer]
+        inherits = ["reader"]
+        [roles.auditor]
+        inherits = ["reader"]
+        [roles.reader]
+    "#;
+
+    #[test]
+    fn expands_inherited_roles_once_in_order() {
+        let config = parse(ROLES);
+        assert_eq!(
+            config.expand_roles(["admin"]),
+            ["admin", "writer", "reader", "auditor"]
+        );
+        assert_eq!(
+            config.expand_roles(["auditor", "writer"]),
+            ["auditor", "reader", "writer"]
+        );
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn skips_unknown_roles() {
+        let config = parse(ROLES);
+        assert_eq!(
+            config.expand_roles(["nobody", "writer", "nobody"]),
+            ["writer", "reader"]
+        );
+        assert!(config.expand_roles(["nobody"]).is_empty());
+    }
+
+    #[test]
+    fn rejects_inheritance_cycles() {
+        let config = parse(
+            r#"
+            [roles.a]
+            inherits = ["b"]
+            [roles.b]
+            inherits = ["c"]
+            [roles.c]
+            inherits = ["a"]
+            "#,
+        );
+        let error = config.validate().unwrap_err();
+        assert!(error.starts_with("role inheritance cycle: "), "{error}");
+        for cycle in ["a -> b -> c -> a", "b -> c -> a -> b", "c -> a -> b -> c"] {
+            if error.ends_with(cycle) {
+                return;
+            }
+        }
+        panic!("unexpected cycle: {error}");
+    }
+
+    #[test]
+    fn rejects_self_inheritance_and_unknown_roles() {
+        let config = parse("[roles.a]\ninherits = [\"a\"]");
+        assert_eq!(
+            config.validate().unwrap_err(),
+            "role inheritance cycle: a -> a"
+        );
+
+        let config = parse("[roles.a]\ninherits = [\"missing\"]");
+        assert_eq!(
+            config.validate().unwrap_err(),
+            "role 'a' inherits unknown role 'missing'"
+        );
+
+        let config = parse("[users.alice]\nroles = [\"missing\"]");
+        assert_eq!(
+            config.validate().unwrap_err(),
+            "user 'alice' references unknown role 'missing'"
+        );
+    }
+
+    #[test]
+    fn rejects_default_deadlines_above_the_maximum() {
+        let config = parse("[deadlines]\ndefault_ms = 5000\nmax_ms = 5000");
+        assert!(config.validate().is_ok());
+
+        let config = parse("[deadlines]\ndefault_ms = 5001\nmax_ms = 5000");
+        assert_eq!(
+            config.validate().unwrap_err(),
+            "deadlines: default_ms (5001) must not exceed max_ms (5000)"
+        );
+
+        let config = parse("[users.alice]\ndeadline = { default_ms = 60000, max_ms = 1000 }");
+        assert_eq!(
+            config.validate().unwrap_err(),
+            "user 'alice' deadline: default_ms (60000) must not exceed max_ms (1000)"
+        );
+    }
+
+    #[test]
+    fn rejects_initial_backoff_above_the_maximum() {
+        let config = parse(
+            "[[retries]]\ncall = \"a.S/*\"\ninitial_backoff_ms = 2000\nmax_backoff_ms = 2000",
+        );
+        assert!(config.validate().is_ok());
+        assert!(config.retries[0].client_streaming);
+
+        let config = parse("[[retries]]\ncall = \"a.S/*\"\ninitial_backoff_ms = 2000");
+        assert_eq!(
+            config.validate().unwrap_err(),
+            "retry policy for 'a.S/*': initial_backoff_ms (2000) must not exceed max_backoff_ms (1000)"
+        );
+    }
+
+    /// Like `parse`, but without `upstream_address`, and returns the
+    /// normalization error instead of panicking.
+    fn normalize(toml: &str) -> Result<Config, String> {
+        let mut config: Config =
+            toml::from_str(&format!("metrics_address = \"127.0.0.1:9090\"\n{toml}")).unwrap();
+        config.normalize_upstreams()?;
+        Ok(config)
+    }
+
+    const ROUTES: &str = r#"
+        [[routes]]
+        service = "billing.Invoices"
+        upstream = "invoices"
+        [[routes]]
+        service = "billing.*"
+        upstream = "billing"
+        [[routes]]
+        service = "regex:(shop|cart)\\.[A-Z][a-z]+"
+        upstream = "shop"
+
+        [upstreams.invoices]
+        address = "127.0.0.1:50061"
+        [upstreams.billing]
+        address = "127.0.0.1:50062"
+        [upstreams.shop]
+        address = "127.0.0.1:50063"
+    "#;
+
+    #[test]
+    fn first_matching_route_wins() {
+        let config = parse(ROUTES);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.route("billing.Invoices"), Some("invoices"));
+        assert_eq!(config.route("billing.Payments"), Some("billing"));
+        assert_eq!(config.route("billing.v2.Invoices"), Some("billing"));
+        assert_eq!(config.route("shop.Catalog"), Some("shop"));
+        assert_eq!(config.route("cart.Items"), Some("shop"));
+    }
+
+    #[test]
+    fn unmatched_services_go_to_the_default_upstream() {
+        let config = parse(ROUTES);
+        assert_eq!(config.default_upstream.as_deref(), Some(DEFAULT_UPSTREAM));
+        assert_eq!(config.route("other.Service"), Some(DEFAULT_UPSTREAM));
+        // Patterns match the whole service name.
+        assert_eq!(config.route("billing"), Some(DEFAULT_UPSTREAM));
+        assert_eq!(config.route("shop.CatalogV2"), Some(DEFAULT_UPSTREAM));
+
+        let config = normalize(&format!("default_upstream = \"billing\"\n{ROUTES}")).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.route("other.Service"), Some("billing"));
+    }
+
+    #[test]
+    fn unmatched_services_without_a_default_have_no_route() {
+        let config = normalize(ROUTES).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.default_upstream, None);
+        assert_eq!(config.route("billing.Invoices"), Some("invoices"));
+        assert_eq!(config.route("other.Service"), None);
+        assert_eq!(
+            ProxyError::NoRoute("other.Service".to_owned()).grpc_status_code(),
+            12
+        );
+    }
+
+    #[test]
+    fn upstream_shorthand_conflicts() {
+        assert_eq!(
+            normalize(
+                "upstream_address = \"127.0.0.1:50052\"\n[upstreams.default]\naddress = \"127.0.0.1:50053\""
+            )
+            .unwrap_err(),
+            "upstream_address conflicts with [upstreams.default], set only one"
+        );
+        assert_eq!(
+            normalize("[upstream_tls]\n[upstreams.default]\naddress = \"127.0.0.1:50053\"")
+                .unwrap_err(),
+            "upstream_tls requires upstream_address"
+        );
+
+        // Named upstreams next to the shorthand are fine.
+        let config = normalize(
+            "upstream_address = \"127.0.0.1:50052\"\n[upstreams.billing]\naddress = \"127.0.0.1:50053\"",
+        )
+        .unwrap();
+        assert_eq!(
+            config.upstreams[DEFAULT_UPSTREAM].address.as_deref(),
+            Some("127.0.0.1:50052")
+        );
+        assert!(config.upstreams.contains_key("billing"));
+    }
+
+    #[test]
+    fn rejects_routes_to_unknown_upstreams() {
+        let config = parse("[[routes]]\nservice = \"billing.*\"\nupstream = \"billing\"");
+        assert_eq!(
+            config.validate().unwrap_err(),
+            "route 'billing.*' references unknown upstream 'billing'"
+        );
+
+        let config = normalize(
+            "default_upstream = \"missing\"\n[upstreams.billing]\naddress = \"127.0.0.1:50053\"",
+        )
+        .unwrap();
+        assert_eq!(
+            config.validate().unwrap_err(),
+            "default_upstream references unknown upstream 'missing'"
+        );
+    }
+
+    fn load_error(content: &str) -> String {
+        match credentials(content) {
+            Err(ProxyError::CredentialsLoad(message)) => message,
+            other => panic!("expected a load error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn loads_passwords_and_tokens() {
+        let credentials = credentials(
+            "# comment\n\
+             alice:$argon2id$v=19$m=8,t=1,p=1$c2FsdHNhbHQ$aGFzaA\n\
+             \n\
+             token:ci:bob:$argon2id$v=19$m=8,t=1,p=1$c2FsdHNhbHQ$dG9rZW4\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            credentials.users["alice"],
+            "$argon2id$v=19$m=8,t=1,p=1$c2FsdHNhbHQ$aGFzaA"
+        );
+        assert!(!credentials.users.contains_key("bob"));
+        let token = &credentials.tokens["ci"];
+        assert_eq!(token.username, "bob");
+        assert_eq!(token.hash, "$argon2id$v=19$m=8,t=1,p=1$c2FsdHNhbHQ$dG9rZW4");
+    }
+
+    #[test]
+    fn tokens_may_name_users_without_a_password() {
+        // Whether the user exists is decided by the config at request time,
+        // not when the credentials file is read.
+        let credentials = credentials("token:deploy:ghost:$argon2id$hash\n").unwrap();
+        assert_eq!(credentials.tokens["deploy"].username, "ghost");
+        assert!(credentials.users.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for line in [
+            "alice",
+            "alice:hash:extra",
+            "token:ci:bob",
+            "token:ci:bob:hash:extra",
+        ] {
+            let message = load_error(&format!("# header\n{line}\n"));
+            assert!(message.starts_with("line 2: expected"), "{line}: {message}");
+        }
     }
 
-    Ok(Credentials { users })
+    #[test]
+    fn rejects_bad_token_names() {
+        assert!(load_error("token::bob:hash\n").contains("must be non-empty"));
+        assert!(load_error("token:c.i:bob:hash\n").contains("must not contain '.'"));
+        assert_eq!(
+            load_error("token:ci:bob:hash\ntoken:ci:alice:hash\n"),
+            "line 2: duplicate token name 'ci'"
+        );
+    }
 }
@@ -0,0 +1,216 @@
+use std::time::Duration;
+
+use http::HeaderValue;
+
+use crate::config::Config;
+
+/// The timeout to enforce for a call: the client's `grpc-timeout`, or the
+/// configured default if it sent none, capped at the configured maximum.
+/// `None` means the call may run forever.
+pub fn effective_timeout(
+    config: &Config,
+    user: &str,
+    call: &str,
+    requested: Option<&HeaderValue>,
+) -> Option<Duration> {
+    let method = config
+        .deadlines
+        .methods
+        .iter()
+        .find(|method| method.call.matches(call))
+        .map(|method| &method.deadline);
+    let user = config
+        .users
+        .get(user)
+        .and_then(|user| user.deadline.as_ref());
+    let layers = [method, user, Some(&config.deadlines.deadline)];
+
+    let default_ms = layers.iter().flatten().find_map(|layer| layer.default_ms);
+    let max_ms = layers.iter().flatten().find_map(|layer| layer.max_ms);
+
+    let timeout = requested
+        .and_then(parse_timeout)
+        .or(default_ms.map(Duration::from_millis));
+    match (timeout, max_ms.map(Duration::from_millis)) {
+        (Some(timeout), Some(max)) => Some(timeout.min(max)),
+        (timeout, max) => timeout.or(max),
+    }
+}
+
+/// Parses a `grpc-timeout` value: up to eight digits followed by a unit.
+fn parse_timeout(value: &HeaderValue) -> Option<Duration> {
+    let value = value.to_str().ok()?;
+    let (digits, unit) = value.split_at_checked(value.len().checked_sub(1)?)?;
+    if digits.is_empty() || digits.len() > 8 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let amount: u64 = digits.parse().ok()?;
+    Some(match unit {
+        "H" => Duration::from_secs(amount * 3600),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    })
+}
+
+/// Encodes `timeout` as a `grpc-timeout` value in the finest unit that fits
+/// into eight digits.
+pub fn encode_timeout(timeout: Duration) -> HeaderValue {
+    const MAX: u128 = 99_999_999;
+    let (amount, unit) = [
+        (timeout.as_micros(), 'u'),
+        (timeout.as_millis(), 'm'),
+        (u128::from(timeout.as_secs()), 'S'),
+        (u128::from(timeout.as_secs() / 60), 'M'),
+    ]
+    .into_iter()
+    .find(|(amount, _)| *amount <= MAX)
+    .unwrap_or(((u128::from(timeout.as_secs()) / 3600).min(MAX), 'H'));
+
+    HeaderValue::from_str(&format!("{amount}{unit}"))
+        .expect("digits and a unit are a valid header value")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(value: &str) -> Option<Duration> {
+        parse_timeout(&HeaderValue::from_str(value).unwrap())
+    }
+
+    fn load_config(toml: &str) -> Config {
+        toml::from_str(&format!("metrics_address = \"127.0.0.1:9090\"\n{toml}")).unwrap()
+    }
+
+    #[test]
+    fn parses_every_unit() {
+        assert_eq!(parse("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse("3M"), Some(Duration::from_secs(180)));
+        assert_eq!(parse("4S"), Some(Duration::from_secs(4)));
+        assert_eq!(parse("5m"), Some(Duration::from_millis(5)));
+        assert_eq!(parse("6u"), Some(Duration::from_micros(6)));
+        assert_eq!(parse("7n"), Some(Duration::from_nanos(7)));
+        assert_eq!(parse("0m"), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn allows_at_most_eight_digits() {
+        assert_eq!(parse("99999999S"), Some(Duration::from_secs(99_999_999)));
+        assert_eq!(parse("00000001m"), Some(Duration::from_millis(1)));
+        assert_eq!(parse("100000000S"), None);
+    }
+
+    #[test]
+    fn largest_value_does_not_overflow() {
+        assert_eq!(
+            parse("99999999H"),
+            Some(Duration::from_secs(99_999_999 * 3600))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_values() {
+        for value in [
+            "", "S", "10", "10s", "10 S", "-1S", "+1S", "1.5S", "1e3m", "S10",
+        ] {
+            assert_eq!(parse(value), None, "{value:?}");
+        }
+        let binary = HeaderValue::from_bytes(b"1\xffS").unwrap();
+        assert_eq!(parse_timeout(&binary), None);
+    }
+
+    #[test]
+    fn encodes_in_the_finest_unit_that_fits() {
+        assert_eq!(encode_timeout(Duration::from_millis(1500)), "1500000u");
+        assert_eq!(encode_timeout(Duration::from_secs(1000)), "1000000m");
+        assert_eq!(encode_timeout(Duration::from_secs(200_000)), "200000S");
+        assert_eq!(encode_timeout(Duration::from_secs(200_000_000)), "3333333M");
+        assert_eq!(encode_timeout(Duration::from_secs(u64::MAX)), "99999999H");
+        assert_eq!(encode_timeout(Duration::ZERO), "0u");
+    }
+
+    #[test]
+    fn encoded_values_parse_back() {
+        for timeout in [
+            Duration::from_micros(1),
+            Duration::from_millis(250),
+            Duration::from_secs(30),
+            Duration::from_secs(86_400 * 365),
+        ] {
+            assert_eq!(parse_timeout(&encode_timeout(timeout)), Some(timeout));
+        }
+    }
+
+    #[test]
+    fn caps_and_reencodes_requested_timeout() {
+        let config = load_config("[deadlines]\nmax_ms = 5000\n");
+        let requested = HeaderValue::from_static("1M");
+        let timeout = effective_timeout(&config, "alice", "pkg.Svc/Get", Some(&requested));
+        assert_eq!(timeout, Some(Duration::from_secs(5)));
+        assert_eq!(encode_timeout(timeout.unwrap()), "5000000u");
+
+        let requested = HeaderValue::from_static("2S");
+        let timeout = effective_timeout(&config, "alice", "pkg.Svc/Get", Some(&requested));
+        assert_eq!(timeout, Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn default_and_max_apply_without_a_header() {
+        let config = load_config("[deadlines]\ndefault_ms = 100\n");
+        assert_eq!(
+            effective_timeout(&config, "alice", "pkg.Svc/Get", None),
+            Some(Duration::from_millis(100))
+        );
+
+        let config = load_config("[deadlines]\nmax_ms = 700\n");
+        assert_eq!(
+            effective_timeout(&config, "alice", "pkg.Svc/Get", None),
+            Some(Duration::from_millis(700))
+        );
+
+        let malformed = HeaderValue::from_static("soon");
+        assert_eq!(
+            effective_timeout(&config, "alice", "pkg.Svc/Get", Some(&malformed)),
+            Some(Duration::from_millis(700))
+        );
+    }
+
+    #[test]
+    fn method_and_user_layers_take_precedence() {
+        let config = load_config(
+            r#"
+            [deadlines]
+            default_ms = 1000
+            max_ms = 10000
+
+            [[deadlines.methods]]
+            call = "pkg.Svc/Slow"
+            max_ms = 60000
+
+            [users.bob]
+            deadline = { default_ms = 2000 }
+            "#,
+        );
+        let requested = HeaderValue::from_static("30S");
+        assert_eq!(
+            effective_timeout(&config, "alice", "pkg.Svc/Slow", Some(&requested)),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            effective_timeout(&config, "alice", "pkg.Svc/Get", Some(&requested)),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            effective_timeout(&config, "bob", "pkg.Svc/Get", None),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            effective_timeout(&config, "alice", "pkg.Svc/Get", None),
+            Some(Duration::from_secs(1))
+        );
+    }
+}
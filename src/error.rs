@@ -1,5 +1,5 @@
 use bytes::Bytes;
-use http::Response;
+use http::{HeaderMap, HeaderValue, Response};
 use http_body_util::Full;
 
 #[derive(Debug, thiserror::Error)]
@@ -19,6 +19,39 @@ pub enum ProxyError {
     #[error("call not permitted: {0}")]
     AuthDenied(String),
 
+    #[error("address not permitted: {0}")]
+    AddressDenied(String),
+
+    #[error("too many pending credential verifications")]
+    AuthOverloaded,
+
+    #[error("credential verification unavailable")]
+    AuthUnavailable,
+
+    #[error("{limit} rate limit exceeded for '{user}'")]
+    RateLimited { user: String, limit: &'static str },
+
+    #[error("{limit} concurrent call limit reached for '{user}'")]
+    ConcurrencyLimited { user: String, limit: &'static str },
+
+    #[error("no upstream for service '{0}'")]
+    NoRoute(String),
+
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+
+    #[error("request body: {0}")]
+    RequestBody(String),
+
+    #[error("unknown service '{0}'")]
+    UnknownService(String),
+
+    #[error("unknown method '{0}'")]
+    UnknownMethod(String),
+
+    #[error("deadline exceeded")]
+    DeadlineExceeded,
+
     #[error("upstream connection failed: {0}")]
     UpstreamConnect(String),
 
@@ -27,18 +60,37 @@ pub enum ProxyError {
 
     #[error("failed to bind server: {0}")]
     ServerBind(String),
+
+    #[error("failed to start auth pool: {0}")]
+    AuthPoolStart(String),
+
+    #[error("invalid TLS configuration: {0}")]
+    TlsConfig(String),
+
+    #[error("invalid JWT configuration: {0}")]
+    JwtConfig(String),
 }
 
 impl ProxyError {
     pub fn grpc_status_code(&self) -> u8 {
         match self {
             Self::AuthMissing | Self::AuthInvalid => 16, // UNAUTHENTICATED
-            Self::AuthDenied(_) => 7,                    // PERMISSION_DENIED
-            Self::UpstreamConnect(_) => 14,              // UNAVAILABLE
+            Self::AuthDenied(_) | Self::AddressDenied(_) => 7, // PERMISSION_DENIED
+            // RESOURCE_EXHAUSTED
+            Self::AuthOverloaded | Self::RateLimited { .. } | Self::ConcurrencyLimited { .. } => 8,
+            Self::RequestBody(_) => 1,                       // CANCELLED
+            Self::InvalidRequest(_) => 3,                    // INVALID_ARGUMENT
+            Self::UnknownService(_) => 5,                    // NOT_FOUND
+            Self::NoRoute(_) | Self::UnknownMethod(_) => 12, // UNIMPLEMENTED
+            Self::DeadlineExceeded => 4,                     // DEADLINE_EXCEEDED
+            Self::UpstreamConnect(_) | Self::AuthUnavailable => 14, // UNAVAILABLE
             Self::UpstreamRequest(_)
             | Self::ConfigLoad(_)
             | Self::CredentialsLoad(_)
-            | Self::ServerBind(_) => 13, // INTERNAL
+            | Self::ServerBind(_)
+            | Self::AuthPoolStart(_)
+            | Self::TlsConfig(_)
+            | Self::JwtConfig(_) => 13, // INTERNAL
         }
     }
 
@@ -47,6 +99,9 @@ impl ProxyError {
             Self::AuthMissing => "missing",
             Self::AuthInvalid => "invalid",
             Self::AuthDenied(_) => "denied",
+            Self::AddressDenied(_) => "address_denied",
+            Self::AuthOverloaded => "overloaded",
+            Self::AuthUnavailable => "unavailable",
             _ => "unknown",
         }
     }
@@ -63,6 +118,20 @@ impl ProxyError {
             .body(Full::new(Bytes::new()))
             .unwrap_or_else(|_| Response::new(Full::new(Bytes::new())))
     }
+
+    /// The error as trailers, for ending a response whose headers have
+    /// already been sent.
+    pub fn to_grpc_trailers(&self) -> HeaderMap {
+        let mut trailers = HeaderMap::new();
+        trailers.insert(
+            "grpc-status",
+            HeaderValue::from(u16::from(self.grpc_status_code())),
+        );
+        if let Ok(message) = HeaderValue::from_str(&percent_encode(&self.to_string())) {
+            trailers.insert("grpc-message", message);
+        }
+        trailers
+    }
 }
 
 fn percent_encode(s: &str) -> String {
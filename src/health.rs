@@ -0,0 +1,325 @@
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use http::{HeaderMap, HeaderValue, Request, Response};
+use http_body_util::{BodyExt, Limited};
+use hyper::body::{Body, Frame, Incoming};
+use tokio::sync::mpsc;
+
+use crate::error::ProxyError;
+use crate::proxy::AppState;
+
+// The few grpc.health.v1 messages the proxy needs are encoded by hand, so no
+// protobuf toolchain is required at build time.
+pub const SERVICE_PREFIX: &str = "/grpc.health.v1.Health/";
+pub const CHECK_PATH: &str = "/grpc.health.v1.Health/Check";
+
+/// `HealthCheckResponse.ServingStatus` values.
+pub const SERVING: u64 = 1;
+const NOT_SERVING: u64 = 2;
+const SERVICE_UNKNOWN: u64 = 3;
+
+const MAX_REQUEST_BYTES: usize = 4096;
+
+/// How often open `Watch` streams re-evaluate the status.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Response body fed from a channel, for answers produced by the proxy itself.
+pub struct LocalBody(mpsc::Receiver<Frame<Bytes>>);
+
+impl Body for LocalBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        self.0.poll_recv(cx).map(|frame| frame.map(Ok))
+    }
+}
+
+/// Answers `grpc.health.v1.Health` calls without authentication or an
+/// upstream round trip. `method` is the path after [`SERVICE_PREFIX`]. The
+/// calls are never forwarded, so this hides any upstream health service.
+pub async fn serve(
+    req: Request<Incoming>,
+    method: &str,
+    state: Arc<AppState>,
+) -> Result<Response<LocalBody>, ProxyError> {
+    if method != "Check" && method != "Watch" {
+        return Err(ProxyError::UnknownMethod(format!(
+            "{SERVICE_PREFIX}{method}"
+        )));
+    }
+
+    let body = Limited::new(req.into_body(), MAX_REQUEST_BYTES)
+        .collect()
+        .await
+        .map_err(|e| ProxyError::InvalidRequest(e.to_string()))?
+        .to_bytes();
+    let service = decode_check_request(&body)
+        .ok_or_else(|| ProxyError::InvalidRequest("malformed HealthCheckRequest".to_owned()))?;
+
+    let (sender, receiver) = mpsc::channel(2);
+    if method == "Check" {
+        let status = serving_status(&state, &service)
+            .ok_or_else(|| ProxyError::UnknownService(service.clone()))?;
+        let _ = sender.try_send(Frame::data(encode_check_response(status)));
+        let _ = sender.try_send(Frame::trailers(grpc_status(0)));
+    } else {
+        tokio::spawn(watch(state, service, sender));
+    }
+
+    Ok(Response::builder()
+        .status(200)
+        .header("content-type", "application/grpc")
+        .body(LocalBody(receiver))
+        .unwrap_or_else(|_| Response::new(LocalBody(mpsc::channel(1).1))))
+}
+
+/// Streams the status of `service` whenever it changes, until the client goes
+/// away or the proxy starts shutting down.
+async fn watch(state: Arc<AppState>, service: String, sender: mpsc::Sender<Frame<Bytes>>) {
+    let mut last = None;
+    loop {
+        let status = serving_status(&state, &service).unwrap_or(SERVICE_UNKNOWN);
+        if last != Some(status) {
+            if sender
+                .send(Frame::data(encode_check_response(status)))
+                .await
+                .is_err()
+            {
+                return;
+            }
+            last = Some(status);
+        }
+
+        // Ending the stream lets the client reconnect elsewhere instead of
+        // holding up the drain.
+        if state.draining.load(Ordering::Relaxed) {
+            let _ = sender.send(Frame::trailers(grpc_status(14))).await;
+            return;
+        }
+
+        tokio::select! {
+            () = tokio::time::sleep(WATCH_INTERVAL) => {}
+            () = sender.closed() => return,
+        }
+    }
+}
+
+/// The status reported for `service`, or `None` if the proxy would not route
+/// it. The empty service stands for the proxy as a whole.
+pub fn serving_status(state: &AppState, service: &str) -> Option<u64> {
+    let snapshot = state.snapshot.load();
+    let config = &snapshot.config;
+
+    let mut upstreams = Vec::new();
+    if service.is_empty() {
+        upstreams.extend(state.upstreams.values());
+    } else {
+        upstreams.push(state.upstreams.get(config.route(service)?)?);
+    }
+
+    let healthy = !state.draining.load(Ordering::Relaxed)
+        && (!config.health.check_upstreams
+            || upstreams
+                .iter()
+                .all(|upstream| upstream.has_healthy_endpoint()));
+    Some(if healthy { SERVING } else { NOT_SERVING })
+}
+
+fn grpc_status(code: u8) -> HeaderMap {
+    let mut trailers = HeaderMap::new();
+    trailers.insert("grpc-status", HeaderValue::from(u16::from(code)));
+    trailers
+}
+
+/// Encodes `HealthCheckRequest { service }` as a length-prefixed gRPC message.
+pub fn encode_check_request(service: &str) -> Bytes {
+    let mut message = BytesMut::new();
+    if !service.is_empty() {
+        message.put_u8(0x0a); // field 1, length-delimited
+        put_varint(&mut message, service.len() as u64);
+        message.put_slice(service.as_bytes());
+    }
+    frame(&message)
+}
+
+fn encode_check_response(status: u64) -> Bytes {
+    let mut message = BytesMut::new();
+    message.put_u8(0x08); // field 1, varint
+    put_varint(&mut message, status);
+    frame(&message)
+}
+
+/// Returns the service named in a `HealthCheckRequest`. An empty body is a
+/// request without any fields set.
+fn decode_check_request(body: &[u8]) -> Option<String> {
+    let mut service = String::new();
+    if body.is_empty() {
+        return Some(service);
+    }
+    for (field, value) in decode_fields(unframe(body)?)? {
+        if let (1, Value::Bytes(bytes)) = (field, value) {
+            service = String::from_utf8(bytes.to_vec()).ok()?;
+        }
+    }
+    Some(service)
+}
+
+/// Returns the serving status of the first `HealthCheckResponse` in a gRPC
+/// response body. A message without a status field decodes as UNKNOWN (0).
+pub fn decode_check_response(body: &[u8]) -> Option<u64> {
+    let mut status = 0;
+    for (field, value) in decode_fields(unframe(body)?)? {
+        if let (1, Value::Varint(value)) = (field, value) {
+            status = value;
+        }
+    }
+    Some(status)
+}
+
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+fn decode_fields(mut message: &[u8]) -> Option<Vec<(u64, Value<'_>)>> {
+    let mut fields = Vec::new();
+    while !message.is_empty() {
+        let tag = take_varint(&mut message)?;
+        let value = match tag & 0x7 {
+            0 => Value::Varint(take_varint(&mut message)?),
+            2 => {
+                let len = usize::try_from(take_varint(&mut message)?).ok()?;
+                let (bytes, rest) = message.split_at_checked(len)?;
+                message = rest;
+                Value::Bytes(bytes)
+            }
+            _ => return None,
+        };
+        fields.push((tag >> 3, value));
+    }
+    Some(fields)
+}
+
+fn frame(message: &[u8]) -> Bytes {
+    let mut framed = BytesMut::with_capacity(5 + message.len());
+    framed.put_u8(0); // not compressed
+    framed.put_u32(message.len() as u32);
+    framed.put_slice(message);
+    framed.freeze()
+}
+
+fn unframe(body: &[u8]) -> Option<&[u8]> {
+    let (header, rest) = body.split_at_checked(5)?;
+    if header[0] != 0 {
+        return None; // compressed messages are never requested
+    }
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    rest.get(..len)
+}
+
+fn put_varint(buf: &mut BytesMut, mut value: u64) {
+    while value >= 0x80 {
+        buf.put_u8((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+fn take_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for (index, byte) in buf.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * index);
+        if byte & 0x80 == 0 {
+            *buf = &buf[index + 1..];
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_request_round_trip() {
+        for service in ["", "inventory.InventoryService", &"x".repeat(300)] {
+            let encoded = encode_check_request(service);
+            assert_eq!(decode_check_request(&encoded).as_deref(), Some(service));
+        }
+    }
+
+    #[test]
+    fn empty_body_is_the_whole_server() {
+        assert_eq!(decode_check_request(b"").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn check_response_round_trip() {
+        for status in [0, SERVING, NOT_SERVING, SERVICE_UNKNOWN, 300, u64::MAX] {
+            let encoded = encode_check_response(status);
+            assert_eq!(decode_check_response(&encoded), Some(status));
+        }
+    }
+
+    #[test]
+    fn response_without_status_is_unknown() {
+        assert_eq!(decode_check_response(&frame(b"")), Some(0));
+    }
+
+    #[test]
+    fn unknown_fields_are_skipped() {
+        // field 2 varint, field 3 bytes, then field 1
+        let message = [0x10, 0x05, 0x1a, 0x02, b'h', b'i', 0x08, 0x01];
+        assert_eq!(decode_check_response(&frame(&message)), Some(SERVING));
+    }
+
+    #[test]
+    fn malformed_varints_are_rejected() {
+        // Continuation bit set on the last byte.
+        assert_eq!(decode_check_response(&frame(&[0x08, 0x81])), None);
+        // More than ten bytes.
+        let mut message = vec![0x08];
+        message.extend([0xff; 11]);
+        assert_eq!(decode_check_response(&frame(&message)), None);
+        // Truncated tag.
+        assert_eq!(decode_check_response(&frame(&[0x80])), None);
+    }
+
+    #[test]
+    fn bad_lengths_are_rejected() {
+        // Field length past the end of the message.
+        assert_eq!(decode_check_request(&frame(&[0x0a, 0x05, b'a'])), None);
+        // Frame length past the end of the body.
+        let mut body = encode_check_request("svc").to_vec();
+        body.pop();
+        assert_eq!(decode_check_request(&body), None);
+        // Shorter than the frame header.
+        assert_eq!(decode_check_response(&[0, 0, 0]), None);
+    }
+
+    #[test]
+    fn unsupported_encodings_are_rejected() {
+        // Compressed message.
+        let mut body = encode_check_request("svc").to_vec();
+        body[0] = 1;
+        assert_eq!(decode_check_request(&body), None);
+        // Fixed64 wire type.
+        assert_eq!(
+            decode_check_response(&frame(&[0x09, 0, 0, 0, 0, 0, 0, 0, 0])),
+            None
+        );
+        // Service name that isn't UTF-8.
+        assert_eq!(decode_check_request(&frame(&[0x0a, 0x01, 0xff])), None);
+    }
+}
@@ -1,23 +1,59 @@
 mod auth;
+mod auth_cache;
+mod auth_pool;
+mod breaker;
+mod cidr;
+mod cluster;
+mod concurrency;
 mod config;
+mod deadline;
 mod error;
+mod health;
+mod jwt;
 mod metrics;
+mod net;
+mod pattern;
 mod proxy;
+mod proxy_protocol;
+mod ratelimit;
+mod reload;
+mod retry;
+mod tls;
+mod upstream;
 
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-use hyper::body::Incoming;
+use arc_swap::ArcSwap;
+use hyper::rt::{Read, Write};
 use hyper::service::service_fn;
-use hyper_util::client::legacy::Client;
 use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::graceful::{GracefulShutdown, Watcher};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
 use tracing_subscriber::EnvFilter;
 
+use crate::config::{ClientIdentity, ListenerAuth};
 use crate::error::ProxyError;
 use crate::metrics::MetricsState;
-use crate::proxy::AppState;
+use crate::net::{Listener, PeerAddr, Stream};
+use crate::proxy::{AppState, ConnectionInfo, Snapshot};
+use crate::tls::ClientCertificate;
 
-#[tokio::main]
-async fn main() -> Result<(), ProxyError> {
+fn main() -> Result<(), ProxyError> {
+    // SAFETY: the runtime, and with it every other thread, starts below.
+    let inherited = unsafe { net::InheritedSockets::from_env() };
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("failed to build the tokio runtime")
+        .block_on(run(inherited))
+}
+
+async fn run(mut inherited: net::InheritedSockets) -> Result<(), ProxyError> {
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env())
         .init();
@@ -29,89 +65,517 @@ async fn main() -> Result<(), ProxyError> {
 
     let config = config::load_config(&config_path)?;
 
-    let credentials = if skip_auth {
-        config::Credentials::empty()
+    let credentials_path = if skip_auth {
+        None
     } else {
-        let credentials_path = std::env::var("CREDENTIALS_FILE").map_err(|_| {
+        Some(std::env::var("CREDENTIALS_FILE").map_err(|_| {
             ProxyError::CredentialsLoad("CREDENTIALS_FILE env var not set".to_owned())
-        })?;
-        config::load_credentials(&credentials_path)?
+        })?)
+    };
+    let credentials = match &credentials_path {
+        Some(path) => config::load_credentials(path)?,
+        None => config::Credentials::empty(),
     };
 
+    let listen_addresses: Vec<String> = config
+        .listeners
+        .iter()
+        .map(|listener| listener.address.to_string())
+        .collect();
     if skip_auth {
         tracing::warn!(
-            listen = %config.listen_address,
-            upstream = %config.upstream_address,
+            listen = ?listen_addresses,
+            upstreams = ?config.upstreams.keys().collect::<Vec<_>>(),
             metrics = %config.metrics_address,
             "starting grpc-proxier with authentication DISABLED"
         );
     } else {
         tracing::info!(
-            listen = %config.listen_address,
-            upstream = %config.upstream_address,
+            listen = ?listen_addresses,
+            upstreams = ?config.upstreams.keys().collect::<Vec<_>>(),
             metrics = %config.metrics_address,
+            tls = config.listeners.iter().any(|listener| listener.tls.is_some()),
+            routes = config.routes.len(),
             users = config.users.len(),
             "starting grpc-proxier"
         );
     }
 
     let metrics = MetricsState::new()?;
-    let metrics_registry = Arc::new(metrics.registry.clone());
-    let metrics_addr = config.metrics_address;
+    metrics.set_config_hash(&config.hash);
+
+    let upstreams = cluster::build_clusters(&config.upstreams, &metrics).await?;
+    for cluster in upstreams.values() {
+        tokio::spawn(cluster::refresh_endpoints(Arc::clone(cluster)));
+        tokio::spawn(cluster::check_health(Arc::clone(cluster)));
+    }
+
+    // Everything is bound before serving, so a bad address fails startup.
+    let mut listeners = Vec::new();
+    for listener_config in &config.listeners {
+        let tls_acceptor = match &listener_config.tls {
+            Some(tls_config) => {
+                let resolver = Arc::new(tls::ReloadingCertResolver::new(
+                    &tls_config.cert_path,
+                    &tls_config.key_path,
+                )?);
+                tokio::spawn(tls::watch_certificates(
+                    Arc::clone(&resolver),
+                    tls_config.clone(),
+                ));
+                Some(tls::build_acceptor(resolver, tls_config)?)
+            }
+            None => None,
+        };
+        let settings = Arc::new(ListenerSettings {
+            tls_acceptor,
+            auth: listener_config.auth,
+            client_identity: listener_config
+                .tls
+                .as_ref()
+                .map(|tls_config| tls_config.client_identity)
+                .unwrap_or_default(),
+            proxy_protocol: listener_config.proxy_protocol,
+        });
+
+        let address = &listener_config.address;
+        let bound = Listener::bind(address, &listener_config.socket, &mut inherited)
+            .await
+            .map_err(|e| ProxyError::ServerBind(format!("{address}: {e}")))?;
+        listeners.extend(
+            bound
+                .into_iter()
+                .map(|listener| (listener, Arc::clone(&settings))),
+        );
+    }
+
+    let metrics_addr = &config.metrics_address;
+    let metrics_listeners = Listener::bind(metrics_addr, &config.metrics_socket, &mut inherited)
+        .await
+        .map_err(|e| ProxyError::ServerBind(format!("metrics {metrics_addr}: {e}")))?;
 
-    let upstream_client: Client<_, Incoming> = Client::builder(TokioExecutor::new())
-        .http2_only(true)
-        .build_http();
+    let unused: Vec<&str> = inherited.unused().collect();
+    if !unused.is_empty() {
+        tracing::warn!(sockets = ?unused, "ignoring sockets from systemd that no listener uses");
+    }
+
+    let jwt = match &config.jwt {
+        Some(jwt_config) if !skip_auth => {
+            let validator = Arc::new(jwt::JwtValidator::new(jwt_config).await?);
+            tokio::spawn(jwt::refresh_keys(Arc::downgrade(&validator)));
+            Some(validator)
+        }
+        _ => None,
+    };
+
+    let verify_cache = auth_cache::VerifyCache::new(
+        &config.auth_cache,
+        metrics.auth_cache_hits_total.clone(),
+        metrics.auth_cache_misses_total.clone(),
+    );
+    let auth_pool =
+        auth_pool::AuthPool::new(&config.auth_pool, metrics.auth_queue_wait_seconds.clone())?;
 
     let state = Arc::new(AppState {
-        config,
-        credentials,
+        snapshot: ArcSwap::from_pointee(Snapshot {
+            config,
+            credentials,
+            jwt,
+        }),
+        verify_cache,
+        auth_pool,
+        rate_limiter: ratelimit::RateLimiter::new(),
+        concurrency_limiter: concurrency::ConcurrencyLimiter::new(metrics.in_flight_calls.clone()),
         skip_auth,
         metrics,
-        upstream_client,
+        upstreams,
+        draining: AtomicBool::new(false),
     });
 
-    tokio::spawn(crate::metrics::serve_metrics(
-        metrics_registry,
-        metrics_addr,
-    ));
+    for listener in metrics_listeners {
+        tokio::spawn(crate::metrics::serve_metrics(Arc::clone(&state), listener));
+    }
 
-    let listener = tokio::net::TcpListener::bind(state.config.listen_address)
-        .await
-        .map_err(|e| ProxyError::ServerBind(format!("{}: {e}", state.config.listen_address)))?;
+    tokio::spawn(reload::watch(reload::Reloader::new(
+        config_path,
+        credentials_path,
+        Arc::clone(&state),
+    )));
+
+    serve(listeners, state, shutdown_signal()).await;
+    Ok(())
+}
 
-    tracing::info!("proxy server listening on {}", state.config.listen_address);
+/// Serves connections from every listener until `shutdown` resolves. Then it
+/// stops accepting and lets every connection finish its open streams after a
+/// GOAWAY, closing those still open after `drain_timeout_secs`.
+async fn serve(
+    listeners: Vec<(Listener, Arc<ListenerSettings>)>,
+    state: Arc<AppState>,
+    shutdown: impl Future<Output = ()>,
+) {
+    let (accepted_tx, mut accepted_rx) = mpsc::channel(64);
+    let mut accept_tasks = JoinSet::new();
+    for (listener, settings) in listeners {
+        tracing::info!("proxy server listening on {}", listener.local_addr());
+        accept_tasks.spawn(accept_connections(listener, settings, accepted_tx.clone()));
+    }
+    drop(accepted_tx);
+
+    let graceful = GracefulShutdown::new();
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
 
     loop {
-        let (stream, peer_addr) = match listener.accept().await {
-            Ok(conn) => conn,
-            Err(e) => {
-                tracing::warn!("accept error: {e}");
-                continue;
+        tokio::select! {
+            Some(accepted) = accepted_rx.recv() => {
+                spawn_connection(&mut connections, accepted, &state, &graceful);
             }
-        };
+            // Reaps finished connections.
+            Some(_) = connections.join_next() => {}
+            () = &mut shutdown => break,
+        }
+    }
+
+    // Connections already accepted but still queued are served too, and see
+    // the drain from the start.
+    state.draining.store(true, Ordering::Relaxed);
+    state.metrics.draining.set(1);
+    accept_tasks.shutdown().await;
+    while let Some(accepted) = accepted_rx.recv().await {
+        spawn_connection(&mut connections, accepted, &state, &graceful);
+    }
+    let drain_timeout = Duration::from_secs(state.snapshot.load().config.drain_timeout_secs);
+    tracing::info!(
+        connections = graceful.count(),
+        timeout_secs = drain_timeout.as_secs(),
+        "shutting down, draining connections"
+    );
+
+    match tokio::time::timeout(drain_timeout, graceful.shutdown()).await {
+        Ok(()) => tracing::info!("all connections drained"),
+        Err(_) => tracing::warn!("drain timeout elapsed, closing remaining connections"),
+    }
+    connections.shutdown().await;
+}
+
+fn spawn_connection(
+    connections: &mut JoinSet<()>,
+    (stream, peer_addr, settings): Accepted,
+    state: &Arc<AppState>,
+    graceful: &GracefulShutdown,
+) {
+    let state = Arc::clone(state);
+    state.metrics.active_connections.inc();
+
+    let watcher = graceful.watcher();
 
-        let state = Arc::clone(&state);
-        state.metrics.active_connections.inc();
+    connections.spawn(async move {
+        handle_connection(stream, peer_addr, &settings, &state, watcher).await;
+        state.metrics.active_connections.dec();
+    });
+}
+
+/// What the connections accepted on one listener share.
+struct ListenerSettings {
+    tls_acceptor: Option<TlsAcceptor>,
+    auth: ListenerAuth,
+    client_identity: ClientIdentity,
+    proxy_protocol: bool,
+}
 
-        tokio::spawn(async move {
-            tracing::debug!(%peer_addr, "new connection");
+type Accepted = (Stream, PeerAddr, Arc<ListenerSettings>);
 
-            let conn_state = Arc::clone(&state);
-            let service = service_fn(move |req| {
-                let state = Arc::clone(&conn_state);
-                proxy::handle_request(req, state)
-            });
+/// Accepts connections on one listener and hands them to the main loop,
+/// until the loop stops taking them.
+async fn accept_connections(
+    listener: Listener,
+    settings: Arc<ListenerSettings>,
+    accepted: mpsc::Sender<Accepted>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer_addr)) => {
+                if accepted
+                    .send((stream, peer_addr, Arc::clone(&settings)))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Err(e) => tracing::warn!("accept error: {e}"),
+        }
+    }
+}
+
+/// Resolves on the first SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    tokio::select! {
+        () = wait_for_signal(SignalKind::terminate()) => tracing::info!("received SIGTERM"),
+        () = wait_for_signal(SignalKind::interrupt()) => tracing::info!("received SIGINT"),
+    }
+}
 
-            let result = hyper::server::conn::http2::Builder::new(TokioExecutor::new())
-                .serve_connection(TokioIo::new(stream), service)
-                .await;
+async fn wait_for_signal(kind: SignalKind) {
+    match signal(kind) {
+        Ok(mut signal) => {
+            signal.recv().await;
+        }
+        Err(e) => {
+            tracing::warn!("cannot listen for signal {}: {e}", kind.as_raw_value());
+            std::future::pending::<()>().await;
+        }
+    }
+}
 
-            state.metrics.active_connections.dec();
+async fn handle_connection(
+    mut stream: Stream,
+    peer_addr: PeerAddr,
+    settings: &ListenerSettings,
+    state: &Arc<AppState>,
+    watcher: Watcher,
+) {
+    let peer_addr = if settings.proxy_protocol {
+        match read_proxy_header(&mut stream, peer_addr).await {
+            Some(client_addr) => client_addr,
+            None => return,
+        }
+    } else {
+        peer_addr
+    };
+    tracing::debug!(%peer_addr, "new connection");
 
-            if let Err(e) = result {
-                tracing::debug!(%peer_addr, "connection closed: {e}");
+    match &settings.tls_acceptor {
+        Some(acceptor) => {
+            if let Some(tls_stream) = accept_tls(acceptor, stream, peer_addr).await {
+                let client_cert = tls_stream
+                    .get_ref()
+                    .1
+                    .peer_certificates()
+                    .and_then(|certs| certs.first())
+                    .and_then(ClientCertificate::from_der);
+                let conn = ConnectionInfo {
+                    peer_addr,
+                    client_cert,
+                    client_identity: settings.client_identity,
+                    auth: settings.auth,
+                };
+                serve_connection(TokioIo::new(tls_stream), conn, state, watcher).await;
             }
+        }
+        None => {
+            let conn = ConnectionInfo {
+                peer_addr,
+                client_cert: None,
+                client_identity: settings.client_identity,
+                auth: settings.auth,
+            };
+            serve_connection(TokioIo::new(stream), conn, state, watcher).await;
+        }
+    }
+}
+
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reads the PROXY protocol header and returns the client it names, or the
+/// connection's own peer if it names none.
+async fn read_proxy_header(stream: &mut Stream, peer_addr: PeerAddr) -> Option<PeerAddr> {
+    match tokio::time::timeout(PROXY_HEADER_TIMEOUT, proxy_protocol::read_header(stream)).await {
+        Ok(Ok(client_addr)) => Some(client_addr.map_or(peer_addr, PeerAddr::Tcp)),
+        Ok(Err(e)) => {
+            tracing::debug!(%peer_addr, "invalid PROXY protocol header: {e}");
+            None
+        }
+        Err(_) => {
+            tracing::debug!(%peer_addr, "PROXY protocol header timed out");
+            None
+        }
+    }
+}
+
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn accept_tls(
+    acceptor: &TlsAcceptor,
+    stream: Stream,
+    peer_addr: PeerAddr,
+) -> Option<tokio_rustls::server::TlsStream<Stream>> {
+    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(Ok(tls_stream)) => Some(tls_stream),
+        Ok(Err(e)) => {
+            tracing::debug!(%peer_addr, "TLS handshake failed: {e}");
+            None
+        }
+        Err(_) => {
+            tracing::debug!(%peer_addr, "TLS handshake timed out");
+            None
+        }
+    }
+}
+
+async fn serve_connection<I>(io: I, conn: ConnectionInfo, state: &Arc<AppState>, watcher: Watcher)
+where
+    I: Read + Write + Unpin + Send + 'static,
+{
+    let peer_addr = conn.peer_addr;
+    if let Some(cert) = &conn.client_cert {
+        tracing::debug!(%peer_addr, cn = ?cert.common_name, "client certificate presented");
+    }
+
+    let conn = Arc::new(conn);
+    let conn_state = Arc::clone(state);
+    let service = service_fn(move |req| {
+        let state = Arc::clone(&conn_state);
+        proxy::handle_request(req, state, Arc::clone(&conn))
+    });
+
+    let connection = hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+        .serve_connection(io, service);
+    let result = watcher.watch(connection).await;
+
+    if let Err(e) = result {
+        tracing::debug!(%peer_addr, "connection closed: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+
+    use bytes::Bytes;
+    use http::{Request, Response, StatusCode};
+    use http_body_util::Full;
+    use hyper::client::conn::http2::SendRequest;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::oneshot;
+    use tokio::task::JoinHandle;
+
+    use super::*;
+    use crate::cluster::tests::{StubUpstream, grpc_response, stub_upstream};
+    use crate::proxy::tests::app_state;
+
+    /// A proxy serving one unauthenticated TCP listener in front of `stub`.
+    struct Server {
+        address: SocketAddr,
+        state: Arc<AppState>,
+        shutdown: oneshot::Sender<()>,
+        serving: JoinHandle<()>,
+    }
+
+    async fn start(stub: &StubUpstream, drain_timeout_secs: u64) -> Server {
+        let config = toml::from_str(&format!(
+            "metrics_address = \"127.0.0.1:9090\"\ndefault_upstream = \"default\"\ndrain_timeout_secs = {drain_timeout_secs}"
+        ))
+        .unwrap();
+        let snapshot = Snapshot {
+            config,
+            credentials: config::Credentials::empty(),
+            jwt: None,
+        };
+        let upstreams = HashMap::from([("default".to_owned(), Arc::clone(&stub.cluster))]);
+        let state = Arc::new(app_state(snapshot, upstreams));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let settings = Arc::new(ListenerSettings {
+            tls_acceptor: None,
+            auth: ListenerAuth::Disabled,
+            client_identity: ClientIdentity::default(),
+            proxy_protocol: false,
         });
+        let (shutdown, signal) = oneshot::channel();
+        let serving = tokio::spawn(serve(
+            vec![(Listener::Tcp(listener), settings)],
+            Arc::clone(&state),
+            async {
+                let _ = signal.await;
+            },
+        ));
+
+        Server {
+            address,
+            state,
+            shutdown,
+            serving,
+        }
+    }
+
+    async fn connect(address: SocketAddr) -> SendRequest<Full<Bytes>> {
+        let stream = TcpStream::connect(address).await.unwrap();
+        let (sender, connection) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await
+                .unwrap();
+        tokio::spawn(connection);
+        sender
+    }
+
+    /// Opens a call and waits until the upstream has it.
+    async fn open_call(
+        sender: &mut SendRequest<Full<Bytes>>,
+        stub: &StubUpstream,
+    ) -> JoinHandle<hyper::Result<Response<hyper::body::Incoming>>> {
+        let calls = stub.bodies.lock().unwrap().len();
+        let request = Request::post("http://proxy/test.Echo/Say")
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .body(Full::new(Bytes::from_static(b"\0\0\0\0\0")))
+            .unwrap();
+        let response = tokio::spawn(sender.send_request(request));
+        while stub.bodies.lock().unwrap().len() == calls {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        response
+    }
+
+    #[tokio::test]
+    async fn open_calls_finish_after_shutdown() {
+        let stub = stub_upstream(|_| (Duration::from_millis(300), grpc_response(0, b""))).await;
+        let server = start(&stub, 30).await;
+        let mut sender = connect(server.address).await;
+
+        let call = open_call(&mut sender, &stub).await;
+        server.shutdown.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(server.state.draining.load(Ordering::Relaxed));
+        assert_eq!(server.state.metrics.draining.get(), 1);
+
+        // The connection got a GOAWAY, and the listener is closed.
+        let request = Request::post("http://proxy/test.Echo/Say")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        assert!(sender.send_request(request).await.is_err());
+        assert!(TcpStream::connect(server.address).await.is_err());
+
+        let response = call.await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["grpc-status"], "0");
+        tokio::time::timeout(Duration::from_secs(5), server.serving)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stub.bodies.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn drain_timeout_closes_remaining_calls() {
+        let stub = stub_upstream(|_| (Duration::from_secs(60), grpc_response(0, b""))).await;
+        let server = start(&stub, 1).await;
+        let mut sender = connect(server.address).await;
+
+        let call = open_call(&mut sender, &stub).await;
+        server.shutdown.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server.serving)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let result = tokio::time::timeout(Duration::from_secs(5), call)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(result.is_err(), "call outlived the drain timeout");
     }
 }
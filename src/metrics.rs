@@ -1,25 +1,43 @@
-use std::net::SocketAddr;
 use std::sync::Arc;
 
 use bytes::Bytes;
-use http::Response;
+use http::{Request, Response};
 use http_body_util::Full;
+use hyper::body::Incoming;
 use hyper::service::service_fn;
 use hyper_util::rt::TokioIo;
 use prometheus::{
-    Encoder, Gauge, Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts, Registry,
-    TextEncoder,
+    Encoder, Gauge, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+    Opts, Registry, TextEncoder,
 };
 
 use crate::error::ProxyError;
+use crate::health;
+use crate::net::Listener;
+use crate::proxy::AppState;
 
 pub struct MetricsState {
     pub registry: Registry,
     pub requests_total: IntCounterVec,
     pub request_duration_seconds: Histogram,
     pub auth_failures_total: IntCounterVec,
+    pub auth_cache_hits_total: IntCounter,
+    pub auth_cache_misses_total: IntCounter,
+    pub auth_queue_wait_seconds: Histogram,
+    pub auth_pool_rejected_total: IntCounterVec,
     pub upstream_errors_total: IntCounter,
+    pub upstream_retries_total: IntCounterVec,
+    pub rate_limited_total: IntCounterVec,
+    pub concurrency_limited_total: IntCounterVec,
+    pub in_flight_calls: IntGaugeVec,
+    pub upstream_endpoints: IntGaugeVec,
+    pub upstream_endpoint_healthy: IntGaugeVec,
+    pub upstream_circuit_state: IntGaugeVec,
+    pub upstream_circuit_transitions_total: IntCounterVec,
     pub active_connections: Gauge,
+    pub draining: IntGauge,
+    pub config_reloads_total: IntCounterVec,
+    pub config_info: IntGaugeVec,
 }
 
 impl MetricsState {
@@ -53,18 +71,139 @@ impl MetricsState {
         )
         .map_err(|e| ProxyError::ConfigLoad(format!("auth_failures metric: {e}")))?;
 
+        let auth_cache_hits_total = IntCounter::with_opts(Opts::new(
+            "auth_cache_hits_total",
+            "Credential verifications answered from the cache",
+        ))
+        .map_err(|e| ProxyError::ConfigLoad(format!("auth_cache_hits metric: {e}")))?;
+
+        let auth_cache_misses_total = IntCounter::with_opts(Opts::new(
+            "auth_cache_misses_total",
+            "Credential verifications that required argon2",
+        ))
+        .map_err(|e| ProxyError::ConfigLoad(format!("auth_cache_misses metric: {e}")))?;
+
+        let auth_queue_wait_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "auth_queue_wait_seconds",
+                "Time credential verifications waited for an auth pool worker",
+            )
+            .buckets(vec![
+                0.0005, 0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+            ]),
+        )
+        .map_err(|e| ProxyError::ConfigLoad(format!("auth_queue_wait metric: {e}")))?;
+
+        let auth_pool_rejected_total = IntCounterVec::new(
+            Opts::new(
+                "auth_pool_rejected_total",
+                "Credential verifications the auth pool could not take",
+            ),
+            &["reason"],
+        )
+        .map_err(|e| ProxyError::ConfigLoad(format!("auth_pool_rejected metric: {e}")))?;
+
+        let upstream_retries_total = IntCounterVec::new(
+            Opts::new(
+                "upstream_retries_total",
+                "Extra attempts sent by retry policies",
+            ),
+            &["upstream", "kind"],
+        )
+        .map_err(|e| ProxyError::ConfigLoad(format!("upstream_retries_total metric: {e}")))?;
+
+        let rate_limited_total = IntCounterVec::new(
+            Opts::new("rate_limited_total", "Calls rejected by a rate limit"),
+            &["user", "grpc_service", "grpc_method", "limit"],
+        )
+        .map_err(|e| ProxyError::ConfigLoad(format!("rate_limited_total metric: {e}")))?;
+
+        let concurrency_limited_total = IntCounterVec::new(
+            Opts::new(
+                "concurrency_limited_total",
+                "Calls rejected by a concurrent call limit",
+            ),
+            &["user", "grpc_service", "grpc_method", "limit"],
+        )
+        .map_err(|e| ProxyError::ConfigLoad(format!("concurrency_limited_total metric: {e}")))?;
+
+        let in_flight_calls = IntGaugeVec::new(
+            Opts::new("in_flight_calls", "Calls currently open, per user"),
+            &["user"],
+        )
+        .map_err(|e| ProxyError::ConfigLoad(format!("in_flight_calls metric: {e}")))?;
+
         let upstream_errors_total = IntCounter::with_opts(Opts::new(
             "upstream_errors_total",
             "Upstream connection/request errors",
         ))
         .map_err(|e| ProxyError::ConfigLoad(format!("upstream_errors metric: {e}")))?;
 
+        let upstream_endpoints = IntGaugeVec::new(
+            Opts::new(
+                "upstream_endpoints",
+                "Endpoints currently known per upstream",
+            ),
+            &["upstream"],
+        )
+        .map_err(|e| ProxyError::ConfigLoad(format!("upstream_endpoints metric: {e}")))?;
+
+        let upstream_endpoint_healthy = IntGaugeVec::new(
+            Opts::new(
+                "upstream_endpoint_healthy",
+                "1 while an upstream endpoint is in rotation, 0 while ejected",
+            ),
+            &["upstream", "endpoint"],
+        )
+        .map_err(|e| ProxyError::ConfigLoad(format!("upstream_endpoint_healthy metric: {e}")))?;
+
+        let upstream_circuit_state = IntGaugeVec::new(
+            Opts::new(
+                "upstream_circuit_state",
+                "Circuit breaker state per endpoint: 0 closed, 1 open, 2 half-open",
+            ),
+            &["upstream", "endpoint"],
+        )
+        .map_err(|e| ProxyError::ConfigLoad(format!("upstream_circuit_state metric: {e}")))?;
+
+        let upstream_circuit_transitions_total = IntCounterVec::new(
+            Opts::new(
+                "upstream_circuit_transitions_total",
+                "Circuit breaker state changes per endpoint",
+            ),
+            &["upstream", "endpoint", "state"],
+        )
+        .map_err(|e| {
+            ProxyError::ConfigLoad(format!("upstream_circuit_transitions_total metric: {e}"))
+        })?;
+
         let active_connections = Gauge::with_opts(Opts::new(
             "active_connections",
             "Currently active gRPC connections",
         ))
         .map_err(|e| ProxyError::ConfigLoad(format!("active_connections metric: {e}")))?;
 
+        let draining = IntGauge::with_opts(Opts::new(
+            "draining",
+            "1 while shutting down and waiting for open streams to finish",
+        ))
+        .map_err(|e| ProxyError::ConfigLoad(format!("draining metric: {e}")))?;
+
+        let config_reloads_total = IntCounterVec::new(
+            Opts::new(
+                "config_reloads_total",
+                "Config and credentials reload attempts",
+            ),
+            &["result"],
+        )
+        .map_err(|e| ProxyError::ConfigLoad(format!("config_reloads metric: {e}")))?;
+
+        let config_info = IntGaugeVec::new(
+            Opts::new("config_info", "Hash of the active config file"),
+            &["hash"],
+        )
+        .map_err(|e| ProxyError::ConfigLoad(format!("config_info metric: {e}")))?;
+
         registry
             .register(Box::new(requests_total.clone()))
             .map_err(|e| ProxyError::ConfigLoad(format!("register requests_total: {e}")))?;
@@ -76,30 +215,103 @@ impl MetricsState {
         registry
             .register(Box::new(auth_failures_total.clone()))
             .map_err(|e| ProxyError::ConfigLoad(format!("register auth_failures_total: {e}")))?;
+        registry
+            .register(Box::new(auth_cache_hits_total.clone()))
+            .map_err(|e| ProxyError::ConfigLoad(format!("register auth_cache_hits_total: {e}")))?;
+        registry
+            .register(Box::new(auth_cache_misses_total.clone()))
+            .map_err(|e| {
+                ProxyError::ConfigLoad(format!("register auth_cache_misses_total: {e}"))
+            })?;
+        registry
+            .register(Box::new(auth_queue_wait_seconds.clone()))
+            .map_err(|e| {
+                ProxyError::ConfigLoad(format!("register auth_queue_wait_seconds: {e}"))
+            })?;
+        registry
+            .register(Box::new(auth_pool_rejected_total.clone()))
+            .map_err(|e| {
+                ProxyError::ConfigLoad(format!("register auth_pool_rejected_total: {e}"))
+            })?;
         registry
             .register(Box::new(upstream_errors_total.clone()))
             .map_err(|e| ProxyError::ConfigLoad(format!("register upstream_errors_total: {e}")))?;
+        registry
+            .register(Box::new(upstream_retries_total.clone()))
+            .map_err(|e| ProxyError::ConfigLoad(format!("register upstream_retries_total: {e}")))?;
+        registry
+            .register(Box::new(rate_limited_total.clone()))
+            .map_err(|e| ProxyError::ConfigLoad(format!("register rate_limited_total: {e}")))?;
+        registry
+            .register(Box::new(concurrency_limited_total.clone()))
+            .map_err(|e| {
+                ProxyError::ConfigLoad(format!("register concurrency_limited_total: {e}"))
+            })?;
+        registry
+            .register(Box::new(in_flight_calls.clone()))
+            .map_err(|e| ProxyError::ConfigLoad(format!("register in_flight_calls: {e}")))?;
+        registry
+            .register(Box::new(upstream_endpoints.clone()))
+            .map_err(|e| ProxyError::ConfigLoad(format!("register upstream_endpoints: {e}")))?;
+        registry
+            .register(Box::new(upstream_endpoint_healthy.clone()))
+            .map_err(|e| {
+                ProxyError::ConfigLoad(format!("register upstream_endpoint_healthy: {e}"))
+            })?;
+        registry
+            .register(Box::new(upstream_circuit_state.clone()))
+            .map_err(|e| ProxyError::ConfigLoad(format!("register upstream_circuit_state: {e}")))?;
+        registry
+            .register(Box::new(upstream_circuit_transitions_total.clone()))
+            .map_err(|e| {
+                ProxyError::ConfigLoad(format!("register upstream_circuit_transitions_total: {e}"))
+            })?;
         registry
             .register(Box::new(active_connections.clone()))
             .map_err(|e| ProxyError::ConfigLoad(format!("register active_connections: {e}")))?;
+        registry
+            .register(Box::new(draining.clone()))
+            .map_err(|e| ProxyError::ConfigLoad(format!("register draining: {e}")))?;
+        registry
+            .register(Box::new(config_reloads_total.clone()))
+            .map_err(|e| ProxyError::ConfigLoad(format!("register config_reloads_total: {e}")))?;
+        registry
+            .register(Box::new(config_info.clone()))
+            .map_err(|e| ProxyError::ConfigLoad(format!("register config_info: {e}")))?;
 
         Ok(Self {
             registry,
             requests_total,
             request_duration_seconds,
             auth_failures_total,
+            auth_cache_hits_total,
+            auth_cache_misses_total,
+            auth_queue_wait_seconds,
+            auth_pool_rejected_total,
             upstream_errors_total,
+            upstream_retries_total,
+            rate_limited_total,
+            concurrency_limited_total,
+            in_flight_calls,
+            upstream_endpoints,
+            upstream_endpoint_healthy,
+            upstream_circuit_state,
+            upstream_circuit_transitions_total,
             active_connections,
+            draining,
+            config_reloads_total,
+            config_info,
         })
     }
-}
 
-pub async fn serve_metrics(registry: Arc<Registry>, addr: SocketAddr) -> Result<(), ProxyError> {
-    let listener = tokio::net::TcpListener::bind(addr)
-        .await
-        .map_err(|e| ProxyError::ServerBind(format!("metrics {addr}: {e}")))?;
+    pub fn set_config_hash(&self, hash: &str) {
+        self.config_info.reset();
+        self.config_info.with_label_values(&[hash]).set(1);
+    }
+}
 
-    tracing::info!("metrics server listening on {addr}");
+pub async fn serve_metrics(state: Arc<AppState>, listener: Listener) {
+    tracing::info!("metrics server listening on {}", listener.local_addr());
 
     loop {
         let (stream, _) = match listener.accept().await {
@@ -110,31 +322,20 @@ pub async fn serve_metrics(registry: Arc<Registry>, addr: SocketAddr) -> Result<
             }
         };
 
-        let registry = Arc::clone(&registry);
+        let state = Arc::clone(&state);
         tokio::spawn(async move {
-            let service = service_fn(move |_req| {
-                let registry = Arc::clone(&registry);
+            let service = service_fn(move |req: Request<Incoming>| {
+                let state = Arc::clone(&state);
                 async move {
-                    let encoder = TextEncoder::new();
-                    let metric_families = registry.gather();
-                    let mut buffer = Vec::new();
-                    if let Err(e) = encoder.encode(&metric_families, &mut buffer) {
-                        return Ok::<_, hyper::Error>(
-                            Response::builder()
-                                .status(500)
-                                .body(Full::new(Bytes::from(format!("encoding error: {e}"))))
-                                .unwrap_or_else(|_| {
-                                    Response::new(Full::new(Bytes::from("internal error")))
-                                }),
-                        );
-                    }
-                    Ok(Response::builder()
-                        .status(200)
-                        .header("content-type", encoder.format_type())
-                        .body(Full::new(Bytes::from(buffer)))
-                        .unwrap_or_else(|_| {
-                            Response::new(Full::new(Bytes::from("internal error")))
-                        }))
+                    let response = match req.uri().path() {
+                        "/healthz" => text_response(200, "ok"),
+                        "/readyz" => match health::serving_status(&state, "") {
+                            Some(health::SERVING) => text_response(200, "ready"),
+                            _ => text_response(503, "not ready"),
+                        },
+                        _ => encode_metrics(&state.metrics.registry),
+                    };
+                    Ok::<_, hyper::Error>(response)
                 }
             });
 
@@ -147,3 +348,24 @@ pub async fn serve_metrics(registry: Arc<Registry>, addr: SocketAddr) -> Result<
         });
     }
 }
+
+fn encode_metrics(registry: &Registry) -> Response<Full<Bytes>> {
+    let encoder = TextEncoder::new();
+    let metric_families = registry.gather();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&metric_families, &mut buffer) {
+        return text_response(500, format!("encoding error: {e}"));
+    }
+    Response::builder()
+        .status(200)
+        .header("content-type", encoder.format_type())
+        .body(Full::new(Bytes::from(buffer)))
+        .unwrap_or_else(|_| Response::new(Full::new(Bytes::from("internal error"))))
+}
+
+fn text_response(status: u16, body: impl Into<Bytes>) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .body(Full::new(body.into()))
+        .unwrap_or_else(|_| Response::new(Full::new(Bytes::from("internal error"))))
+}
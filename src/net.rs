@@ -0,0 +1,606 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{DirBuilder, Permissions};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use serde::Deserialize;
+use socket2::{Domain, SockRef, Type};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+use crate::config::UnixSocketConfig;
+
+/// A listen address: `host:port`, `unix:/path` for a Unix domain socket, or
+/// `systemd:name` for sockets passed in by systemd socket activation.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    Systemd(String),
+}
+
+impl ListenAddress {
+    pub fn is_unix(&self) -> bool {
+        matches!(self, Self::Unix(_))
+    }
+}
+
+impl TryFrom<String> for ListenAddress {
+    type Error = String;
+
+    fn try_from(address: String) -> Result<Self, Self::Error> {
+        if let Some(path) = address.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("unix: address needs a socket path".to_owned());
+            }
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+        if let Some(name) = address.strip_prefix("systemd:") {
+            if name.is_empty() {
+                return Err("systemd: address needs a socket name".to_owned());
+            }
+            return Ok(Self::Systemd(name.to_owned()));
+        }
+        address
+            .parse()
+            .map(Self::Tcp)
+            .map_err(|e| format!("invalid address '{address}': {e}"))
+    }
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+            Self::Systemd(name) => write!(f, "systemd:{name}"),
+        }
+    }
+}
+
+/// Listening sockets passed in by systemd socket activation, each named by
+/// the socket unit's `FileDescriptorName=`.
+pub struct InheritedSockets {
+    sockets: Vec<(String, OwnedFd)>,
+}
+
+impl InheritedSockets {
+    /// Takes the sockets announced by `LISTEN_PID`, `LISTEN_FDS` and
+    /// `LISTEN_FDNAMES`. There are none if they are unset or meant for
+    /// another process. The variables are cleared either way, so child
+    /// processes do not take the sockets to be theirs.
+    ///
+    /// # Safety
+    ///
+    /// Must be called before the process starts any other thread, as it
+    /// modifies the environment.
+    pub unsafe fn from_env() -> Self {
+        const LISTEN_FDS_START: RawFd = 3;
+        const VARS: [&str; 3] = ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"];
+
+        let for_us = std::env::var("LISTEN_PID")
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok())
+            == Some(std::process::id());
+        let count = std::env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|count| count.parse::<RawFd>().ok())
+            .filter(|_| for_us)
+            .unwrap_or(0);
+        let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+        for var in VARS {
+            // SAFETY: the caller guarantees no other thread reads or writes
+            // the environment.
+            unsafe { std::env::remove_var(var) };
+        }
+        let mut names = names.split(':');
+
+        let sockets = (0..count)
+            .map(|index| {
+                let name = names.next().filter(|name| !name.is_empty());
+                // SAFETY: systemd hands these descriptors to this process
+                // alone, and nothing else in it takes ownership of them.
+                let fd = unsafe { OwnedFd::from_raw_fd(LISTEN_FDS_START + index) };
+                (name.unwrap_or("unknown").to_owned(), fd)
+            })
+            .collect();
+        Self { sockets }
+    }
+
+    /// Removes every socket named `name`. A socket unit with several
+    /// `ListenStream=` lines passes one socket per line under the same name.
+    fn take(&mut self, name: &str) -> Vec<OwnedFd> {
+        let (taken, rest) = std::mem::take(&mut self.sockets)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(socket_name, _)| socket_name == name);
+        self.sockets = rest;
+        taken.into_iter().map(|(_, fd)| fd).collect()
+    }
+
+    /// Names of the sockets no listener has taken.
+    pub fn unused(&self) -> impl Iterator<Item = &str> {
+        self.sockets.iter().map(|(name, _)| name.as_str())
+    }
+}
+
+/// Where a connection or call came from. Unix socket peers are unnamed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    Unix,
+    /// A client named by a trusted proxy's `x-forwarded-for`/`x-real-ip`.
+    Forwarded(IpAddr),
+}
+
+impl PeerAddr {
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Self::Tcp(addr) => Some(addr.ip().to_canonical()),
+            Self::Unix => None,
+            Self::Forwarded(ip) => Some(*ip),
+        }
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix => f.write_str("unix"),
+            Self::Forwarded(ip) => write!(f, "{ip}"),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    /// A socket file created by the proxy is removed when the listener is
+    /// dropped. Inherited sockets have no path and are left alone.
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+impl Listener {
+    /// Binds `address`, or takes its sockets from `inherited` for a
+    /// `systemd:` address. A Unix socket left behind by a previous run is
+    /// replaced unless something still accepts connections on it, and
+    /// `socket` sets the new file's mode and ownership.
+    pub async fn bind(
+        address: &ListenAddress,
+        socket: &UnixSocketConfig,
+        inherited: &mut InheritedSockets,
+    ) -> io::Result<Vec<Self>> {
+        match address {
+            ListenAddress::Tcp(addr) => Ok(vec![Self::Tcp(TcpListener::bind(addr).await?)]),
+            ListenAddress::Unix(path) => Ok(vec![Self::bind_unix(path, socket)?]),
+            ListenAddress::Systemd(name) => {
+                let fds = inherited.take(name);
+                if fds.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "no socket with this name was passed in by systemd",
+                    ));
+                }
+                fds.into_iter().map(Self::from_fd).collect()
+            }
+        }
+    }
+
+    fn bind_unix(path: &PathBuf, socket: &UnixSocketConfig) -> io::Result<Self> {
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => {
+                // A stale socket refuses connections; a live one belongs to
+                // another instance, which would be cut off from new clients.
+                if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        "another process is serving this socket",
+                    ));
+                }
+                std::fs::remove_file(path)?;
+            }
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "path exists and is not a socket",
+                ));
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        if *socket == UnixSocketConfig::default() {
+            return Ok(Self::Unix(UnixListener::bind(path)?, Some(path.clone())));
+        }
+
+        // Bound in a private directory and moved into place once its mode and
+        // ownership are set, so nobody can connect in between. The directory
+        // sits next to the socket, for the rename, and keeps the name short,
+        // for the socket path length limit.
+        let name = path.file_name().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "socket path has no file name")
+        })?;
+        let mut staging_name = OsString::from(".");
+        staging_name.push(name);
+        staging_name.push(".tmp");
+        let staging = path.with_file_name(staging_name);
+        let staged = staging.join("s");
+        // Left behind if a previous run died while binding.
+        let _ = std::fs::remove_file(&staged);
+        let _ = std::fs::remove_dir(&staging);
+
+        DirBuilder::new().mode(0o700).create(&staging)?;
+        let listener = UnixListener::bind(&staged).and_then(|listener| {
+            let moved =
+                Self::prepare_unix(&staged, socket).and_then(|()| std::fs::rename(&staged, path));
+            moved.map(|()| listener)
+        });
+        if listener.is_err() {
+            let _ = std::fs::remove_file(&staged);
+        }
+        let _ = std::fs::remove_dir(&staging);
+        Ok(Self::Unix(listener?, Some(path.clone())))
+    }
+
+    fn prepare_unix(path: &Path, socket: &UnixSocketConfig) -> io::Result<()> {
+        if let Some(mode) = socket.mode {
+            std::fs::set_permissions(path, Permissions::from_mode(mode))?;
+        }
+        if socket.owner.is_some() || socket.group.is_some() {
+            std::os::unix::fs::chown(path, socket.owner, socket.group)?;
+        }
+        Ok(())
+    }
+
+    /// Wraps an inherited socket, which must be a listening TCP or Unix
+    /// stream socket: a datagram socket or a socket unit with `Accept=yes`
+    /// would otherwise fail on the first accept.
+    fn from_fd(fd: OwnedFd) -> io::Result<Self> {
+        let socket = SockRef::from(&fd);
+        if socket.r#type()? != Type::STREAM || !socket.is_listener()? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "inherited socket is not a listening stream socket",
+            ));
+        }
+        match socket.domain()? {
+            Domain::IPV4 | Domain::IPV6 => {
+                let tcp = std::net::TcpListener::from(fd);
+                tcp.set_nonblocking(true)?;
+                TcpListener::from_std(tcp).map(Self::Tcp)
+            }
+            Domain::UNIX => {
+                let unix = std::os::unix::net::UnixListener::from(fd);
+                unix.set_nonblocking(true)?;
+                Ok(Self::Unix(UnixListener::from_std(unix)?, None))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "inherited socket is neither TCP nor Unix",
+            )),
+        }
+    }
+
+    /// The address the listener is bound to, for logging.
+    pub fn local_addr(&self) -> String {
+        let addr = match self {
+            Self::Tcp(listener) => listener.local_addr().map(|addr| addr.to_string()),
+            // A socket bound in a staging directory reports that path.
+            Self::Unix(_, Some(path)) => Ok(format!("unix:{}", path.display())),
+            Self::Unix(listener, None) => {
+                listener.local_addr().map(|addr| match addr.as_pathname() {
+                    Some(path) => format!("unix:{}", path.display()),
+                    None => "unix".to_owned(),
+                })
+            }
+        };
+        addr.unwrap_or_else(|e| format!("unknown ({e})"))
+    }
+
+    pub async fn accept(&self) -> io::Result<(Stream, PeerAddr)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), PeerAddr::Tcp(addr)))
+            }
+            Self::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Stream::Unix(stream), PeerAddr::Unix))
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Self::Unix(_, Some(path)) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Connects to an upstream address: `host:port`, or `unix:/path`.
+pub async fn connect(address: &str) -> io::Result<Stream> {
+    match address.strip_prefix("unix:") {
+        Some(path) => UnixStream::connect(path).await.map(Stream::Unix),
+        None => {
+            let tcp = TcpStream::connect(address).await?;
+            tcp.set_nodelay(true)?;
+            Ok(Stream::Tcp(tcp))
+        }
+    }
+}
+
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Self::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Self::Tcp(stream) => stream.is_write_vectored(),
+            Self::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn socket_path() -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        std::env::temp_dir().join(format!(
+            "grpc-proxier-net-{}-{}.sock",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ))
+    }
+
+    fn inherited(names: &[&str]) -> InheritedSockets {
+        let sockets = names
+            .iter()
+            .map(|name| {
+                let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+                ((*name).to_owned(), OwnedFd::from(listener))
+            })
+            .collect();
+        InheritedSockets { sockets }
+    }
+
+    #[test]
+    fn parses_listen_addresses() {
+        let parse = |address: &str| ListenAddress::try_from(address.to_owned());
+        assert_eq!(
+            parse("127.0.0.1:8443"),
+            Ok(ListenAddress::Tcp("127.0.0.1:8443".parse().unwrap()))
+        );
+        assert_eq!(
+            parse("[::1]:8443"),
+            Ok(ListenAddress::Tcp("[::1]:8443".parse().unwrap()))
+        );
+        assert_eq!(
+            parse("unix:/run/grpc.sock"),
+            Ok(ListenAddress::Unix(PathBuf::from("/run/grpc.sock")))
+        );
+        assert_eq!(
+            parse("systemd:grpc"),
+            Ok(ListenAddress::Systemd("grpc".to_owned()))
+        );
+
+        assert!(parse("unix:").is_err());
+        assert!(parse("systemd:").is_err());
+        assert!(parse("localhost:8443").is_err());
+        assert!(parse("127.0.0.1").is_err());
+    }
+
+    #[test]
+    fn listen_addresses_display_as_parsed() {
+        for address in [
+            "127.0.0.1:8443",
+            "[::1]:8443",
+            "unix:/run/grpc.sock",
+            "systemd:grpc",
+        ] {
+            let parsed = ListenAddress::try_from(address.to_owned()).unwrap();
+            assert_eq!(parsed.to_string(), address);
+        }
+    }
+
+    #[test]
+    fn takes_every_inherited_socket_with_a_name() {
+        let mut inherited = inherited(&["grpc", "metrics", "grpc", "unknown"]);
+
+        assert_eq!(inherited.take("grpc").len(), 2);
+        assert!(inherited.take("grpc").is_empty());
+        assert!(inherited.take("missing").is_empty());
+        assert_eq!(
+            inherited.unused().collect::<Vec<_>>(),
+            ["metrics", "unknown"]
+        );
+        assert_eq!(inherited.take("metrics").len(), 1);
+        assert_eq!(inherited.unused().collect::<Vec<_>>(), ["unknown"]);
+    }
+
+    #[tokio::test]
+    async fn wraps_inherited_listeners() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let listener = Listener::from_fd(OwnedFd::from(tcp)).unwrap();
+        assert!(matches!(listener, Listener::Tcp(_)));
+        assert_eq!(listener.local_addr(), addr.to_string());
+
+        let path = socket_path();
+        let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let listener = Listener::from_fd(OwnedFd::from(unix)).unwrap();
+        assert!(matches!(listener, Listener::Unix(_, None)));
+        assert_eq!(listener.local_addr(), format!("unix:{}", path.display()));
+        // Inherited sockets are not the proxy's to remove.
+        drop(listener);
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_inherited_sockets_that_do_not_listen() {
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let Err(e) = Listener::from_fd(OwnedFd::from(udp)) else {
+            panic!("accepted a datagram socket");
+        };
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+        let server = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let connected = std::net::TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        let Err(e) = Listener::from_fd(OwnedFd::from(connected)) else {
+            panic!("accepted a connected socket");
+        };
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn replaces_a_stale_unix_socket() {
+        let path = socket_path();
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let listener = Listener::bind_unix(&path, &UnixSocketConfig::default()).unwrap();
+        UnixStream::connect(&path).await.unwrap();
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn refuses_a_unix_socket_in_use() {
+        let path = socket_path();
+        let serving = std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+        let Err(e) = Listener::bind_unix(&path, &UnixSocketConfig::default()) else {
+            panic!("bound a socket another listener is serving");
+        };
+        assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+        assert!(path.exists());
+        drop(serving);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn refuses_to_replace_other_files() {
+        let path = socket_path();
+        std::fs::write(&path, "").unwrap();
+
+        let Err(e) = Listener::bind_unix(&path, &UnixSocketConfig::default()) else {
+            panic!("replaced a regular file");
+        };
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn staging_dir(path: &Path) -> PathBuf {
+        let name = path.file_name().unwrap().to_str().unwrap();
+        path.with_file_name(format!(".{name}.tmp"))
+    }
+
+    #[tokio::test]
+    async fn sets_the_mode_before_moving_the_socket_into_place() {
+        let path = socket_path();
+        // Left behind by a run that died while binding.
+        let staging = staging_dir(&path);
+        std::fs::create_dir(&staging).unwrap();
+        std::fs::write(staging.join("s"), "").unwrap();
+
+        let socket = UnixSocketConfig {
+            mode: Some(0o660),
+            ..UnixSocketConfig::default()
+        };
+        let listener = Listener::bind_unix(&path, &socket).unwrap();
+        let metadata = std::fs::symlink_metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o660);
+        assert!(!staging.exists());
+        assert_eq!(listener.local_addr(), format!("unix:{}", path.display()));
+
+        let _client = UnixStream::connect(&path).await.unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer, PeerAddr::Unix);
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn failed_staged_bind_leaves_nothing_behind() {
+        // Short enough to bind in place, but too long for the socket path
+        // limit once inside the staging directory.
+        let path = socket_path();
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let padding = 104 - path.as_os_str().len();
+        let path = path.with_file_name(format!("{}{name}", "x".repeat(padding)));
+        drop(Listener::bind_unix(&path, &UnixSocketConfig::default()).unwrap());
+        assert!(!path.exists());
+
+        let socket = UnixSocketConfig {
+            mode: Some(0o660),
+            ..UnixSocketConfig::default()
+        };
+        assert!(Listener::bind_unix(&path, &socket).is_err());
+        assert!(!path.exists());
+        assert!(!staging_dir(&path).exists());
+    }
+}
@@ -0,0 +1,171 @@
+use std::fmt;
+
+use regex::Regex;
+use serde::Deserialize;
+
+/// A compiled `allowed_calls` entry, matched against `package.Service/Method`.
+///
+/// - `*` matches every call
+/// - entries without wildcards match exactly
+/// - `*` and `?` inside an entry are globs (`*` may span `.` and `/`), e.g.
+///   `mypackage.MyService/*`, `mypackage.*` or `*/Get*`
+/// - `regex:<expr>` matches the whole call against a regular expression
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct CallPattern {
+    source: String,
+    matcher: Matcher,
+}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    Any,
+    Exact,
+    Regex(Regex),
+}
+
+impl CallPattern {
+    pub fn matches(&self, call: &str) -> bool {
+        match &self.matcher {
+            Matcher::Any => true,
+            Matcher::Exact => self.source == call,
+            Matcher::Regex(regex) => regex.is_match(call),
+        }
+    }
+}
+
+impl TryFrom<String> for CallPattern {
+    type Error = String;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        let matcher = if source == "*" {
+            Matcher::Any
+        } else if let Some(expr) = source.strip_prefix("regex:") {
+            let regex = Regex::new(&format!("^(?:{expr})$"))
+                .map_err(|e| format!("invalid pattern '{source}': {e}"))?;
+            Matcher::Regex(regex)
+        } else if source.contains(['*', '?']) {
+            Matcher::Regex(glob_to_regex(&source)?)
+        } else {
+            Matcher::Exact
+        };
+
+        Ok(Self { source, matcher })
+    }
+}
+
+impl fmt::Display for CallPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+fn glob_to_regex(glob: &str) -> Result<Regex, String> {
+    let mut expr = String::with_capacity(glob.len() + 8);
+    expr.push('^');
+    for c in glob.chars() {
+        match c {
+            '*' => expr.push_str(".*"),
+            '?' => expr.push('.'),
+            c => expr.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    expr.push('$');
+
+    Regex::new(&expr).map_err(|e| format!("invalid pattern '{glob}': {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(source: &str) -> CallPattern {
+        CallPattern::try_from(source.to_owned()).unwrap()
+    }
+
+    #[test]
+    fn star_matches_everything() {
+        let any = pattern("*");
+        assert!(any.matches("pkg.Svc/Method"));
+        assert!(any.matches(""));
+    }
+
+    #[test]
+    fn exact_pattern() {
+        let exact = pattern("pkg.Svc/Get");
+        assert!(exact.matches("pkg.Svc/Get"));
+        assert!(!exact.matches("pkg.Svc/GetAll"));
+        assert!(!exact.matches("other.pkg.Svc/Get"));
+    }
+
+    #[test]
+    fn service_glob() {
+        let service = pattern("pkg.Svc/*");
+        assert!(service.matches("pkg.Svc/Get"));
+        assert!(service.matches("pkg.Svc/"));
+        assert!(!service.matches("pkg.SvcX/Get"));
+        assert!(!service.matches("other.pkg.Svc/Get"));
+    }
+
+    #[test]
+    fn package_glob_spans_dots_and_slashes() {
+        let package = pattern("pkg.*");
+        assert!(package.matches("pkg.Svc/Get"));
+        assert!(package.matches("pkg.sub.Svc/Get"));
+        assert!(!package.matches("pkgx.Svc/Get"));
+        assert!(!package.matches("other.pkg.Svc/Get"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_character() {
+        let single = pattern("pkg.Svc/Get?");
+        assert!(single.matches("pkg.Svc/GetA"));
+        assert!(!single.matches("pkg.Svc/Get"));
+        assert!(!single.matches("pkg.Svc/GetAB"));
+    }
+
+    #[test]
+    fn literal_metacharacters_in_globs() {
+        // `.` is literal, not "any character".
+        let glob = pattern("pkg.Svc/Get*");
+        assert!(glob.matches("pkg.Svc/GetItem"));
+        assert!(!glob.matches("pkgXSvc/GetItem"));
+
+        let brackets = pattern("pkg.Svc/(Get)+*");
+        assert!(brackets.matches("pkg.Svc/(Get)+x"));
+        assert!(!brackets.matches("pkg.Svc/GetGet"));
+    }
+
+    #[test]
+    fn globs_are_anchored() {
+        let glob = pattern("*/Get*");
+        assert!(glob.matches("pkg.Svc/GetItem"));
+        assert!(!glob.matches("pkg.Svc/ForGet"));
+    }
+
+    #[test]
+    fn regex_is_anchored() {
+        let regex = pattern(r"regex:pkg\.(Foo|Bar)/List.*");
+        assert!(regex.matches("pkg.Foo/ListItems"));
+        assert!(regex.matches("pkg.Bar/List"));
+        assert!(!regex.matches("pkg.Baz/List"));
+        assert!(!regex.matches("xpkg.Foo/List"));
+
+        // The whole call must match, even with alternation at the top level.
+        let alternation = pattern("regex:pkg.Foo/A|pkg.Foo/B");
+        assert!(alternation.matches("pkg.Foo/B"));
+        assert!(!alternation.matches("pkg.Foo/Bx"));
+        assert!(!alternation.matches("xpkg.Foo/A"));
+    }
+
+    #[test]
+    fn invalid_regex_is_rejected() {
+        let error = CallPattern::try_from("regex:((".to_owned()).unwrap_err();
+        assert!(error.contains("invalid pattern 'regex:(('"), "{error}");
+    }
+
+    #[test]
+    fn displays_source() {
+        assert_eq!(pattern("pkg.*").to_string(), "pkg.*");
+    }
+}
@@ -1,35 +1,154 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
 use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::task::{Context, Poll};
 use std::time::Instant;
 
+use arc_swap::ArcSwap;
 use bytes::Bytes;
-use http::{Request, Response, Uri};
-use http_body_util::{Either, Full};
-use hyper::body::Incoming;
-use hyper_util::client::legacy::Client;
+use http::{HeaderMap, Request, Response};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Either};
+use hyper::body::{Body, Frame, Incoming, SizeHint};
+use tokio::time::Sleep;
 
 use crate::auth;
-use crate::config::{Config, Credentials};
+use crate::auth_cache::VerifyCache;
+use crate::auth_pool::AuthPool;
+use crate::cidr::TrustedProxy;
+use crate::cluster::{Cluster, UpstreamBody};
+use crate::concurrency::{CallPermit, ConcurrencyLimiter};
+use crate::config::{ClientIdentity, Config, Credentials, ListenerAuth};
+use crate::deadline;
 use crate::error::ProxyError;
+use crate::health;
+use crate::jwt::JwtValidator;
 use crate::metrics::MetricsState;
+use crate::net::PeerAddr;
+use crate::ratelimit::RateLimiter;
+use crate::retry;
+use crate::tls::ClientCertificate;
 
-type ProxyBody = Either<Incoming, Full<Bytes>>;
+/// Proxied responses, or ones produced locally (errors, health checks).
+type ProxyBody = Either<CallBody, BoxBody<Bytes, Infallible>>;
 
-pub struct AppState {
+/// Everything replaced on reload. A request loads the snapshot once, so it
+/// never sees a mix of old and new settings.
+pub struct Snapshot {
     pub config: Config,
     pub credentials: Credentials,
+    pub jwt: Option<Arc<JwtValidator>>,
+}
+
+pub struct AppState {
+    pub snapshot: ArcSwap<Snapshot>,
+    pub verify_cache: VerifyCache,
+    pub auth_pool: AuthPool,
+    pub rate_limiter: RateLimiter,
+    pub concurrency_limiter: ConcurrencyLimiter,
     pub skip_auth: bool,
     pub metrics: MetricsState,
-    pub upstream_client: Client<hyper_util::client::legacy::connect::HttpConnector, Incoming>,
+    pub upstreams: HashMap<String, Arc<Cluster>>,
+    /// Set once shutdown begins.
+    pub draining: AtomicBool,
+}
+
+/// A proxied response body. The call counts against its concurrency limits
+/// until the body is dropped, and is cut off with DEADLINE_EXCEEDED if it
+/// outlives its deadline.
+pub struct CallBody {
+    /// Dropped once the deadline passes, which resets the upstream stream.
+    inner: Option<UpstreamBody>,
+    deadline: Option<Pin<Box<Sleep>>>,
+    _permit: CallPermit,
+}
+
+impl Body for CallBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+        if this.inner.is_some()
+            && let Some(deadline) = &mut this.deadline
+            && deadline.as_mut().poll(cx).is_ready()
+        {
+            this.inner = None;
+            let trailers = ProxyError::DeadlineExceeded.to_grpc_trailers();
+            return Poll::Ready(Some(Ok(Frame::trailers(trailers))));
+        }
+
+        match &mut this.inner {
+            Some(inner) => Pin::new(inner).poll_frame(cx),
+            None => Poll::Ready(None),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.as_ref().is_none_or(Body::is_end_stream)
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner
+            .as_ref()
+            .map_or_else(SizeHint::default, Body::size_hint)
+    }
+}
+
+/// Per-connection details shared by every request on that connection.
+#[derive(Debug)]
+pub struct ConnectionInfo {
+    pub peer_addr: PeerAddr,
+    pub client_cert: Option<ClientCertificate>,
+    /// From the listener's TLS settings.
+    pub client_identity: ClientIdentity,
+    pub auth: ListenerAuth,
 }
 
 pub async fn handle_request(
     req: Request<Incoming>,
     state: Arc<AppState>,
-) -> Result<Response<ProxyBody>, std::convert::Infallible> {
+    conn: Arc<ConnectionInfo>,
+) -> Result<Response<ProxyBody>, Infallible> {
     let start = Instant::now();
     let path = req.uri().path().to_owned();
 
-    match handle_request_inner(req, &state, &path).await {
+    let snapshot = state.snapshot.load_full();
+    let client_addr = client_addr(
+        conn.peer_addr,
+        req.headers(),
+        &snapshot.config.trusted_proxies,
+    );
+
+    // Probes must work without credentials and without a healthy upstream,
+    // but not from networks shut out entirely.
+    if let Some(method) = path.strip_prefix(health::SERVICE_PREFIX) {
+        if let Err(e) = auth::authorize_address(client_addr, &snapshot.config) {
+            state
+                .metrics
+                .auth_failures_total
+                .with_label_values(&[e.auth_failure_reason()])
+                .inc();
+            tracing::warn!(client = %client_addr, "{e}");
+            return Ok(e.to_grpc_response().map(|body| Either::Right(body.boxed())));
+        }
+        let response = match health::serve(req, method, state).await {
+            Ok(response) => response.map(BodyExt::boxed),
+            Err(e) => {
+                tracing::debug!("health check: {e}");
+                e.to_grpc_response().map(BodyExt::boxed)
+            }
+        };
+        return Ok(response.map(Either::Right));
+    }
+
+    match handle_request_inner(req, &state, &snapshot, &conn, client_addr, &path, start).await {
         Ok((response, username)) => {
             let duration = start.elapsed().as_secs_f64();
             let (service, method) = parse_grpc_path(&path);
@@ -54,7 +173,10 @@ pub async fn handle_request(
             let (service, method) = parse_grpc_path(&path);
 
             match &proxy_err {
-                ProxyError::AuthMissing | ProxyError::AuthInvalid | ProxyError::AuthDenied(_) => {
+                ProxyError::AuthMissing
+                | ProxyError::AuthInvalid
+                | ProxyError::AuthDenied(_)
+                | ProxyError::AddressDenied(_) => {
                     state
                         .metrics
                         .auth_failures_total
@@ -71,8 +193,56 @@ pub async fn handle_request(
                         ])
                         .inc();
                 }
-                ProxyError::UpstreamConnect(_) | ProxyError::UpstreamRequest(_) => {
-                    state.metrics.upstream_errors_total.inc();
+                // The credentials were never checked, so this is neither an
+                // auth failure nor an unauthenticated call.
+                ProxyError::AuthOverloaded | ProxyError::AuthUnavailable => {
+                    state
+                        .metrics
+                        .auth_pool_rejected_total
+                        .with_label_values(&[proxy_err.auth_failure_reason()])
+                        .inc();
+                    state
+                        .metrics
+                        .requests_total
+                        .with_label_values(&[
+                            "_error",
+                            service,
+                            method,
+                            &proxy_err.grpc_status_code().to_string(),
+                        ])
+                        .inc();
+                }
+                ProxyError::RateLimited { user, limit }
+                | ProxyError::ConcurrencyLimited { user, limit } => {
+                    let rejections = match proxy_err {
+                        ProxyError::RateLimited { .. } => &state.metrics.rate_limited_total,
+                        _ => &state.metrics.concurrency_limited_total,
+                    };
+                    rejections
+                        .with_label_values(&[user.as_str(), service, method, limit])
+                        .inc();
+                    state
+                        .metrics
+                        .requests_total
+                        .with_label_values(&[
+                            user.as_str(),
+                            service,
+                            method,
+                            &proxy_err.grpc_status_code().to_string(),
+                        ])
+                        .inc();
+                }
+                ProxyError::UpstreamConnect(_)
+                | ProxyError::UpstreamRequest(_)
+                | ProxyError::DeadlineExceeded
+                | ProxyError::RequestBody(_)
+                | ProxyError::NoRoute(_) => {
+                    if !matches!(
+                        proxy_err,
+                        ProxyError::NoRoute(_) | ProxyError::RequestBody(_)
+                    ) {
+                        state.metrics.upstream_errors_total.inc();
+                    }
                     state
                         .metrics
                         .requests_total
@@ -87,53 +257,181 @@ pub async fn handle_request(
                 _ => {}
             }
 
-            tracing::warn!("{proxy_err}");
-            Ok(proxy_err.to_grpc_response().map(Either::Right))
+            tracing::warn!(client = %client_addr, "{proxy_err}");
+            Ok(proxy_err
+                .to_grpc_response()
+                .map(|body| Either::Right(body.boxed())))
         }
     }
 }
 
+/// The credential the caller sent, unless the listener only accepts client
+/// certificates.
+fn authorization_header(headers: &HeaderMap, auth: ListenerAuth) -> Option<&str> {
+    headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .filter(|_| auth != ListenerAuth::ClientCert)
+}
+
 async fn handle_request_inner(
     req: Request<Incoming>,
     state: &AppState,
+    snapshot: &Snapshot,
+    conn: &ConnectionInfo,
+    client_addr: PeerAddr,
     path: &str,
-) -> Result<(Response<Incoming>, String), ProxyError> {
-    let username = if state.skip_auth {
-        tracing::debug!(path = %path, "proxying request (auth skipped)");
+    start: Instant,
+) -> Result<(Response<CallBody>, String), ProxyError> {
+    let config = &snapshot.config;
+    auth::authorize_address(client_addr, config)?;
+
+    let username = if state.skip_auth || conn.auth == ListenerAuth::Disabled {
+        tracing::debug!(client = %client_addr, path = %path, "proxying request (auth skipped)");
         "Anonymous".to_owned()
     } else {
-        let auth_header = req
-            .headers()
-            .get("authorization")
-            .and_then(|v| v.to_str().ok())
-            .ok_or(ProxyError::AuthMissing)?;
+        let auth_header = authorization_header(req.headers(), conn.auth);
 
-        let username = auth::authenticate(auth_header, &state.credentials)?;
-        auth::authorize(&username, path, &state.config)?;
+        // An explicit authorization header takes precedence over the client
+        // certificate, so a caller can act as a different user if it has to.
+        let identity = match (auth_header, &conn.client_cert) {
+            (Some(auth_header), _) => {
+                auth::authenticate(
+                    auth_header,
+                    &snapshot.credentials,
+                    snapshot.jwt.as_deref(),
+                    &state.verify_cache,
+                    &state.auth_pool,
+                )
+                .await?
+            }
+            (None, Some(cert)) => {
+                auth::authenticate_client_cert(cert, conn.client_identity, config)?
+            }
+            _ => return Err(ProxyError::AuthMissing),
+        };
+        auth::authorize_user_address(&identity, client_addr, config)?;
+        auth::authorize(&identity, path, config)?;
 
-        tracing::debug!(user = %username, path = %path, "proxying request");
-        username
+        tracing::debug!(
+            client = %client_addr,
+            user = %identity.username,
+            path = %path,
+            "proxying request"
+        );
+        identity.username
     };
 
-    let upstream_uri: Uri = format!("http://{}{}", state.config.upstream_address, path)
-        .parse()
-        .map_err(|e| ProxyError::UpstreamConnect(format!("invalid upstream URI: {e}")))?;
+    let call = path.strip_prefix('/').unwrap_or(path);
+    // The permit comes first: a call turned away for concurrency must not
+    // spend a rate limit token. A rate-limited call drops its permit.
+    let permit = state.concurrency_limiter.acquire(config, &username, call)?;
+    state.rate_limiter.check(config, &username, call)?;
+
+    let (service, _) = parse_grpc_path(path);
+    let upstream_name = config
+        .route(service)
+        .ok_or_else(|| ProxyError::NoRoute(service.to_owned()))?;
+    let upstream = state
+        .upstreams
+        .get(upstream_name)
+        .ok_or_else(|| ProxyError::NoRoute(service.to_owned()))?;
+    tracing::debug!(service = %service, upstream = %upstream_name, "routing request");
 
-    let (mut parts, body) = req.into_parts();
-    parts.uri = upstream_uri;
-    parts.headers.remove("authorization");
+    let mut req = req;
+    req.headers_mut().remove("authorization");
 
-    let upstream_req = Request::from_parts(parts, body);
+    // The deadline runs from when the call arrived, so time spent on
+    // authentication counts against it.
+    let timeout =
+        deadline::effective_timeout(config, &username, call, req.headers().get("grpc-timeout"));
+    let deadline = timeout.map(|timeout| start + timeout);
+    if let Some(deadline) = deadline {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        req.headers_mut()
+            .insert("grpc-timeout", deadline::encode_timeout(remaining));
+    }
 
-    let response = state
-        .upstream_client
-        .request(upstream_req)
-        .await
-        .map_err(|e| ProxyError::UpstreamRequest(e.to_string()))?;
+    let policy = config
+        .retries
+        .iter()
+        .find(|policy| policy.call.matches(call));
+    let send = async {
+        match policy {
+            Some(policy) => {
+                let retries = &state.metrics.upstream_retries_total;
+                retry::send(upstream, req, policy, retries).await
+            }
+            None => upstream.send(req.map(BodyExt::boxed)).await,
+        }
+    };
+    let response = match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), send)
+            .await
+            .map_err(|_| ProxyError::DeadlineExceeded)??,
+        None => send.await?,
+    };
 
+    let response = response.map(|inner| CallBody {
+        inner: Some(inner),
+        deadline: deadline.map(|deadline| Box::pin(tokio::time::sleep_until(deadline.into()))),
+        _permit: permit,
+    });
     Ok((response, username))
 }
 
+/// The address a call came from. Behind a trusted proxy that is the client
+/// it names: the last `x-forwarded-for` entry not added by another trusted
+/// proxy, or `x-real-ip` if there is no `x-forwarded-for`.
+fn client_addr(
+    peer_addr: PeerAddr,
+    headers: &HeaderMap,
+    trusted_proxies: &[TrustedProxy],
+) -> PeerAddr {
+    let is_trusted = |addr: PeerAddr| trusted_proxies.iter().any(|proxy| proxy.matches(addr));
+    if !is_trusted(peer_addr) {
+        return peer_addr;
+    }
+
+    let forwarded: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    if forwarded.is_empty() {
+        return headers
+            .get("x-real-ip")
+            .and_then(|value| parse_forwarded_ip(value.to_str().ok()?))
+            .map_or(peer_addr, PeerAddr::Forwarded);
+    }
+
+    // Each proxy appends the address it received the call from, so walk back
+    // from the nearest hop. An entry that doesn't parse can't be trusted to
+    // have been written by a proxy, and stops the walk.
+    let mut client = peer_addr;
+    for hop in forwarded.iter().rev() {
+        let Some(ip) = parse_forwarded_ip(hop) else {
+            break;
+        };
+        client = PeerAddr::Forwarded(ip);
+        if !is_trusted(client) {
+            break;
+        }
+    }
+    client
+}
+
+/// An address as proxies write it, with or without a port.
+fn parse_forwarded_ip(value: &str) -> Option<IpAddr> {
+    let ip = value
+        .parse::<IpAddr>()
+        .or_else(|_| value.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()?;
+    Some(ip.to_canonical())
+}
+
 fn parse_grpc_path(path: &str) -> (&str, &str) {
     let trimmed = path.strip_prefix('/').unwrap_or(path);
     match trimmed.rsplit_once('/') {
@@ -141,3 +439,238 @@ fn parse_grpc_path(path: &str) -> (&str, &str) {
         None => (trimmed, "unknown"),
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::time::Duration;
+
+    use http_body_util::Full;
+    use prometheus::{IntGaugeVec, Opts};
+
+    use super::*;
+    use crate::cluster::tests::{grpc_response, stub_upstream};
+
+    /// Proxy state running `snapshot`, with authentication enabled.
+    pub(crate) fn app_state(
+        snapshot: Snapshot,
+        upstreams: HashMap<String, Arc<Cluster>>,
+    ) -> AppState {
+        let config = &snapshot.config;
+        let metrics = MetricsState::new().unwrap();
+        AppState {
+            verify_cache: VerifyCache::new(
+                &config.auth_cache,
+                metrics.auth_cache_hits_total.clone(),
+                metrics.auth_cache_misses_total.clone(),
+            ),
+            auth_pool: AuthPool::new(&config.auth_pool, metrics.auth_queue_wait_seconds.clone())
+                .unwrap(),
+            rate_limiter: RateLimiter::new(),
+            concurrency_limiter: ConcurrencyLimiter::new(metrics.in_flight_calls.clone()),
+            skip_auth: false,
+            metrics,
+            upstreams,
+            draining: AtomicBool::new(false),
+            snapshot: ArcSwap::from_pointee(snapshot),
+        }
+    }
+
+    fn trusted(entries: &[&str]) -> Vec<TrustedProxy> {
+        entries
+            .iter()
+            .map(|entry| TrustedProxy::try_from((*entry).to_owned()).unwrap())
+            .collect()
+    }
+
+    fn header_map(entries: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in entries {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn tcp(addr: &str) -> PeerAddr {
+        PeerAddr::Tcp(addr.parse().unwrap())
+    }
+
+    fn forwarded(ip: &str) -> PeerAddr {
+        PeerAddr::Forwarded(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn untrusted_peers_keep_their_address() {
+        let proxies = trusted(&["10.0.0.0/8"]);
+        let headers = header_map(&[("x-forwarded-for", "1.2.3.4"), ("x-real-ip", "1.2.3.4")]);
+        let peer = tcp("192.0.2.1:5000");
+        assert_eq!(client_addr(peer, &headers, &proxies), peer);
+        assert_eq!(
+            client_addr(PeerAddr::Unix, &headers, &proxies),
+            PeerAddr::Unix
+        );
+        assert_eq!(client_addr(peer, &headers, &[]), peer);
+    }
+
+    #[test]
+    fn single_trusted_hop() {
+        let proxies = trusted(&["127.0.0.1"]);
+        let headers = header_map(&[("x-forwarded-for", "203.0.113.7")]);
+        assert_eq!(
+            client_addr(tcp("127.0.0.1:4000"), &headers, &proxies),
+            forwarded("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn walks_back_through_trusted_hops() {
+        let proxies = trusted(&["127.0.0.1", "10.0.0.0/8"]);
+        let headers = header_map(&[("x-forwarded-for", "203.0.113.7, 10.1.1.1, 10.2.2.2")]);
+        assert_eq!(
+            client_addr(tcp("127.0.0.1:4000"), &headers, &proxies),
+            forwarded("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn spoofed_leftmost_entries_are_ignored() {
+        // The client sent its own x-forwarded-for; the proxies appended to it.
+        let proxies = trusted(&["127.0.0.1", "10.0.0.0/8"]);
+        let headers = header_map(&[(
+            "x-forwarded-for",
+            "10.9.9.9, 6.6.6.6, 203.0.113.7, 10.1.1.1",
+        )]);
+        assert_eq!(
+            client_addr(tcp("127.0.0.1:4000"), &headers, &proxies),
+            forwarded("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn all_hops_trusted_yields_the_first() {
+        let proxies = trusted(&["10.0.0.0/8"]);
+        let headers = header_map(&[("x-forwarded-for", "10.3.3.3, 10.1.1.1")]);
+        assert_eq!(
+            client_addr(tcp("10.0.0.1:4000"), &headers, &proxies),
+            forwarded("10.3.3.3")
+        );
+    }
+
+    #[test]
+    fn unparsable_entry_stops_the_walk() {
+        let proxies = trusted(&["10.0.0.0/8"]);
+        let headers = header_map(&[("x-forwarded-for", "203.0.113.7, unknown, 10.1.1.1")]);
+        assert_eq!(
+            client_addr(tcp("10.0.0.1:4000"), &headers, &proxies),
+            forwarded("10.1.1.1")
+        );
+
+        let headers = header_map(&[("x-forwarded-for", "garbage")]);
+        let peer = tcp("10.0.0.1:4000");
+        assert_eq!(client_addr(peer, &headers, &proxies), peer);
+    }
+
+    #[test]
+    fn joins_repeated_headers_and_strips_ports() {
+        let proxies = trusted(&["10.0.0.0/8"]);
+        let headers = header_map(&[
+            ("x-forwarded-for", "203.0.113.7:1234"),
+            ("x-forwarded-for", "[2001:db8::5]:99, 10.1.1.1"),
+        ]);
+        assert_eq!(
+            client_addr(tcp("10.0.0.1:4000"), &headers, &proxies),
+            forwarded("2001:db8::5")
+        );
+    }
+
+    #[test]
+    fn x_real_ip_without_x_forwarded_for() {
+        let proxies = trusted(&["unix"]);
+        let headers = header_map(&[("x-real-ip", "::ffff:203.0.113.7")]);
+        assert_eq!(
+            client_addr(PeerAddr::Unix, &headers, &proxies),
+            forwarded("203.0.113.7")
+        );
+
+        let headers = header_map(&[("x-real-ip", "nonsense")]);
+        assert_eq!(
+            client_addr(PeerAddr::Unix, &headers, &proxies),
+            PeerAddr::Unix
+        );
+    }
+
+    #[test]
+    fn x_forwarded_for_takes_precedence_over_x_real_ip() {
+        let proxies = trusted(&["127.0.0.1"]);
+        let headers = header_map(&[
+            ("x-forwarded-for", "203.0.113.7"),
+            ("x-real-ip", "198.51.100.1"),
+        ]);
+        assert_eq!(
+            client_addr(tcp("127.0.0.1:4000"), &headers, &proxies),
+            forwarded("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn ipv4_mapped_peers_match_ipv4_networks() {
+        let proxies = trusted(&["127.0.0.1"]);
+        let headers = header_map(&[("x-forwarded-for", "203.0.113.7")]);
+        assert_eq!(
+            client_addr(tcp("[::ffff:127.0.0.1]:4000"), &headers, &proxies),
+            forwarded("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn client_cert_listeners_ignore_authorization_headers() {
+        let headers = header_map(&[("authorization", "Bearer ci.s3cret")]);
+        assert_eq!(
+            authorization_header(&headers, ListenerAuth::Credentials),
+            Some("Bearer ci.s3cret")
+        );
+        assert_eq!(
+            authorization_header(&headers, ListenerAuth::ClientCert),
+            None
+        );
+        assert_eq!(
+            authorization_header(&HeaderMap::new(), ListenerAuth::Credentials),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn call_body_holds_the_permit_until_dropped() {
+        let stub = stub_upstream(|_| (Duration::ZERO, grpc_response(0, b"\0\0\0\0\0"))).await;
+        let config: Config = toml::from_str(
+            "metrics_address = \"127.0.0.1:9090\"\n[concurrency_limits]\nper_user = 1",
+        )
+        .unwrap();
+        let limiter = ConcurrencyLimiter::new(
+            IntGaugeVec::new(Opts::new("in_flight", "help"), &["user"]).unwrap(),
+        );
+
+        let permit = limiter.acquire(&config, "alice", "a.S/M").unwrap();
+        let request = Request::post("/a.S/M")
+            .body(
+                Full::new(Bytes::new())
+                    .map_err(|never| match never {})
+                    .boxed(),
+            )
+            .unwrap();
+        let response = stub.cluster.send(request).await.unwrap();
+        assert_eq!(stub.bodies.lock().unwrap().len(), 1);
+        let mut body = CallBody {
+            inner: Some(response.into_body()),
+            deadline: None,
+            _permit: permit,
+        };
+        assert!(limiter.acquire(&config, "alice", "a.S/M").is_err());
+
+        // Reading the body to the end is not enough, the call stays open
+        // until the body is dropped.
+        while body.frame().await.is_some() {}
+        assert!(limiter.acquire(&config, "alice", "a.S/M").is_err());
+        drop(body);
+        assert!(limiter.acquire(&config, "alice", "a.S/M").is_ok());
+    }
+}
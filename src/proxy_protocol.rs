@@ -0,0 +1,288 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The longest v1 header, including the line break.
+const V1_MAX_LENGTH: usize = 107;
+
+/// Reads a PROXY protocol v1 or v2 header from the start of `stream` and
+/// returns the client address it announces. `None` means the sender had no
+/// client to report (`LOCAL`, `UNKNOWN` or a non-IP address family), as for
+/// the balancer's own health checks.
+///
+/// Nothing past the header is read, so the stream can be handed on as is.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut start = [0u8; 6];
+    stream.read_exact(&mut start).await?;
+    if &start == b"PROXY " {
+        return read_v1(stream).await;
+    }
+    if start == V2_SIGNATURE[..6] {
+        return read_v2(stream).await;
+    }
+    Err(invalid("missing PROXY protocol header"))
+}
+
+/// `PROXY TCP4 <src> <dst> <src port> <dst port>\r\n`, after `PROXY `.
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    // Read byte by byte, since the line has no length prefix and reading
+    // past it would swallow the start of the connection.
+    let mut line = Vec::with_capacity(V1_MAX_LENGTH);
+    while !line.ends_with(b"\r\n") {
+        if line.len() + 6 >= V1_MAX_LENGTH {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    line.truncate(line.len() - 2);
+
+    let line = std::str::from_utf8(&line).map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["UNKNOWN", ..] => Ok(None),
+        [
+            protocol @ ("TCP4" | "TCP6"),
+            source,
+            _destination,
+            port,
+            _destination_port,
+        ] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| invalid("invalid PROXY v1 source address"))?;
+            if ip.is_ipv4() != (*protocol == "TCP4") {
+                return Err(invalid("PROXY v1 address does not match protocol"));
+            }
+            let port: u16 = port
+                .parse()
+                .map_err(|_| invalid("invalid PROXY v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY v1 header")),
+    }
+}
+
+/// The binary header, after the first six bytes of its signature.
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut header = [0u8; 10];
+    stream.read_exact(&mut header).await?;
+    if header[..6] != V2_SIGNATURE[6..] {
+        return Err(invalid("invalid PROXY v2 signature"));
+    }
+    let version_command = header[6];
+    let family = header[7];
+    let length = u16::from_be_bytes([header[8], header[9]]);
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+
+    // Addresses are followed by optional TLVs, which are skipped.
+    let mut payload = vec![0u8; usize::from(length)];
+    stream.read_exact(&mut payload).await?;
+
+    match version_command & 0x0f {
+        // LOCAL: a connection from the balancer itself.
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unsupported PROXY v2 command")),
+    }
+    match family >> 4 {
+        // TCP or UDP over IPv4: source, destination, source port, destination port.
+        1 => {
+            let address = payload
+                .first_chunk::<12>()
+                .ok_or_else(|| invalid("PROXY v2 IPv4 addresses truncated"))?;
+            let ip = Ipv4Addr::from(*address.first_chunk::<4>().expect("12 bytes"));
+            let port = u16::from_be_bytes([address[8], address[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        2 => {
+            let address = payload
+                .first_chunk::<36>()
+                .ok_or_else(|| invalid("PROXY v2 IPv6 addresses truncated"))?;
+            let ip = Ipv6Addr::from(*address.first_chunk::<16>().expect("36 bytes"));
+            let port = u16::from_be_bytes([address[32], address[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // Unspecified or Unix socket addresses name no IP client.
+        _ => Ok(None),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(mut input: &[u8]) -> (io::Result<Option<SocketAddr>>, &[u8]) {
+        let result = read_header(&mut input).await;
+        (result, input)
+    }
+
+    fn v2(command: u8, family: u8, payload: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend((payload.len() as u16).to_be_bytes());
+        header.extend(payload);
+        header
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4() {
+        let (result, rest) = parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nPRI").await;
+        assert_eq!(result.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"PRI");
+    }
+
+    #[tokio::test]
+    async fn v1_tcp6() {
+        let (result, _) = parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\n").await;
+        assert_eq!(result.unwrap(), Some("[2001:db8::1]:4000".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn v1_unknown() {
+        let (result, rest) = parse(b"PROXY UNKNOWN\r\nPRI").await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"PRI");
+        let (result, _) = parse(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n").await;
+        assert_eq!(result.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v1_rejects_malformed_lines() {
+        for input in [
+            &b"PROXY TCP4 2001:db8::1 192.0.2.2 1 2\r\n"[..],
+            b"PROXY TCP6 192.0.2.1 192.0.2.2 1 2\r\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 70000 2\r\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 1\r\n",
+            b"PROXY UDP4 192.0.2.1 192.0.2.2 1 2\r\n",
+            b"PROXY TCP4 192.0.2.1  192.0.2.2 1 2\r\n",
+            b"PROXY TCP4 \xff 192.0.2.2 1 2\r\n",
+        ] {
+            let (result, _) = parse(input).await;
+            assert_eq!(
+                result.unwrap_err().kind(),
+                io::ErrorKind::InvalidData,
+                "{}",
+                String::from_utf8_lossy(input)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn v1_rejects_oversized_lines() {
+        let mut input = b"PROXY UNKNOWN ".to_vec();
+        input.extend([b'a'; 200]);
+        input.extend(b"\r\n");
+        let (result, rest) = parse(&input).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // Reading stops at the limit.
+        assert_eq!(rest.len(), input.len() - V1_MAX_LENGTH);
+
+        // The longest valid line is accepted.
+        let mut input = b"PROXY UNKNOWN ".to_vec();
+        input.resize(V1_MAX_LENGTH - 2, b'a');
+        input.extend(b"\r\n");
+        let (result, _) = parse(&input).await;
+        assert_eq!(result.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v1_truncated() {
+        let (result, _) = parse(b"PROXY TCP4 192.0.2.1").await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn v2_proxy_ipv4() {
+        let payload = [192, 0, 2, 1, 198, 51, 100, 1, 0x1f, 0x90, 0x01, 0xbb];
+        let mut input = v2(1, 0x11, &payload);
+        input.extend(b"PRI");
+        let (result, rest) = parse(&input).await;
+        assert_eq!(result.unwrap(), Some("192.0.2.1:8080".parse().unwrap()));
+        assert_eq!(rest, b"PRI");
+    }
+
+    #[tokio::test]
+    async fn v2_proxy_ipv6_with_tlvs() {
+        let mut payload = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)
+            .octets()
+            .to_vec();
+        payload.extend(Ipv6Addr::LOCALHOST.octets());
+        payload.extend([0x10, 0x00, 0x01, 0xbb]);
+        payload.extend([0x04, 0x00, 0x01, 0xff]); // a TLV
+        let input = v2(1, 0x21, &payload);
+        let (result, rest) = parse(&input).await;
+        assert_eq!(result.unwrap(), Some("[2001:db8::1]:4096".parse().unwrap()));
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn v2_local_and_unix_name_no_client() {
+        let mut input = v2(0, 0x00, &[]);
+        input.extend(b"PRI");
+        let (result, rest) = parse(&input).await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"PRI");
+
+        let input = v2(1, 0x31, &[0; 216]);
+        let (result, rest) = parse(&input).await;
+        assert_eq!(result.unwrap(), None);
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn v2_rejects_truncated_addresses() {
+        let (result, _) = parse(&v2(1, 0x11, &[192, 0, 2, 1])).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let (result, _) = parse(&v2(1, 0x21, &[0; 20])).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn v2_truncated_payload() {
+        let mut input = v2(1, 0x11, &[0; 12]);
+        input.truncate(input.len() - 1);
+        let (result, _) = parse(&input).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn v2_rejects_bad_version_and_command() {
+        let mut input = v2(1, 0x11, &[0; 12]);
+        input[12] = 0x11;
+        let (result, _) = parse(&input).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let (result, _) = parse(&v2(2, 0x11, &[0; 12])).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_signatures() {
+        let mut input = v2(1, 0x11, &[0; 12]);
+        input[8] = b'X';
+        let (result, _) = parse(&input).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let (result, _) = parse(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n").await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let (result, _) = parse(b"proxy TCP4 192.0.2.1 192.0.2.2 1 2\r\n").await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn empty_input() {
+        let (result, _) = parse(b"").await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, RateLimit};
+use crate::error::ProxyError;
+
+/// Buckets at or above this count are swept for idle entries before another
+/// one is added.
+const MIN_PRUNE_AT: usize = 1024;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum BucketKey {
+    Global,
+    User(String),
+    Method(String),
+}
+
+impl BucketKey {
+    fn limit_name(&self) -> &'static str {
+        match self {
+            Self::Global => "global",
+            Self::User(_) => "user",
+            Self::Method(_) => "method",
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// When the bucket will have refilled completely, as of the last update.
+    full_at: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.capacity());
+        self.updated = now;
+    }
+
+    fn take(&mut self, limit: &RateLimit) {
+        self.tokens -= 1.0;
+        let missing = limit.capacity() - self.tokens;
+        self.full_at = self.updated + Duration::from_secs_f64(missing / limit.rate);
+    }
+}
+
+struct Buckets {
+    entries: HashMap<BucketKey, Bucket>,
+    prune_at: usize,
+}
+
+/// Token buckets for `[rate_limits]` and per-user limits. The limits are read
+/// from the config on every call, so reloads apply to buckets already in use.
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self {
+            buckets: Mutex::new(Buckets {
+                entries: HashMap::new(),
+                prune_at: MIN_PRUNE_AT,
+            }),
+        }
+    }
+
+    /// Takes a token from every bucket that applies to the call, or from none
+    /// of them if any is empty.
+    pub fn check(&self, config: &Config, user: &str, call: &str) -> Result<(), ProxyError> {
+        let limits = &config.rate_limits;
+        let user_limit = config
+            .users
+            .get(user)
+            .and_then(|user| user.rate_limit.as_ref())
+            .or(limits.per_user.as_ref());
+
+        let mut applicable = Vec::new();
+        if let Some(limit) = &limits.global {
+            applicable.push((BucketKey::Global, limit));
+        }
+        if let Some(limit) = user_limit {
+            applicable.push((BucketKey::User(user.to_owned()), limit));
+        }
+        for method in &limits.methods {
+            if method.call.matches(call) {
+                applicable.push((BucketKey::Method(method.call.to_string()), &method.limit));
+            }
+        }
+        if applicable.is_empty() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        buckets.prune(now);
+
+        for (key, limit) in &applicable {
+            let bucket = buckets.entries.entry(key.clone()).or_insert(Bucket {
+                tokens: limit.capacity(),
+                updated: now,
+                full_at: now,
+            });
+            bucket.refill(limit, now);
+            if bucket.tokens < 1.0 {
+                return Err(ProxyError::RateLimited {
+                    user: user.to_owned(),
+                    limit: key.limit_name(),
+                });
+            }
+        }
+        for (key, limit) in &applicable {
+            if let Some(bucket) = buckets.entries.get_mut(key) {
+                bucket.take(limit);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Buckets {
+    /// Drops buckets that have refilled completely, which behave exactly like
+    /// buckets that were never created. Keeps the map from growing with every
+    /// JWT subject ever seen.
+    fn prune(&mut self, now: Instant) {
+        if self.entries.len() < self.prune_at {
+            return;
+        }
+        self.entries.retain(|_, bucket| bucket.full_at > now);
+        self.prune_at = (self.entries.len() * 2).max(MIN_PRUNE_AT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_config(toml: &str) -> Config {
+        toml::from_str(&format!("metrics_address = \"127.0.0.1:9090\"\n{toml}")).unwrap()
+    }
+
+    /// Moves every bucket's clock back, as if `elapsed` had passed since its
+    /// last update.
+    fn advance(limiter: &RateLimiter, elapsed: Duration) {
+        let mut buckets = limiter.buckets.lock().unwrap();
+        for bucket in buckets.entries.values_mut() {
+            bucket.updated -= elapsed;
+            bucket.full_at -= elapsed;
+        }
+    }
+
+    fn limited_by(result: Result<(), ProxyError>) -> Option<&'static str> {
+        match result {
+            Ok(()) => None,
+            Err(ProxyError::RateLimited { limit, .. }) => Some(limit),
+            Err(e) => panic!("unexpected error: {e}"),
+        }
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let config = load_config("[rate_limits]\nper_user = { rate = 2, burst = 3 }");
+        let limiter = RateLimiter::new();
+        for _ in 0..3 {
+            assert!(limiter.check(&config, "alice", "a.S/M").is_ok());
+        }
+        assert_eq!(
+            limited_by(limiter.check(&config, "alice", "a.S/M")),
+            Some("user")
+        );
+
+        advance(&limiter, Duration::from_millis(500));
+        assert!(limiter.check(&config, "alice", "a.S/M").is_ok());
+        assert!(limiter.check(&config, "alice", "a.S/M").is_err());
+
+        // Refilling stops at the burst size.
+        advance(&limiter, Duration::from_secs(60));
+        for _ in 0..3 {
+            assert!(limiter.check(&config, "alice", "a.S/M").is_ok());
+        }
+        assert!(limiter.check(&config, "alice", "a.S/M").is_err());
+    }
+
+    #[test]
+    fn users_have_separate_buckets() {
+        let config = load_config(
+            r#"
+            [rate_limits]
+            per_user = { rate = 1 }
+            [users.bob]
+            rate_limit = { rate = 1, burst = 2 }
+            "#,
+        );
+        let limiter = RateLimiter::new();
+        assert!(limiter.check(&config, "alice", "a.S/M").is_ok());
+        assert!(limiter.check(&config, "alice", "a.S/M").is_err());
+        assert!(limiter.check(&config, "carol", "a.S/M").is_ok());
+        assert!(limiter.check(&config, "bob", "a.S/M").is_ok());
+        assert!(limiter.check(&config, "bob", "a.S/M").is_ok());
+        assert!(limiter.check(&config, "bob", "a.S/M").is_err());
+    }
+
+    #[test]
+    fn takes_from_all_buckets_or_none() {
+        let config = load_config(
+            r#"
+            [rate_limits]
+            global = { rate = 1, burst = 4 }
+            per_user = { rate = 1, burst = 2 }
+            methods = [{ call = "shop.Orders/*", rate = 1, burst = 1 }]
+            "#,
+        );
+        let limiter = RateLimiter::new();
+
+        assert!(
+            limiter
+                .check(&config, "alice", "shop.Orders/Create")
+                .is_ok()
+        );
+        assert_eq!(
+            limited_by(limiter.check(&config, "bob", "shop.Orders/Create")),
+            Some("method")
+        );
+        // The rejected call took nothing from the global or bob's bucket.
+        assert!(limiter.check(&config, "bob", "shop.Catalog/Get").is_ok());
+        assert!(limiter.check(&config, "bob", "shop.Catalog/Get").is_ok());
+        assert_eq!(
+            limited_by(limiter.check(&config, "bob", "shop.Catalog/Get")),
+            Some("user")
+        );
+        assert!(limiter.check(&config, "carol", "shop.Catalog/Get").is_ok());
+        assert_eq!(
+            limited_by(limiter.check(&config, "dave", "shop.Catalog/Get")),
+            Some("global")
+        );
+    }
+
+    #[test]
+    fn calls_without_limits_pass() {
+        let config = load_config("");
+        let limiter = RateLimiter::new();
+        for _ in 0..100 {
+            assert!(limiter.check(&config, "alice", "a.S/M").is_ok());
+        }
+        assert!(limiter.buckets.lock().unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn prunes_full_buckets_once_there_are_many() {
+        let config = load_config("[rate_limits]\nper_user = { rate = 1, burst = 5 }");
+        let limiter = RateLimiter::new();
+        for user in 0..MIN_PRUNE_AT {
+            assert!(
+                limiter
+                    .check(&config, &format!("user-{user}"), "a.S/M")
+                    .is_ok()
+            );
+        }
+        assert_eq!(limiter.buckets.lock().unwrap().entries.len(), MIN_PRUNE_AT);
+
+        // Every bucket has refilled except the one used again just now.
+        advance(&limiter, Duration::from_secs(10));
+        {
+            let mut buckets = limiter.buckets.lock().unwrap();
+            let busy = buckets
+                .entries
+                .get_mut(&BucketKey::User("user-7".to_owned()))
+                .unwrap();
+            busy.full_at = Instant::now() + Duration::from_secs(10);
+        }
+
+        assert!(limiter.check(&config, "newcomer", "a.S/M").is_ok());
+        let buckets = limiter.buckets.lock().unwrap();
+        let mut remaining: Vec<&str> = buckets
+            .entries
+            .keys()
+            .map(|key| match key {
+                BucketKey::User(user) => user.as_str(),
+                _ => panic!("only user buckets were created"),
+            })
+            .collect();
+        remaining.sort_unstable();
+        assert_eq!(remaining, ["newcomer", "user-7"]);
+        assert_eq!(buckets.prune_at, MIN_PRUNE_AT);
+    }
+}
@@ -0,0 +1,400 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::time::Interval;
+
+use crate::config::{self, Config, Credentials};
+use crate::error::ProxyError;
+use crate::jwt::{self, JwtValidator};
+use crate::proxy::{AppState, Snapshot};
+
+/// Re-reads the config and credentials files and swaps them into the running
+/// proxy. Requests already in flight finish with the snapshot they started
+/// with.
+pub struct Reloader {
+    config_path: String,
+    /// `None` when authentication is disabled.
+    credentials_path: Option<String>,
+    state: Arc<AppState>,
+}
+
+impl Reloader {
+    pub fn new(
+        config_path: String,
+        credentials_path: Option<String>,
+        state: Arc<AppState>,
+    ) -> Self {
+        Self {
+            config_path,
+            credentials_path,
+            state,
+        }
+    }
+
+    /// Loads and validates both files, keeping the running state untouched if
+    /// anything fails.
+    async fn reload(&self) -> Result<(), ProxyError> {
+        let mut config = config::load_config(&self.config_path)?;
+        let credentials = match &self.credentials_path {
+            Some(path) => config::load_credentials(path)?,
+            None => Credentials::empty(),
+        };
+
+        let current = self.state.snapshot.load_full();
+        keep_restart_settings(&mut config, &current.config);
+        // Routes must still resolve against the upstreams actually running.
+        config.validate().map_err(ProxyError::ConfigLoad)?;
+
+        let jwt = match &config.jwt {
+            _ if self.state.skip_auth => None,
+            jwt_config if *jwt_config == current.config.jwt => current.jwt.clone(),
+            Some(jwt_config) => {
+                let validator = Arc::new(JwtValidator::new(jwt_config).await?);
+                tokio::spawn(jwt::refresh_keys(Arc::downgrade(&validator)));
+                Some(validator)
+            }
+            None => None,
+        };
+
+        tracing::info!(
+            hash = %config.hash,
+            users = config.users.len(),
+            roles = config.roles.len(),
+            "reloaded config and credentials"
+        );
+
+        self.state.metrics.set_config_hash(&config.hash);
+        self.state.snapshot.store(Arc::new(Snapshot {
+            config,
+            credentials,
+            jwt,
+        }));
+        Ok(())
+    }
+
+    async fn reload_and_record(&self) {
+        let result = match self.reload().await {
+            Ok(()) => "success",
+            Err(e) => {
+                tracing::warn!("keeping previous config: {e}");
+                "failure"
+            }
+        };
+        self.state
+            .metrics
+            .config_reloads_total
+            .with_label_values(&[result])
+            .inc();
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        std::iter::once(&self.config_path)
+            .chain(&self.credentials_path)
+            .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+}
+
+/// Reloads on SIGHUP and, if `reload.watch_interval_secs` is set, whenever the
+/// modification time of either file changes.
+pub async fn watch(reloader: Reloader) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            tracing::warn!("cannot listen for SIGHUP, config reload disabled: {e}");
+            return;
+        }
+    };
+
+    let watch_interval_secs = reloader
+        .state
+        .snapshot
+        .load()
+        .config
+        .reload
+        .watch_interval_secs;
+    let mut interval = (watch_interval_secs > 0)
+        .then(|| tokio::time::interval(Duration::from_secs(watch_interval_secs)));
+    let mut last_modified = reloader.modified();
+
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => tracing::info!("received SIGHUP, reloading config"),
+            () = tick(&mut interval) => {
+                if reloader.modified() == last_modified {
+                    continue;
+                }
+                tracing::info!("config or credentials file changed, reloading");
+            }
+        }
+
+        last_modified = reloader.modified();
+        reloader.reload_and_record().await;
+    }
+}
+
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Settings bound at startup (sockets, upstream client, TLS acceptor, auth
+/// pool) cannot change at runtime. Changes to them are reported and the
+/// running values kept, so the snapshot always describes what is in effect.
+fn keep_restart_settings(config: &mut Config, running: &Config) {
+    let mut changed = Vec::new();
+    keep(
+        &mut changed,
+        "listeners",
+        &mut config.listeners,
+        &running.listeners,
+    );
+    keep(
+        &mut changed,
+        "upstreams",
+        &mut config.upstreams,
+        &running.upstreams,
+    );
+    keep(
+        &mut changed,
+        "metrics_address",
+        &mut config.metrics_address,
+        &running.metrics_address,
+    );
+    keep(
+        &mut changed,
+        "metrics_socket",
+        &mut config.metrics_socket,
+        &running.metrics_socket,
+    );
+    keep(
+        &mut changed,
+        "auth_cache",
+        &mut config.auth_cache,
+        &running.auth_cache,
+    );
+    keep(
+        &mut changed,
+        "auth_pool",
+        &mut config.auth_pool,
+        &running.auth_pool,
+    );
+    keep(&mut changed, "reload", &mut config.reload, &running.reload);
+
+    if !changed.is_empty() {
+        tracing::warn!(
+            settings = %changed.join(", "),
+            "ignoring changed settings that require a restart"
+        );
+    }
+}
+
+fn keep<T: PartialEq + Clone>(
+    changed: &mut Vec<&'static str>,
+    name: &'static str,
+    value: &mut T,
+    running: &T,
+) {
+    if value != running {
+        changed.push(name);
+        *value = running.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::proxy::tests::app_state;
+
+    const CONFIG: &str = r#"
+        listen_address = "127.0.0.1:50051"
+        metrics_address = "127.0.0.1:9090"
+        upstream_address = "127.0.0.1:50052"
+
+        [users.alice]
+        allowed_calls = ["*"]
+    "#;
+
+    const CREDENTIALS: &str = "alice:$argon2id$v=19$m=8,t=1,p=1$c2FsdHNhbHQ$aGFzaA\n";
+
+    fn temp_path(name: &str) -> String {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "grpc-proxier-reload-{name}-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        path.to_str().unwrap().to_owned()
+    }
+
+    /// A reloader over temporary config and credentials files, running with
+    /// whatever they held when it was created.
+    struct Fixture {
+        reloader: Reloader,
+        secret_path: String,
+    }
+
+    impl Fixture {
+        /// `{secret}` in `config` is replaced by the path of an HMAC secret.
+        async fn new(config: &str) -> Self {
+            let secret_path = temp_path("secret");
+            std::fs::write(&secret_path, "jwt secret").unwrap();
+            let config_path = temp_path("config");
+            let credentials_path = temp_path("credentials");
+            std::fs::write(&credentials_path, CREDENTIALS).unwrap();
+            let fixture_config = config.replace("{secret}", &format!("{secret_path:?}"));
+            std::fs::write(&config_path, fixture_config).unwrap();
+
+            let config = config::load_config(&config_path).unwrap();
+            let credentials = config::load_credentials(&credentials_path).unwrap();
+            let jwt = match &config.jwt {
+                Some(jwt_config) => Some(Arc::new(JwtValidator::new(jwt_config).await.unwrap())),
+                None => None,
+            };
+            let state = app_state(
+                Snapshot {
+                    config,
+                    credentials,
+                    jwt,
+                },
+                HashMap::new(),
+            );
+
+            Self {
+                reloader: Reloader::new(config_path, Some(credentials_path), Arc::new(state)),
+                secret_path,
+            }
+        }
+
+        fn write_config(&self, content: &str) {
+            let content = content.replace("{secret}", &format!("{:?}", self.secret_path));
+            std::fs::write(&self.reloader.config_path, content).unwrap();
+        }
+
+        fn write_credentials(&self, content: &str) {
+            let path = self.reloader.credentials_path.as_ref().unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+
+        fn snapshot(&self) -> Arc<Snapshot> {
+            self.reloader.state.snapshot.load_full()
+        }
+
+        fn reloads(&self, result: &str) -> u64 {
+            self.reloader
+                .state
+                .metrics
+                .config_reloads_total
+                .with_label_values(&[result])
+                .get()
+        }
+
+        /// Reloads and checks that it failed without touching the snapshot.
+        async fn assert_reload_fails(&self) {
+            let before = self.snapshot();
+            let failures = self.reloads("failure");
+            self.reloader.reload_and_record().await;
+            assert!(Arc::ptr_eq(&before, &self.snapshot()));
+            assert_eq!(self.reloads("failure"), failures + 1);
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let reloader = &self.reloader;
+            for path in std::iter::once(&reloader.config_path)
+                .chain(&reloader.credentials_path)
+                .chain([&self.secret_path])
+            {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_config_keeps_the_running_snapshot() {
+        let fixture = Fixture::new(CONFIG).await;
+
+        fixture.write_config("listen_address = ");
+        fixture.assert_reload_fails().await;
+
+        fixture.write_config(&format!("{CONFIG}\n[auth_pool]\nconcurrency = 0"));
+        fixture.assert_reload_fails().await;
+
+        // The new upstream is only added on restart, so the route to it
+        // cannot resolve.
+        fixture.write_config(&format!(
+            "[[routes]]\nservice = \"billing.*\"\nupstream = \"billing\"\n{CONFIG}\n[upstreams.billing]\naddress = \"127.0.0.1:50053\""
+        ));
+        fixture.assert_reload_fails().await;
+        assert_eq!(fixture.reloads("success"), 0);
+    }
+
+    #[tokio::test]
+    async fn broken_credentials_keep_the_running_snapshot() {
+        let fixture = Fixture::new(CONFIG).await;
+        fixture.write_config(&format!("{CONFIG}\n[users.bob]"));
+        fixture.write_credentials("alice\n");
+        fixture.assert_reload_fails().await;
+
+        std::fs::remove_file(fixture.reloader.credentials_path.as_ref().unwrap()).unwrap();
+        fixture.assert_reload_fails().await;
+        assert!(!fixture.snapshot().config.users.contains_key("bob"));
+    }
+
+    #[tokio::test]
+    async fn restart_settings_keep_their_running_values() {
+        let fixture = Fixture::new(CONFIG).await;
+        let running = fixture.snapshot();
+
+        fixture.write_config(
+            &CONFIG
+                .replace("50051", "50061")
+                .replace("50052", "50062")
+                .replace("[users.alice]", "[users.bob]\n[users.alice]"),
+        );
+        fixture.write_credentials(&format!("{CREDENTIALS}bob:$argon2id$hash\n"));
+        fixture.reloader.reload_and_record().await;
+        assert_eq!(fixture.reloads("success"), 1);
+
+        let reloaded = fixture.snapshot();
+        assert_eq!(reloaded.config.listeners, running.config.listeners);
+        assert_eq!(
+            reloaded.config.listeners[0].address.to_string(),
+            "127.0.0.1:50051"
+        );
+        assert_eq!(reloaded.config.upstreams, running.config.upstreams);
+        // Everything else is taken from the new files.
+        assert!(reloaded.config.users.contains_key("bob"));
+        assert!(reloaded.credentials.users.contains_key("bob"));
+        assert_ne!(reloaded.config.hash, running.config.hash);
+    }
+
+    #[tokio::test]
+    async fn unchanged_jwt_settings_reuse_the_validator() {
+        let jwt = "[jwt]\nalgorithms = [\"HS256\"]\nsecret_path = {secret}\n";
+        let fixture = Fixture::new(&format!("{CONFIG}\n{jwt}")).await;
+        let running = fixture.snapshot().jwt.clone().unwrap();
+
+        fixture.write_config(&format!("{CONFIG}\n[users.bob]\n{jwt}"));
+        fixture.reloader.reload_and_record().await;
+        let reloaded = fixture.snapshot();
+        assert!(reloaded.config.users.contains_key("bob"));
+        assert!(Arc::ptr_eq(&running, reloaded.jwt.as_ref().unwrap()));
+
+        fixture.write_config(&format!("{CONFIG}\n{jwt}leeway_secs = 5\n"));
+        fixture.reloader.reload_and_record().await;
+        let reloaded = fixture.snapshot();
+        assert_eq!(reloaded.config.jwt.as_ref().unwrap().leeway_secs, 5);
+        assert!(!Arc::ptr_eq(&running, reloaded.jwt.as_ref().unwrap()));
+        assert_eq!(fixture.reloads("success"), 2);
+    }
+}
@@ -0,0 +1,626 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use http::request::Parts;
+use http::{HeaderMap, Request, Response};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, SizeHint};
+use prometheus::IntCounterVec;
+use rand_core::{OsRng, RngCore};
+use tokio::task::JoinSet;
+
+use crate::cluster::{Cluster, UpstreamBody};
+use crate::config::RetryPolicy;
+use crate::error::ProxyError;
+
+type Attempt = Result<Response<UpstreamBody>, ProxyError>;
+
+/// How long an open request body may sit idle between messages before the
+/// call is taken for a client stream.
+const STREAM_IDLE: Duration = Duration::from_millis(10);
+
+/// Sends a call under its retry policy. The request body is buffered so it
+/// can be replayed; if it exceeds `max_buffer_bytes` or turns out to be a
+/// stream the call is sent once, as if it had no policy.
+pub async fn send<B>(
+    upstream: &Arc<Cluster>,
+    req: Request<B>,
+    policy: &RetryPolicy,
+    retries: &IntCounterVec,
+) -> Attempt
+where
+    B: Body<Data = Bytes, Error = hyper::Error> + Send + Sync + Unpin + 'static,
+{
+    let (parts, body) = req.into_parts();
+    let body = match buffer(body, policy.max_buffer_bytes, policy.client_streaming).await? {
+        Buffered::Complete(body) => body,
+        Buffered::Incomplete(body, reason) => {
+            tracing::debug!(upstream = %upstream.name(), reason, "request sent without retries");
+            return upstream
+                .send(Request::from_parts(parts, body.boxed()))
+                .await;
+        }
+    };
+
+    let hedging_delay = policy.hedging_delay_ms.map(Duration::from_millis);
+    let max_backoff = Duration::from_millis(policy.max_backoff_ms);
+    let mut attempts = JoinSet::new();
+    let mut started = 1;
+    let mut backoff = Duration::from_millis(policy.initial_backoff_ms);
+    start_attempt(&mut attempts, upstream, &parts, &body);
+
+    loop {
+        let hedge = async {
+            match hedging_delay {
+                Some(delay) if started < policy.max_attempts => tokio::time::sleep(delay).await,
+                _ => std::future::pending().await,
+            }
+        };
+
+        let result = tokio::select! {
+            Some(joined) = attempts.join_next() => joined.unwrap_or_else(|e| {
+                Err(ProxyError::UpstreamRequest(format!("attempt failed: {e}")))
+            }),
+            () = hedge => {
+                retries.with_label_values(&[upstream.name(), "hedge"]).inc();
+                started += 1;
+                start_attempt(&mut attempts, upstream, &parts, &body);
+                continue;
+            }
+        };
+
+        // Whatever attempts are still running are cancelled when the set is
+        // dropped.
+        if !is_retryable(&result, policy) {
+            return result;
+        }
+        if started >= policy.max_attempts {
+            if attempts.is_empty() {
+                return result;
+            }
+            continue;
+        }
+
+        tracing::debug!(upstream = %upstream.name(), attempt = started, "retrying call");
+        if hedging_delay.is_none() {
+            tokio::time::sleep(jitter(backoff)).await;
+            // In f64, so a large multiplier saturates instead of overflowing.
+            backoff = Duration::from_secs_f64(
+                (backoff.as_secs_f64() * policy.backoff_multiplier).min(max_backoff.as_secs_f64()),
+            );
+        }
+        retries.with_label_values(&[upstream.name(), "retry"]).inc();
+        started += 1;
+        start_attempt(&mut attempts, upstream, &parts, &body);
+    }
+}
+
+fn start_attempt<B>(
+    attempts: &mut JoinSet<Attempt>,
+    upstream: &Arc<Cluster>,
+    parts: &Parts,
+    body: &BufferedBody<B>,
+) where
+    B: Body<Data = Bytes, Error = hyper::Error> + Send + Sync + Unpin + 'static,
+{
+    let mut req = Request::new(body.clone().boxed());
+    *req.method_mut() = parts.method.clone();
+    *req.uri_mut() = parts.uri.clone();
+    *req.version_mut() = parts.version;
+    *req.headers_mut() = parts.headers.clone();
+
+    let upstream = Arc::clone(upstream);
+    attempts.spawn(async move { upstream.send(req).await });
+}
+
+/// Connection failures are always retryable. A response only if it carries
+/// no message, i.e. it is trailers-only with a retryable status.
+fn is_retryable(result: &Attempt, policy: &RetryPolicy) -> bool {
+    let response = match result {
+        Ok(response) => response,
+        Err(ProxyError::UpstreamConnect(_) | ProxyError::UpstreamRequest(_)) => return true,
+        Err(_) => return false,
+    };
+    response
+        .headers()
+        .get("grpc-status")
+        .and_then(|status| status.to_str().ok()?.parse::<u8>().ok())
+        .is_some_and(|status| {
+            policy
+                .retryable_status_codes
+                .iter()
+                .any(|code| *code as u8 == status)
+        })
+}
+
+/// A random duration up to `backoff`, so retries from many clients spread out.
+fn jitter(backoff: Duration) -> Duration {
+    backoff.mul_f64(f64::from(OsRng.next_u32()) / f64::from(u32::MAX))
+}
+
+enum Buffered<B> {
+    Complete(BufferedBody<B>),
+    /// Too large or a stream, with the reason for logging.
+    Incomplete(BufferedBody<B>, &'static str),
+}
+
+/// Reads `body` until it ends or exceeds `limit` bytes. If the method may be
+/// `client_streaming`, a body still open after a whole message that sends
+/// nothing more for [`STREAM_IDLE`] is taken for a client stream, which may
+/// be waiting for a response before it goes on. Either way it is returned
+/// with the unread rest attached.
+async fn buffer<B>(
+    mut body: B,
+    limit: usize,
+    client_streaming: bool,
+) -> Result<Buffered<B>, ProxyError>
+where
+    B: Body<Data = Bytes, Error = hyper::Error> + Unpin,
+{
+    let mut data = BytesMut::new();
+    let mut trailers = None;
+    while !body.is_end_stream() {
+        // Before the first message and mid-message, more data is on its way
+        // regardless. A unary client may also send END_STREAM in a frame of
+        // its own, a little after the message, so only stream-capable
+        // methods stop waiting.
+        let frame = if client_streaming && !data.is_empty() && is_message_boundary(&data) {
+            let next = tokio::time::timeout(STREAM_IDLE, body.frame()).await;
+            let Ok(frame) = next else {
+                return Ok(Buffered::Incomplete(
+                    BufferedBody {
+                        data: Some(data.freeze()),
+                        rest: Some(body),
+                        trailers,
+                    },
+                    "streaming",
+                ));
+            };
+            frame
+        } else {
+            body.frame().await
+        };
+        let Some(frame) = frame else {
+            break;
+        };
+
+        let frame = frame.map_err(|e| ProxyError::RequestBody(e.to_string()))?;
+        match frame.into_data() {
+            Ok(chunk) => data.extend_from_slice(&chunk),
+            Err(frame) => trailers = frame.into_trailers().ok(),
+        }
+        if data.len() > limit {
+            return Ok(Buffered::Incomplete(
+                BufferedBody {
+                    data: Some(data.freeze()),
+                    rest: Some(body),
+                    trailers,
+                },
+                "too large",
+            ));
+        }
+    }
+
+    Ok(Buffered::Complete(BufferedBody {
+        data: Some(data.freeze()),
+        rest: None,
+        trailers,
+    }))
+}
+
+/// Whether `data` ends exactly after a length-prefixed gRPC message.
+fn is_message_boundary(mut data: &[u8]) -> bool {
+    while let Some((header, rest)) = data.split_first_chunk::<5>() {
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        match rest.get(len..) {
+            Some(rest) => data = rest,
+            None => return false,
+        }
+    }
+    data.is_empty()
+}
+
+/// A request body read into memory, optionally followed by the rest of a
+/// body too large to buffer.
+struct BufferedBody<B> {
+    data: Option<Bytes>,
+    rest: Option<B>,
+    trailers: Option<HeaderMap>,
+}
+
+impl<B> Clone for BufferedBody<B> {
+    /// Only complete bodies are cloned, so there is never a `rest` to copy.
+    fn clone(&self) -> Self {
+        Self {
+            data: self.data.clone(),
+            rest: None,
+            trailers: self.trailers.clone(),
+        }
+    }
+}
+
+impl<B> Body for BufferedBody<B>
+where
+    B: Body<Data = Bytes, Error = hyper::Error> + Unpin,
+{
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, hyper::Error>>> {
+        if let Some(data) = self.data.take().filter(|data| !data.is_empty()) {
+            return Poll::Ready(Some(Ok(Frame::data(data))));
+        }
+        if let Some(rest) = &mut self.rest {
+            match Pin::new(rest).poll_frame(cx) {
+                Poll::Ready(None) => self.rest = None,
+                other => return other,
+            }
+        }
+        Poll::Ready(
+            self.trailers
+                .take()
+                .map(|trailers| Ok(Frame::trailers(trailers))),
+        )
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.data.as_ref().is_none_or(Bytes::is_empty)
+            && self.rest.is_none()
+            && self.trailers.is_none()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match &self.rest {
+            Some(_) => SizeHint::default(),
+            None => SizeHint::with_exact(self.data.as_ref().map_or(0, |data| data.len() as u64)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::Full;
+    use prometheus::Opts;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::cluster::tests::{grpc_response, stub_upstream};
+
+    /// A request body fed frame by frame. The flag sent with a frame marks
+    /// it as the last one, like END_STREAM on an HTTP/2 DATA frame.
+    struct ChannelBody {
+        frames: mpsc::UnboundedReceiver<(Frame<Bytes>, bool)>,
+        ended: bool,
+    }
+
+    impl Body for ChannelBody {
+        type Data = Bytes;
+        type Error = hyper::Error;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, hyper::Error>>> {
+            if self.ended {
+                return Poll::Ready(None);
+            }
+            self.frames.poll_recv(cx).map(|next| match next {
+                Some((frame, last)) => {
+                    self.ended = last;
+                    Some(Ok(frame))
+                }
+                None => {
+                    self.ended = true;
+                    None
+                }
+            })
+        }
+
+        fn is_end_stream(&self) -> bool {
+            self.ended
+        }
+    }
+
+    type Frames = mpsc::UnboundedSender<(Frame<Bytes>, bool)>;
+
+    fn channel() -> (Frames, ChannelBody) {
+        let (sender, frames) = mpsc::unbounded_channel();
+        let body = ChannelBody {
+            frames,
+            ended: false,
+        };
+        (sender, body)
+    }
+
+    fn send_data(frames: &Frames, data: &[u8], last: bool) {
+        frames
+            .send((Frame::data(Bytes::copy_from_slice(data)), last))
+            .unwrap();
+    }
+
+    /// Sends `data` after a pause well past [`STREAM_IDLE`].
+    fn send_later(frames: &Frames, data: &[u8], last: bool) {
+        let frames = frames.clone();
+        let data = data.to_vec();
+        tokio::spawn(async move {
+            tokio::time::sleep(STREAM_IDLE * 5).await;
+            send_data(&frames, &data, last);
+        });
+    }
+
+    /// A length-prefixed gRPC message.
+    fn message(payload: &[u8]) -> Vec<u8> {
+        let mut message = vec![0];
+        message.extend_from_slice(&u32::try_from(payload.len()).unwrap().to_be_bytes());
+        message.extend_from_slice(payload);
+        message
+    }
+
+    fn complete<B>(buffered: Buffered<B>) -> BufferedBody<B> {
+        match buffered {
+            Buffered::Complete(body) => body,
+            Buffered::Incomplete(_, reason) => panic!("body incomplete: {reason}"),
+        }
+    }
+
+    fn incomplete<B>(buffered: Buffered<B>) -> (BufferedBody<B>, &'static str) {
+        match buffered {
+            Buffered::Complete(_) => panic!("body complete"),
+            Buffered::Incomplete(body, reason) => (body, reason),
+        }
+    }
+
+    fn policy(toml: &str) -> RetryPolicy {
+        toml::from_str(&format!(
+            "call = \"test.Service/*\"\ninitial_backoff_ms = 1\n{toml}"
+        ))
+        .unwrap()
+    }
+
+    fn retries() -> IntCounterVec {
+        IntCounterVec::new(Opts::new("retries", "help"), &["upstream", "kind"]).unwrap()
+    }
+
+    fn request(payload: &'static [u8]) -> Request<impl Body<Data = Bytes, Error = hyper::Error>> {
+        Request::post("/test.Service/Method")
+            .header("content-type", "application/grpc")
+            .body(Full::new(Bytes::from_static(payload)).map_err(|never| match never {}))
+            .unwrap()
+    }
+
+    async fn into_parts(response: Response<UpstreamBody>) -> (Option<String>, Bytes) {
+        let status = response
+            .headers()
+            .get("grpc-status")
+            .map(|status| status.to_str().unwrap().to_owned());
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, body)
+    }
+
+    #[test]
+    fn finds_message_boundaries() {
+        assert!(is_message_boundary(b""));
+        assert!(is_message_boundary(&message(b"")));
+        assert!(is_message_boundary(&message(b"hello")));
+        assert!(is_message_boundary(
+            &[message(b"one"), message(b""), message(b"three")].concat()
+        ));
+
+        let whole = message(b"hello");
+        for len in 1..whole.len() {
+            assert!(!is_message_boundary(&whole[..len]), "{len} bytes");
+        }
+        assert!(!is_message_boundary(
+            &[message(b"one"), vec![0, 0]].concat()
+        ));
+    }
+
+    #[tokio::test]
+    async fn decides_which_attempts_to_retry() {
+        let stub = stub_upstream(|index| {
+            let status = [14, 8, 3, 0][index];
+            (Duration::ZERO, grpc_response(status, b""))
+        })
+        .await;
+        let default = policy("");
+        let exhausted = policy(r#"retryable_status_codes = ["UNAVAILABLE", "RESOURCE_EXHAUSTED"]"#);
+
+        let mut results = Vec::new();
+        for _ in 0..4 {
+            let request = request(b"").map(BodyExt::boxed);
+            results.push(stub.cluster.send(request).await);
+        }
+        let retried: Vec<(bool, bool)> = results
+            .iter()
+            .map(|result| {
+                (
+                    is_retryable(result, &default),
+                    is_retryable(result, &exhausted),
+                )
+            })
+            .collect();
+        assert_eq!(
+            retried,
+            [(true, true), (false, true), (false, false), (false, false)]
+        );
+
+        for (error, retryable) in [
+            (ProxyError::UpstreamConnect("refused".to_owned()), true),
+            (ProxyError::UpstreamRequest("reset".to_owned()), true),
+            (ProxyError::DeadlineExceeded, false),
+            (ProxyError::RequestBody("cancelled".to_owned()), false),
+        ] {
+            assert_eq!(is_retryable(&Err(error), &default), retryable);
+        }
+    }
+
+    #[tokio::test]
+    async fn buffers_a_unary_request() {
+        let (frames, body) = channel();
+        send_data(&frames, &message(b"hello"), true);
+        let body = complete(buffer(body, 1024, true).await.unwrap());
+        assert_eq!(body.data.as_deref(), Some(&message(b"hello")[..]));
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_first_and_for_partial_messages() {
+        let (frames, body) = channel();
+        send_later(&frames, &message(b"late"), true);
+        let body = complete(buffer(body, 1024, true).await.unwrap());
+        assert_eq!(body.data.as_deref(), Some(&message(b"late")[..]));
+
+        let whole = message(b"split in two");
+        let (frames, body) = channel();
+        send_data(&frames, &whole[..7], false);
+        send_later(&frames, &whole[7..], true);
+        let body = complete(buffer(body, 1024, true).await.unwrap());
+        assert_eq!(body.data.as_deref(), Some(&whole[..]));
+    }
+
+    #[tokio::test]
+    async fn idle_open_body_is_a_stream_unless_the_policy_rules_it_out() {
+        let (frames, body) = channel();
+        send_data(&frames, &message(b"first"), false);
+        send_later(&frames, b"", true);
+        let (_, reason) = incomplete(buffer(body, 1024, true).await.unwrap());
+        assert_eq!(reason, "streaming");
+
+        let (frames, body) = channel();
+        send_data(&frames, &message(b"first"), false);
+        send_later(&frames, b"", true);
+        let body = complete(buffer(body, 1024, false).await.unwrap());
+        assert_eq!(body.data.as_deref(), Some(&message(b"first")[..]));
+    }
+
+    #[tokio::test]
+    async fn keeps_request_trailers() {
+        let (frames, body) = channel();
+        send_data(&frames, &message(b"hello"), false);
+        let mut trailers = HeaderMap::new();
+        trailers.insert("x-checksum", "abc".parse().unwrap());
+        frames.send((Frame::trailers(trailers), true)).unwrap();
+
+        let body = complete(buffer(body, 1024, true).await.unwrap());
+        let replayed = body.clone().collect().await.unwrap();
+        assert_eq!(replayed.trailers().unwrap()["x-checksum"], "abc");
+        assert_eq!(replayed.to_bytes(), message(b"hello"));
+    }
+
+    #[tokio::test]
+    async fn large_bodies_are_forwarded_whole() {
+        let (frames, body) = channel();
+        send_data(&frames, &message(b"0123456789"), false);
+        let (body, reason) = incomplete(buffer(body, 8, false).await.unwrap());
+        assert_eq!(reason, "too large");
+
+        send_data(&frames, &message(b"more"), true);
+        let forwarded = body.collect().await.unwrap().to_bytes();
+        assert_eq!(
+            forwarded,
+            [message(b"0123456789"), message(b"more")].concat()
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_until_an_attempt_succeeds() {
+        let stub = stub_upstream(|index| match index {
+            0 | 1 => (Duration::ZERO, grpc_response(14, b"")),
+            _ => (Duration::ZERO, grpc_response(0, b"ok")),
+        })
+        .await;
+        let retries = retries();
+        let response = send(&stub.cluster, request(b"payload"), &policy(""), &retries)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            into_parts(response).await,
+            (Some("0".to_owned()), Bytes::from_static(b"ok"))
+        );
+        assert_eq!(*stub.bodies.lock().unwrap(), [&b"payload"[..]; 3]);
+        assert_eq!(retries.with_label_values(&["backend", "retry"]).get(), 2);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let stub = stub_upstream(|_| (Duration::ZERO, grpc_response(14, b""))).await;
+        let retries = retries();
+        let response = send(
+            &stub.cluster,
+            request(b""),
+            &policy("max_attempts = 4"),
+            &retries,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(into_parts(response).await.0.as_deref(), Some("14"));
+        assert_eq!(stub.bodies.lock().unwrap().len(), 4);
+        assert_eq!(retries.with_label_values(&["backend", "retry"]).get(), 3);
+    }
+
+    #[tokio::test]
+    async fn returns_other_answers_at_once() {
+        let stub = stub_upstream(|_| (Duration::ZERO, grpc_response(3, b""))).await;
+        let retries = retries();
+        let response = send(&stub.cluster, request(b""), &policy(""), &retries)
+            .await
+            .unwrap();
+
+        assert_eq!(into_parts(response).await.0.as_deref(), Some("3"));
+        assert_eq!(stub.bodies.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn hedges_slow_attempts() {
+        let stub = stub_upstream(|index| match index {
+            0 => (Duration::from_secs(5), grpc_response(0, b"slow")),
+            _ => (Duration::ZERO, grpc_response(0, b"fast")),
+        })
+        .await;
+        let retries = retries();
+        let started = std::time::Instant::now();
+        let response = send(
+            &stub.cluster,
+            request(b"payload"),
+            &policy("max_attempts = 2\nhedging_delay_ms = 20"),
+            &retries,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(into_parts(response).await.1, Bytes::from_static(b"fast"));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(retries.with_label_values(&["backend", "hedge"]).get(), 1);
+        assert_eq!(retries.with_label_values(&["backend", "retry"]).get(), 0);
+    }
+
+    #[tokio::test]
+    async fn hedging_retries_failures_without_waiting() {
+        let stub = stub_upstream(|index| match index {
+            0 => (Duration::ZERO, grpc_response(14, b"")),
+            _ => (Duration::ZERO, grpc_response(0, b"ok")),
+        })
+        .await;
+        let retries = retries();
+        let started = std::time::Instant::now();
+        let response = send(
+            &stub.cluster,
+            request(b""),
+            &policy("hedging_delay_ms = 60000"),
+            &retries,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(into_parts(response).await.1, Bytes::from_static(b"ok"));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(retries.with_label_values(&["backend", "retry"]).get(), 1);
+    }
+}
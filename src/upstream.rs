@@ -0,0 +1,298 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::{Connected, Connection};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+use crate::config::UpstreamTlsConfig;
+use crate::error::ProxyError;
+use crate::net::{self, Stream};
+use crate::tls;
+
+/// Proxied calls stream the client's body, the proxy's own calls (health
+/// checks) send a buffered one.
+pub type RequestBody = BoxBody<Bytes, hyper::Error>;
+
+pub type UpstreamClient = Client<UpstreamConnector, RequestBody>;
+
+/// Connects every request to a fixed upstream address, optionally over TLS.
+/// The request URI only provides the `:authority` sent to the upstream, so it
+/// can differ from the address that is dialed.
+#[derive(Clone)]
+pub struct UpstreamConnector {
+    address: Arc<str>,
+    tls: Option<UpstreamTls>,
+}
+
+/// TLS settings shared by every endpoint of an upstream.
+#[derive(Clone)]
+pub struct UpstreamTls {
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl UpstreamConnector {
+    pub fn new(address: &str, tls: Option<UpstreamTls>) -> Self {
+        Self {
+            address: address.into(),
+            tls,
+        }
+    }
+}
+
+/// `address` is the upstream's configured name; its host is used as SNI
+/// unless `server_name` overrides it.
+pub fn build_tls(address: &str, tls_config: &UpstreamTlsConfig) -> Result<UpstreamTls, ProxyError> {
+    let roots = match &tls_config.ca_path {
+        Some(ca_path) => tls::load_root_store(ca_path)?,
+        None => RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        },
+    };
+
+    let builder = ClientConfig::builder_with_provider(tls::crypto_provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| ProxyError::TlsConfig(format!("upstream protocol versions: {e}")))?
+        .with_root_certificates(roots);
+
+    let mut client_config = match (&tls_config.cert_path, &tls_config.key_path) {
+        (Some(cert_path), Some(key_path)) => builder
+            .with_client_auth_cert(
+                tls::load_certs(cert_path)?,
+                tls::load_private_key(key_path)?,
+            )
+            .map_err(|e| ProxyError::TlsConfig(format!("upstream client certificate: {e}")))?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => {
+            return Err(ProxyError::TlsConfig(
+                "upstream_tls.cert_path and upstream_tls.key_path must be set together".to_owned(),
+            ));
+        }
+    };
+    client_config.alpn_protocols = vec![b"h2".to_vec()];
+
+    let host = match &tls_config.server_name {
+        Some(server_name) => server_name.as_str(),
+        None => host_of(address),
+    };
+    let server_name = ServerName::try_from(host.to_owned())
+        .map_err(|e| ProxyError::TlsConfig(format!("upstream server name '{host}': {e}")))?;
+
+    Ok(UpstreamTls {
+        connector: TlsConnector::from(Arc::new(client_config)),
+        server_name,
+    })
+}
+
+/// The `:authority` for requests to `address`. Unix sockets have no host
+/// name, so their calls go to `localhost`.
+pub fn authority_of(address: &str) -> &str {
+    if address.starts_with("unix:") {
+        "localhost"
+    } else {
+        address
+    }
+}
+
+/// Strips the port and IPv6 brackets from a `host:port` address.
+fn host_of(address: &str) -> &str {
+    let address = authority_of(address);
+    let host = address
+        .rsplit_once(':')
+        .map_or(address, |(host, _port)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+impl tower_service::Service<http::Uri> for UpstreamConnector {
+    type Response = TokioIo<UpstreamStream>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _uri: http::Uri) -> Self::Future {
+        let connector = self.clone();
+        Box::pin(async move {
+            let stream = net::connect(&connector.address).await?;
+
+            let stream = match connector.tls {
+                Some(tls) => {
+                    let tls_stream = tls.connector.connect(tls.server_name, stream).await?;
+                    UpstreamStream::Tls(Box::new(tls_stream))
+                }
+                None => UpstreamStream::Plain(stream),
+            };
+            Ok(TokioIo::new(stream))
+        })
+    }
+}
+
+/// Builds a client for a single endpoint. Each client keeps its own HTTP/2
+/// connection, so load balancing works per call rather than per connection.
+pub fn build_client(address: &str, tls: Option<UpstreamTls>) -> UpstreamClient {
+    let connector = UpstreamConnector::new(address, tls);
+    Client::builder(TokioExecutor::new())
+        .http2_only(true)
+        .build(connector)
+}
+
+pub enum UpstreamStream {
+    Plain(Stream),
+    Tls(Box<TlsStream<Stream>>),
+}
+
+impl Connection for UpstreamStream {
+    fn connected(&self) -> Connected {
+        let stream = match self {
+            Self::Plain(stream) => stream,
+            Self::Tls(stream) => stream.get_ref().0,
+        };
+        match stream {
+            Stream::Tcp(stream) => stream.connected(),
+            Stream::Unix(stream) => stream.connected(),
+        }
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Self::Tls(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Self::Plain(stream) => stream.is_write_vectored(),
+            Self::Tls(stream) => stream.is_write_vectored(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tls_config(toml: &str) -> UpstreamTlsConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn tls_error(address: &str, toml: &str) -> String {
+        match build_tls(address, &tls_config(toml)) {
+            Err(ProxyError::TlsConfig(message)) => message,
+            Err(e) => panic!("expected a TLS config error, got {e}"),
+            Ok(_) => panic!("expected a TLS config error"),
+        }
+    }
+
+    #[test]
+    fn authority_and_host_of_addresses() {
+        for (address, authority, host) in [
+            ("backend:50051", "backend:50051", "backend"),
+            ("10.0.0.1:443", "10.0.0.1:443", "10.0.0.1"),
+            ("[::1]:443", "[::1]:443", "::1"),
+            ("[2001:db8::5]:50051", "[2001:db8::5]:50051", "2001:db8::5"),
+            ("unix:/run/backend.sock", "localhost", "localhost"),
+        ] {
+            assert_eq!(authority_of(address), authority, "{address}");
+            assert_eq!(host_of(address), host, "{address}");
+        }
+    }
+
+    #[test]
+    fn server_name_defaults_to_the_address_host() {
+        let tls = build_tls("backend.internal:443", &tls_config("")).unwrap();
+        assert_eq!(
+            tls.server_name,
+            ServerName::try_from("backend.internal").unwrap()
+        );
+
+        let tls = build_tls("[::1]:443", &tls_config("")).unwrap();
+        assert_eq!(tls.server_name, ServerName::try_from("::1").unwrap());
+
+        let tls = build_tls(
+            "10.0.0.1:443",
+            &tls_config("server_name = \"backend.internal\""),
+        )
+        .unwrap();
+        assert_eq!(
+            tls.server_name,
+            ServerName::try_from("backend.internal").unwrap()
+        );
+
+        assert!(
+            tls_error("10.0.0.1:443", "server_name = \"not a name\"")
+                .starts_with("upstream server name 'not a name': ")
+        );
+    }
+
+    #[test]
+    fn client_cert_and_key_must_be_set_together() {
+        for toml in [
+            "cert_path = \"/nonexistent/client.pem\"",
+            "key_path = \"/nonexistent/client.key\"",
+        ] {
+            assert_eq!(
+                tls_error("backend:443", toml),
+                "upstream_tls.cert_path and upstream_tls.key_path must be set together",
+                "{toml}"
+            );
+        }
+    }
+}